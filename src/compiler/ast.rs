@@ -0,0 +1,270 @@
+use std::borrow::Cow;
+
+/// Range of tokens a node was parsed from, as indices in the tokens given to
+/// the parser, `end` excluded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Index of the last token of the node.
+    pub fn last(&self) -> usize {
+        self.end - 1
+    }
+}
+
+/// A name in the source along with the index of its token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Identifier<'a> {
+    pub name: &'a str,
+    pub token: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal<'a> {
+    Number(f64),
+    Int(i64),
+    // Owned when built by the optimizer
+    Str(Cow<'a, str>),
+    True,
+    False,
+    Null,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    IntDiv,
+    Pow,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add
+                | BinaryOp::Sub
+                | BinaryOp::Mul
+                | BinaryOp::Div
+                | BinaryOp::Mod
+                | BinaryOp::IntDiv
+                | BinaryOp::Pow
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Expr<'a> {
+    pub kind: ExprKind<'a>,
+    pub span: Span,
+}
+
+// The `token` of operators is the index of the operator token
+#[derive(Debug, PartialEq)]
+pub enum ExprKind<'a> {
+    Literal(Literal<'a>),
+    Variable(Identifier<'a>),
+    Assign {
+        name: Identifier<'a>,
+        // Index of the '=' token
+        token: usize,
+        value: Box<Expr<'a>>,
+    },
+    Unary {
+        operator: UnaryOp,
+        token: usize,
+        operand: Box<Expr<'a>>,
+    },
+    Binary {
+        left: Box<Expr<'a>>,
+        operator: BinaryOp,
+        token: usize,
+        right: Box<Expr<'a>>,
+    },
+    Logical {
+        left: Box<Expr<'a>>,
+        operator: LogicalOp,
+        token: usize,
+        right: Box<Expr<'a>>,
+    },
+    Call {
+        callee: Box<Expr<'a>>,
+        arguments: Vec<Expr<'a>>,
+    },
+    Grouping(Box<Expr<'a>>),
+    // `object.name`, only modules have properties
+    Get {
+        object: Box<Expr<'a>>,
+        name: Identifier<'a>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FnDecl<'a> {
+    pub name: Identifier<'a>,
+    pub params: Vec<Identifier<'a>>,
+    pub body: Vec<Stmt<'a>>,
+    // Lines of the `///` comments before the declaration
+    pub doc: Vec<&'a str>,
+}
+
+/// What an import binds.
+#[derive(Debug, PartialEq)]
+pub enum ImportKind<'a> {
+    // `import "path" as name;`, the module object
+    Module(Identifier<'a>),
+    // `from "path" import a, b;`, globals of the module
+    Names(Vec<Identifier<'a>>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stmt<'a> {
+    pub kind: StmtKind<'a>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StmtKind<'a> {
+    Expression(Expr<'a>),
+    Print(Expr<'a>),
+    Let {
+        name: Identifier<'a>,
+        initializer: Option<Expr<'a>>,
+        doc: Vec<&'a str>,
+    },
+    Fn(FnDecl<'a>),
+    Block(Vec<Stmt<'a>>),
+    If {
+        condition: Expr<'a>,
+        then_branch: Box<Stmt<'a>>,
+        else_branch: Option<Box<Stmt<'a>>>,
+    },
+    While {
+        condition: Expr<'a>,
+        body: Box<Stmt<'a>>,
+    },
+    Return(Option<Expr<'a>>),
+    Import {
+        path: &'a str,
+        // Index of the path token
+        token: usize,
+        kind: ImportKind<'a>,
+    },
+}
+
+/// The statements of a script, the statements with syntax errors are left
+/// out.
+#[derive(Debug, PartialEq)]
+pub struct Program<'a> {
+    pub statements: Vec<Stmt<'a>>,
+    // Index of the Eof token
+    pub eof: usize,
+}
+
+/// Walks the tree, the default methods visit every child so an analysis only
+/// overrides the nodes it cares about and calls `walk_*` to go deeper.
+pub trait Visitor<'a> {
+    fn visit_stmt(&mut self, stmt: &Stmt<'a>) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr<'a>) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_program<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, program: &Program<'a>) {
+    for stmt in &program.statements {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, stmt: &Stmt<'a>) {
+    match &stmt.kind {
+        StmtKind::Expression(expr) | StmtKind::Print(expr) => visitor.visit_expr(expr),
+        StmtKind::Let { initializer, .. } => {
+            if let Some(initializer) = initializer {
+                visitor.visit_expr(initializer);
+            }
+        }
+        StmtKind::Fn(function) => {
+            for stmt in &function.body {
+                visitor.visit_stmt(stmt);
+            }
+        }
+        StmtKind::Block(statements) => {
+            for stmt in statements {
+                visitor.visit_stmt(stmt);
+            }
+        }
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            visitor.visit_expr(condition);
+            visitor.visit_stmt(then_branch);
+            if let Some(else_branch) = else_branch {
+                visitor.visit_stmt(else_branch);
+            }
+        }
+        StmtKind::While { condition, body } => {
+            visitor.visit_expr(condition);
+            visitor.visit_stmt(body);
+        }
+        StmtKind::Return(value) => {
+            if let Some(value) = value {
+                visitor.visit_expr(value);
+            }
+        }
+        StmtKind::Import { .. } => (),
+    }
+}
+
+pub fn walk_expr<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, expr: &Expr<'a>) {
+    match &expr.kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) => (),
+        ExprKind::Assign { value, .. } => visitor.visit_expr(value),
+        ExprKind::Unary { operand, .. } => visitor.visit_expr(operand),
+        ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+        ExprKind::Call { callee, arguments } => {
+            visitor.visit_expr(callee);
+            for argument in arguments {
+                visitor.visit_expr(argument);
+            }
+        }
+        ExprKind::Grouping(expr) => visitor.visit_expr(expr),
+        ExprKind::Get { object, .. } => visitor.visit_expr(object),
+    }
+}
@@ -1,25 +1,84 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use super::value::Value;
 
-#[allow(dead_code)]
-#[derive(Clone, Copy)]
+/// Most constants the pool of a single chunk holds, the compiler reports an
+/// error on the constant past it.
+pub const MAX_CONSTANTS: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug)]
 pub enum OpCode {
     Return,
+    Print,
+    Pop,
+    Null,
+    True,
+    False,
     // Unary Op
     Negate,
+    Not,
     // Binary Op
     Add,
     Sub,
     Mul,
     Div,
     Mod,
+    IntDiv,
+    Pow,
+    Equal,
+    Greater,
+    Less,
 
     // usize reprensent the index of the constant in the chunk
     Constant(usize),
+    // Constant followed by Add or Sub, built by the optimizer
+    AddConstant(usize),
+    SubConstant(usize),
+    // usize represent the index of the constant holding the variable name
+    DefineGlobal(usize),
+    GetGlobal(usize),
+    SetGlobal(usize),
+    // usize represent the stack slot of the local, relative to the frame
+    GetLocal(usize),
+    SetLocal(usize),
+    // usize represent the offset of the instruction to jump to
+    Jump(usize),
+    JumpIfFalse(usize),
+    // usize represent the number of arguments
+    Call(usize),
+    // usize represent the index of the constant holding the module path
+    Import(usize),
+    // usize represent the index of the constant holding the property name
+    GetProperty(usize),
+}
+
+// The constants deduplicated in the pool, numbers are compared by their
+// bits so 0 and -0 stay apart
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Int(i64),
+    Str(Rc<str>),
+}
+
+impl ConstantKey {
+    fn new(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(num) => Some(ConstantKey::Number(num.to_bits())),
+            Value::Int(num) => Some(ConstantKey::Int(*num)),
+            Value::Str(string) => Some(ConstantKey::Str(string.clone())),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Debug)]
 pub struct Chunk {
     code: Vec<OpCode>,
     constants: Vec<Value>,
+    // Index in the pool of the numbers and strings already added
+    constant_indices: HashMap<ConstantKey, usize>,
     lines: Vec<usize>,
 }
 
@@ -28,6 +87,7 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
             lines: Vec::new(),
         }
     }
@@ -37,9 +97,34 @@ impl Chunk {
         self.lines.push(line);
     }
 
-    pub fn add_constants(&mut self, value: Value) -> usize {
+    /// Replaces the instruction at `index`, used to set the target of a
+    /// jump once it is known.
+    pub fn patch_opcode(&mut self, index: usize, byte: OpCode) {
+        self.code[index] = byte;
+    }
+
+    /// Replaces the whole code, `lines` has the line of each instruction.
+    pub fn replace_code(&mut self, code: Vec<OpCode>, lines: Vec<usize>) {
+        self.code = code;
+        self.lines = lines;
+    }
+
+    /// Index of `value` in the pool, reused when the same number or string
+    /// is already there. `None` when the pool has `MAX_CONSTANTS` values.
+    pub fn add_constants(&mut self, value: Value) -> Option<usize> {
+        let key = ConstantKey::new(&value);
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return Some(*index);
+        }
+        if self.constants.len() >= MAX_CONSTANTS {
+            return None;
+        }
         self.constants.push(value);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        Some(index)
     }
 
     pub fn code_nb(&self) -> usize {
@@ -58,10 +143,14 @@ impl Chunk {
     }
 
     pub fn get_constant(&self, index: usize) -> Value {
-        *self
-            .constants
+        self.constants
             .get(index)
             .expect("Expected a correct index of instruction code")
+            .clone()
+    }
+
+    pub fn constants(&self) -> &Vec<Value> {
+        &self.constants
     }
 
     pub fn get_line(&self, index: usize) -> usize {
@@ -71,3 +160,35 @@ impl Chunk {
             .expect("Expected a correct index of lines")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn constants_are_deduplicated() {
+        let mut chunk = Chunk::new();
+
+        assert_eq!(chunk.add_constants(Value::Number(1.0)), Some(0));
+        assert_eq!(chunk.add_constants(Value::Str("a".into())), Some(1));
+        assert_eq!(chunk.add_constants(Value::Number(1.0)), Some(0));
+        assert_eq!(chunk.add_constants(Value::Str("a".into())), Some(1));
+        assert_eq!(chunk.add_constants(Value::Number(-0.0)), Some(2));
+        assert_eq!(chunk.add_constants(Value::Number(0.0)), Some(3));
+        assert_eq!(chunk.add_constants(Value::Int(1)), Some(4));
+        assert_eq!(chunk.add_constants(Value::Int(1)), Some(4));
+        assert_eq!(chunk.constants().len(), 5);
+    }
+
+    #[test]
+    fn pool_limit() {
+        let mut chunk = Chunk::new();
+        for num in 0..MAX_CONSTANTS {
+            assert_eq!(chunk.add_constants(Value::Number(num as f64)), Some(num));
+        }
+
+        assert_eq!(chunk.add_constants(Value::Number(-1.0)), None);
+        // Values already in the pool are still found
+        assert_eq!(chunk.add_constants(Value::Number(3.0)), Some(3));
+    }
+}
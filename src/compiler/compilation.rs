@@ -1,5 +1,1009 @@
-pub struct Compiler;
+use thiserror::Error;
 
-impl Compiler {
-    pub fn compile() {}
-}
\ No newline at end of file
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use super::ast::{
+    walk_expr, BinaryOp, Expr, ExprKind, FnDecl, Identifier, ImportKind, Literal, LogicalOp, Span,
+    Stmt, StmtKind, UnaryOp, Visitor,
+};
+use super::chunk::{Chunk, OpCode, MAX_CONSTANTS};
+use super::function::{Function, SCRIPT_NAME};
+use super::lint::Rule;
+use super::optimizer::{self, OptLevel};
+use super::parser::Parser;
+use super::value::Value;
+use crate::natives;
+use crate::scanner::token::{Token, TokenType};
+
+/// An error, or a warning of a lint rule, found while compiling.
+#[derive(Debug)]
+pub struct CompileError {
+    line: usize,
+    location: String,
+    message: String,
+    // Index of the token the error is reported at
+    token: usize,
+    // Set for warnings
+    rule: Option<Rule>,
+}
+
+impl CompileError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Index in the compiled tokens of the token the error is reported at.
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    /// 1-based like the displayed line.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The lint rule of a warning, None for errors.
+    pub fn rule(&self) -> Option<Rule> {
+        self.rule
+    }
+
+    pub(super) fn new(tokens: &[Token], index: usize, message: String, rule: Option<Rule>) -> Self {
+        let token = tokens[index];
+        let location = match token.ty() {
+            TokenType::Eof => String::from(" at end"),
+            TokenType::Error(_) => String::new(),
+            _ => format!(" at '{}'", token.lexeme()),
+        };
+        Self {
+            line: token.line() + 1,
+            location,
+            message,
+            token: index,
+            rule,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rule {
+            None => write!(
+                f,
+                "[line {}] Error{}: {}",
+                self.line, self.location, self.message
+            ),
+            Some(rule) => write!(
+                f,
+                "[line {}] Warning{}: {} [{rule}]",
+                self.line, self.location, self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug, Error)]
+#[error("{}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))]
+pub struct CompileErrors(pub Vec<CompileError>);
+
+// Name of the functions built by `Compiler::compile_expression`
+const EXPRESSION_NAME: &str = "expression";
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+}
+
+struct Local<'a> {
+    name: &'a str,
+    // None while the initializer of the variable is being compiled
+    depth: Option<usize>,
+    // Index of the token declaring the variable
+    token: usize,
+    // Whether the variable is read, for the unused-local rule
+    used: bool,
+}
+
+// State of the function being compiled, nested function declarations push a
+// new one on top of the enclosing function.
+struct FunctionState<'a> {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local<'a>>,
+    scope_depth: usize,
+    // Set once the pool is full, the chunk reports it a single time
+    constants_full: bool,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(function: Function, kind: FunctionKind) -> Self {
+        Self {
+            function,
+            kind,
+            // The first slot of every frame holds the function being called
+            locals: vec![Local {
+                name: "",
+                depth: Some(0),
+                token: 0,
+                used: true,
+            }],
+            scope_depth: 0,
+            constants_full: false,
+        }
+    }
+}
+
+// First assignment of an expression, for the assign-in-condition rule
+#[derive(Default)]
+struct FindAssignment(Option<usize>);
+
+impl<'a> Visitor<'a> for FindAssignment {
+    fn visit_expr(&mut self, expr: &Expr<'a>) {
+        match expr.kind {
+            ExprKind::Assign { token, .. } if self.0.is_none() => self.0 = Some(token),
+            _ => walk_expr(self, expr),
+        }
+    }
+}
+
+/// Compiles a script in two phases: the `Parser` builds its tree, then the
+/// tree is turned into the chunks of the script and its functions.
+pub struct Compiler<'a> {
+    tokens: Vec<Token<'a>>,
+    functions: Vec<FunctionState<'a>>,
+    errors: Vec<CompileError>,
+    warnings: Vec<CompileError>,
+    // Set by an error until the end of the statement, like the parser does
+    panic_mode: bool,
+    // Names declared by the script, and the calls of globals to check against
+    // them once the whole script is compiled
+    globals: HashSet<&'a str>,
+    global_calls: Vec<(&'a str, usize)>,
+    opt_level: OptLevel,
+    module: usize,
+}
+
+impl<'a> Compiler<'a> {
+    /// Takes the tokens of a `Vec` or straight from a `&Scanner`. The tree
+    /// refers to tokens by index, so all of them are kept.
+    pub fn new(tokens: impl IntoIterator<Item = Token<'a>>) -> Self {
+        Self {
+            tokens: tokens.into_iter().collect(),
+            functions: vec![FunctionState::new(
+                Function::new(SCRIPT_NAME),
+                FunctionKind::Script,
+            )],
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            panic_mode: false,
+            globals: HashSet::new(),
+            global_calls: Vec::new(),
+            opt_level: OptLevel::default(),
+            module: 0,
+        }
+    }
+
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    /// Index in the VM of the module being compiled, its functions read the
+    /// globals of that module.
+    pub fn set_module(&mut self, module: usize) {
+        self.module = module;
+    }
+
+    #[allow(dead_code)]
+    pub fn compile(self) -> Result<Chunk, CompileErrors> {
+        self.compile_script().map(Function::into_chunk)
+    }
+
+    /// Same as `compile` but keeps the script function along with the debug
+    /// information of its locals.
+    pub fn compile_script(mut self) -> Result<Function, CompileErrors> {
+        let script = self.script();
+        if self.errors.is_empty() {
+            Ok(script)
+        } else {
+            Err(CompileErrors(self.sorted_errors()))
+        }
+    }
+
+    /// Compiles the script for its errors and the warnings of every lint
+    /// rule, in source order.
+    pub fn diagnostics(mut self) -> Vec<CompileError> {
+        self.script();
+        self.panic_mode = false;
+        let undefined = self
+            .global_calls
+            .iter()
+            .filter(|(name, _)| !self.globals.contains(name) && natives::lookup(name).is_none())
+            .copied()
+            .collect::<Vec<_>>();
+        for (name, index) in undefined {
+            self.warn_at(
+                index,
+                Rule::UndefinedGlobal,
+                format!("Call to undefined global '{name}'."),
+            );
+        }
+
+        let mut diagnostics = self.sorted_errors();
+        diagnostics.append(&mut self.warnings);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.token);
+        diagnostics
+    }
+
+    /// Compiles a single expression into a function returning its value.
+    /// The expression can refer to `params` like locals, they are bound in
+    /// order to the arguments of the call.
+    pub fn compile_expression(mut self, params: &[&'a str]) -> Result<Function, CompileErrors> {
+        self.functions = vec![FunctionState::new(
+            Function::new(EXPRESSION_NAME),
+            FunctionKind::Function,
+        )];
+        self.begin_scope();
+        for name in params {
+            let state = self.state_mut();
+            state.function.add_param();
+            state.locals.push(Local {
+                name,
+                depth: Some(1),
+                token: 0,
+                used: true,
+            });
+        }
+
+        let (expr, errors) = Parser::new(&self.tokens).parse_expression();
+        self.errors = errors;
+        if let Some(expr) = expr {
+            self.expression(&expr);
+        }
+        self.emit(OpCode::Return, self.tokens.len() - 1);
+        let function = self
+            .functions
+            .pop()
+            .expect("Expected a function being compiled")
+            .function;
+
+        if self.errors.is_empty() {
+            Ok(function)
+        } else {
+            Err(CompileErrors(self.sorted_errors()))
+        }
+    }
+
+    fn script(&mut self) -> Function {
+        let (mut program, errors) = Parser::new(&self.tokens).parse();
+        self.errors = errors;
+        if self.opt_level == OptLevel::O1 {
+            optimizer::fold_constants(&mut program);
+        }
+        for stmt in &program.statements {
+            self.declaration(stmt);
+        }
+        self.end_function(program.eof)
+    }
+
+    // The syntax errors come first, the errors of the code generation are
+    // put back in source order
+    fn sorted_errors(&mut self) -> Vec<CompileError> {
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|error| error.token);
+        errors
+    }
+
+    fn state(&self) -> &FunctionState<'a> {
+        self.functions
+            .last()
+            .expect("Expected a function being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.functions
+            .last_mut()
+            .expect("Expected a function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        self.state_mut().function.chunk_mut()
+    }
+
+    // `end` is the index of the last token of the function
+    fn end_function(&mut self, end: usize) -> Function {
+        self.emit_return(end);
+        let state = self
+            .functions
+            .pop()
+            .expect("Expected a function being compiled");
+        // The locals of the body and the parameters are discarded with the
+        // frame, without an end_scope
+        for local in &state.locals {
+            self.check_used(local);
+        }
+        let mut function = state.function;
+        function.set_module(self.module);
+        if self.opt_level == OptLevel::O1 {
+            optimizer::peephole(&mut function);
+        }
+        function
+    }
+
+    fn declaration(&mut self, stmt: &Stmt<'a>) {
+        self.statement(stmt);
+        self.panic_mode = false;
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'a>) {
+        let end = stmt.span.last();
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Pop, end);
+            }
+            StmtKind::Print(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Print, end);
+            }
+            StmtKind::Let {
+                name, initializer, ..
+            } => {
+                let global = self.declare_variable(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(OpCode::Null, name.token),
+                }
+                self.define_variable(global, end);
+            }
+            StmtKind::Fn(function) => {
+                let global = self.declare_variable(&function.name);
+                // A function can refer to itself to recurse
+                self.mark_initialized();
+                self.function(function, end);
+                self.define_variable(global, end);
+            }
+            StmtKind::Block(statements) => {
+                self.begin_scope();
+                self.block(statements);
+                self.end_scope(end);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => self.if_statement(condition, then_branch, else_branch.as_deref()),
+            StmtKind::While { condition, body } => self.while_statement(condition, body),
+            StmtKind::Return(value) => self.return_statement(value.as_ref(), stmt.span),
+            StmtKind::Import { path, token, kind } => {
+                let path = self.make_constant(Value::Str(Rc::from(*path)), *token);
+                match kind {
+                    ImportKind::Module(name) => {
+                        let global = self.declare_variable(name);
+                        self.emit(OpCode::Import(path), *token);
+                        self.define_variable(global, end);
+                    }
+                    // The module is only run by the first import, the next
+                    // ones get it from the cache
+                    ImportKind::Names(names) => {
+                        for name in names {
+                            let global = self.declare_variable(name);
+                            self.emit(OpCode::Import(path), *token);
+                            let property = self.identifier_constant(name);
+                            self.emit(OpCode::GetProperty(property), name.token);
+                            self.define_variable(global, end);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, declaration: &FnDecl<'a>, end: usize) {
+        self.functions.push(FunctionState::new(
+            Function::new(declaration.name.name),
+            FunctionKind::Function,
+        ));
+        self.begin_scope();
+        for param in &declaration.params {
+            self.state_mut().function.add_param();
+            self.declare_local(param);
+            self.mark_initialized();
+        }
+        self.block(&declaration.body);
+
+        let function = self.end_function(end);
+        self.emit_constant(Value::Function(Rc::new(function)), end);
+    }
+
+    fn block(&mut self, statements: &[Stmt<'a>]) {
+        let mut returned = false;
+        let mut reported = false;
+        for stmt in statements {
+            if returned && !reported {
+                self.warn_at(
+                    stmt.span.start,
+                    Rule::UnreachableCode,
+                    String::from("Unreachable code after 'return'."),
+                );
+                reported = true;
+            }
+            returned |= matches!(stmt.kind, StmtKind::Return(_));
+            self.declaration(stmt);
+        }
+    }
+
+    // Returns the constant index of the name for globals, locals live on the
+    // stack and don't need one.
+    fn declare_variable(&mut self, name: &Identifier<'a>) -> Option<usize> {
+        if self.state().scope_depth > 0 {
+            self.declare_local(name);
+            return None;
+        }
+        self.globals.insert(name.name);
+        Some(self.identifier_constant(name))
+    }
+
+    fn identifier_constant(&mut self, name: &Identifier<'a>) -> usize {
+        self.make_constant(Value::Str(Rc::from(name.name)), name.token)
+    }
+
+    fn declare_local(&mut self, name: &Identifier<'a>) {
+        let scope_depth = self.state().scope_depth;
+        let already_declared = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name.name);
+        if already_declared {
+            self.error_at(
+                name.token,
+                "Already a variable with this name in this scope.",
+            );
+        } else if self
+            .state()
+            .locals
+            .iter()
+            .any(|local| local.name == name.name)
+        {
+            self.warn_at(
+                name.token,
+                Rule::Shadowing,
+                format!(
+                    "Variable '{}' shadows a variable of an enclosing scope.",
+                    name.name
+                ),
+            );
+        }
+        self.state_mut().locals.push(Local {
+            name: name.name,
+            depth: None,
+            token: name.token,
+            used: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let slot = state.locals.len() - 1;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+            let name = local.name;
+            state.function.open_local(name, slot);
+        }
+    }
+
+    fn define_variable(&mut self, global: Option<usize>, end: usize) {
+        match global {
+            Some(global) => self.emit(OpCode::DefineGlobal(global), end),
+            None => self.mark_initialized(),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    // `end` is the index of the closing brace
+    fn end_scope(&mut self, end: usize) {
+        self.state_mut().scope_depth -= 1;
+        let scope_depth = self.state().scope_depth;
+        while self
+            .state()
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|depth| depth > scope_depth))
+        {
+            let state = self.state_mut();
+            let local = state.locals.pop().expect("Expected a local to pop");
+            let slot = state.locals.len();
+            state.function.close_local(slot);
+            self.check_used(&local);
+            self.emit(OpCode::Pop, end);
+        }
+    }
+
+    fn check_used(&mut self, local: &Local) {
+        if !local.used && !local.name.is_empty() && !local.name.starts_with('_') {
+            self.warn_at(
+                local.token,
+                Rule::UnusedLocal,
+                format!("Local variable '{}' is never used.", local.name),
+            );
+        }
+    }
+
+    fn if_statement(
+        &mut self,
+        condition: &Expr<'a>,
+        then_branch: &Stmt<'a>,
+        else_branch: Option<&Stmt<'a>>,
+    ) {
+        self.expression(condition);
+        let mut assignment = FindAssignment::default();
+        assignment.visit_expr(condition);
+        if let Some(index) = assignment.0 {
+            self.warn_at(
+                index,
+                Rule::AssignInCondition,
+                String::from("Assignment in an 'if' condition, did you mean '=='?"),
+            );
+        }
+        // The closing parenthesis follows the condition
+        let right_paren = condition.span.end;
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse(0), right_paren);
+        self.emit(OpCode::Pop, right_paren);
+        self.statement(then_branch);
+        let then_end = then_branch.span.last();
+        let else_jump = self.emit_jump(OpCode::Jump(0), then_end);
+
+        self.patch_jump(then_jump);
+        self.emit(OpCode::Pop, then_end);
+        if let Some(else_branch) = else_branch {
+            self.statement(else_branch);
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self, condition: &Expr<'a>, body: &Stmt<'a>) {
+        let loop_start = self.chunk().code_nb();
+        self.expression(condition);
+        let right_paren = condition.span.end;
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0), right_paren);
+        self.emit(OpCode::Pop, right_paren);
+        self.statement(body);
+        let body_end = body.span.last();
+        self.emit(OpCode::Jump(loop_start), body_end);
+
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop, body_end);
+    }
+
+    fn return_statement(&mut self, value: Option<&Expr<'a>>, span: Span) {
+        if self.state().kind == FunctionKind::Script {
+            self.error_at(span.start, "Can't return from top-level code.");
+        }
+        match value {
+            None => self.emit_return(span.last()),
+            Some(value) => {
+                self.expression(value);
+                self.emit(OpCode::Return, span.last());
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr<'a>) {
+        let end = expr.span.last();
+        match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Number(num) => self.emit_constant(Value::Number(*num), end),
+                Literal::Int(num) => self.emit_constant(Value::Int(*num), end),
+                Literal::Str(string) => {
+                    self.emit_constant(Value::Str(Rc::from(string.as_ref())), end)
+                }
+                Literal::True => self.emit(OpCode::True, end),
+                Literal::False => self.emit(OpCode::False, end),
+                Literal::Null => self.emit(OpCode::Null, end),
+            },
+            ExprKind::Variable(name) => {
+                let (get, _) = self.variable_ops(name);
+                if let OpCode::GetLocal(slot) = get {
+                    self.state_mut().locals[slot].used = true;
+                }
+                self.emit(get, name.token);
+            }
+            ExprKind::Assign { name, value, .. } => {
+                let (_, set) = self.variable_ops(name);
+                self.expression(value);
+                self.emit(set, end);
+            }
+            ExprKind::Unary {
+                operator, operand, ..
+            } => {
+                self.expression(operand);
+                match operator {
+                    UnaryOp::Negate => self.emit(OpCode::Negate, end),
+                    UnaryOp::Not => self.emit(OpCode::Not, end),
+                }
+            }
+            ExprKind::Binary {
+                left,
+                operator,
+                token,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                if operator.is_comparison() && self.same_operands(left.span, right.span) {
+                    self.warn_at(
+                        *token,
+                        Rule::SelfComparison,
+                        String::from("Comparison of a value with itself."),
+                    );
+                }
+                self.binary(*operator, end);
+            }
+            ExprKind::Logical {
+                left,
+                operator,
+                token,
+                right,
+            } => {
+                self.expression(left);
+                match operator {
+                    LogicalOp::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse(0), *token);
+                        self.emit(OpCode::Pop, *token);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse(0), *token);
+                        let end_jump = self.emit_jump(OpCode::Jump(0), *token);
+                        self.patch_jump(else_jump);
+                        self.emit(OpCode::Pop, *token);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                }
+            }
+            ExprKind::Call { callee, arguments } => {
+                if let ExprKind::Variable(name) = &callee.kind {
+                    if !self.is_local(name.name) {
+                        self.global_calls.push((name.name, name.token));
+                    }
+                }
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.emit(OpCode::Call(arguments.len()), end);
+            }
+            ExprKind::Grouping(expr) => self.expression(expr),
+            ExprKind::Get { object, name } => {
+                self.expression(object);
+                let property = self.identifier_constant(name);
+                self.emit(OpCode::GetProperty(property), name.token);
+            }
+        }
+    }
+
+    fn binary(&mut self, operator: BinaryOp, end: usize) {
+        let ops: &[OpCode] = match operator {
+            BinaryOp::Add => &[OpCode::Add],
+            BinaryOp::Sub => &[OpCode::Sub],
+            BinaryOp::Mul => &[OpCode::Mul],
+            BinaryOp::Div => &[OpCode::Div],
+            BinaryOp::Mod => &[OpCode::Mod],
+            BinaryOp::IntDiv => &[OpCode::IntDiv],
+            BinaryOp::Pow => &[OpCode::Pow],
+            BinaryOp::Equal => &[OpCode::Equal],
+            BinaryOp::NotEqual => &[OpCode::Equal, OpCode::Not],
+            BinaryOp::Greater => &[OpCode::Greater],
+            BinaryOp::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            BinaryOp::Less => &[OpCode::Less],
+            BinaryOp::LessEqual => &[OpCode::Greater, OpCode::Not],
+        };
+        for op in ops {
+            self.emit(*op, end);
+        }
+    }
+
+    // Whether two operands are the same tokens without calls, which could
+    // return different values
+    fn same_operands(&self, left: Span, right: Span) -> bool {
+        let left = &self.tokens[left.start..left.end];
+        let right = &self.tokens[right.start..right.end];
+        let calls = left.windows(2).any(|pair| {
+            pair[1].ty() == TokenType::LeftParen
+                && matches!(pair[0].ty(), TokenType::Identifier | TokenType::RightParen)
+        });
+        !calls
+            && left.len() == right.len()
+            && left
+                .iter()
+                .zip(right)
+                .all(|(l, r)| l.ty() == r.ty() && l.lexeme() == r.lexeme())
+    }
+
+    // The instructions reading and writing a variable
+    fn variable_ops(&mut self, name: &Identifier<'a>) -> (OpCode, OpCode) {
+        match self.resolve_local(name) {
+            Some(slot) => (OpCode::GetLocal(slot), OpCode::SetLocal(slot)),
+            None => {
+                let global = self.identifier_constant(name);
+                (OpCode::GetGlobal(global), OpCode::SetGlobal(global))
+            }
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.state().locals.iter().any(|local| local.name == name)
+    }
+
+    fn resolve_local(&mut self, name: &Identifier<'a>) -> Option<usize> {
+        let found = self
+            .state()
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.name)
+            .map(|(slot, local)| (slot, local.depth.is_none()));
+        match found {
+            Some((slot, uninitialized)) => {
+                if uninitialized {
+                    self.error_at(
+                        name.token,
+                        "Can't read local variable in its own initializer.",
+                    );
+                }
+                Some(slot)
+            }
+            None => {
+                // Without closures a function only sees its own locals and the
+                // globals, refuse the locals of the enclosing functions
+                let enclosing = &self.functions[..self.functions.len() - 1];
+                let captured = enclosing
+                    .iter()
+                    .any(|state| state.locals.iter().any(|local| local.name == name.name));
+                if captured {
+                    self.error_at(
+                        name.token,
+                        "Can't capture local variables of an enclosing function.",
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    // `token` is the index of the token the instruction comes from, for the
+    // line table
+    fn emit(&mut self, opcode: OpCode, token: usize) {
+        let line = self.tokens[token].line();
+        self.chunk().write_opcode(opcode, line);
+    }
+
+    fn make_constant(&mut self, value: Value, token: usize) -> usize {
+        match self.chunk().add_constants(value) {
+            Some(index) => index,
+            None => {
+                if !std::mem::replace(&mut self.state_mut().constants_full, true) {
+                    self.error_at(
+                        token,
+                        &format!("Too many constants in one chunk (the limit is {MAX_CONSTANTS})."),
+                    );
+                }
+                0
+            }
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value, token: usize) {
+        let index = self.make_constant(value, token);
+        self.emit(OpCode::Constant(index), token);
+    }
+
+    fn emit_return(&mut self, token: usize) {
+        self.emit(OpCode::Null, token);
+        self.emit(OpCode::Return, token);
+    }
+
+    // Emits a jump with a placeholder target, to be set with `patch_jump`
+    fn emit_jump(&mut self, jump: OpCode, token: usize) -> usize {
+        self.emit(jump, token);
+        self.chunk().code_nb() - 1
+    }
+
+    // Makes the jump at `index` land on the next instruction to be emitted
+    fn patch_jump(&mut self, index: usize) {
+        let target = self.chunk().code_nb();
+        let jump = match self.chunk().get_instruction(index) {
+            OpCode::Jump(_) => OpCode::Jump(target),
+            OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(target),
+            _ => unreachable!(),
+        };
+        self.chunk().patch_opcode(index, jump);
+    }
+
+    fn error_at(&mut self, index: usize, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let error = CompileError::new(&self.tokens, index, message.to_string(), None);
+        self.errors.push(error);
+    }
+
+    // Warnings don't stop the compilation, they are only skipped in the
+    // statements with an error
+    fn warn_at(&mut self, index: usize, rule: Rule, message: String) {
+        if self.panic_mode {
+            return;
+        }
+        let warning = CompileError::new(&self.tokens, index, message, Some(rule));
+        self.warnings.push(warning);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> Result<Chunk, CompileErrors> {
+        let scanner = Scanner::new(source.to_string());
+        let compiler = Compiler::new(&scanner);
+        compiler.compile()
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        let chunk = compile("1 + 2 * -3;").unwrap();
+        let ops = chunk
+            .code()
+            .iter()
+            .map(|op| format!("{op:?}"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            ops,
+            vec![
+                "Constant(0)",
+                "Constant(1)",
+                "Constant(2)",
+                "Negate",
+                "Mul",
+                "Add",
+                "Pop",
+                "Null",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn print_statement() {
+        let chunk = compile("print 1;").unwrap();
+        let ops = chunk
+            .code()
+            .iter()
+            .map(|op| format!("{op:?}"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(ops, vec!["Constant(0)", "Print", "Null", "Return"]);
+    }
+
+    #[test]
+    fn errors_are_reported_once_per_statement() {
+        let errors = compile("1 + * 2 3; print (;").unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "[line 1] Error at '*': Expect expression.\n\
+             [line 1] Error at ';': Expect expression."
+        );
+    }
+
+    #[test]
+    fn missing_operand() {
+        let errors = compile("(1 +").unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "[line 1] Error at end: Expect expression."
+        );
+    }
+
+    #[test]
+    fn locals_debug_info() {
+        let scanner = Scanner::new(String::from("{ let a = 1; { let b = 2; print b; } }"));
+        let script = Compiler::new(&scanner).compile_script().unwrap();
+        // CONSTANT, CONSTANT, GET_LOCAL, PRINT, POP (b), POP (a), NULL, RETURN
+        let names = |ip| {
+            script
+                .locals_at(ip)
+                .iter()
+                .map(|local| local.name.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(names(0).is_empty());
+        assert_eq!(names(1), vec!["a"]);
+        assert_eq!(names(3), vec!["a", "b"]);
+        assert_eq!(names(4), vec!["a"]);
+        assert!(names(5).is_empty());
+    }
+
+    #[test]
+    fn constants_are_shared() {
+        let chunk = compile("let a = 1; a = a + 1; print \"a\" + \"a\";").unwrap();
+
+        // 1, "a" (the name and the string)
+        assert_eq!(chunk.constants().len(), 2);
+    }
+
+    #[test]
+    fn too_many_constants() {
+        let source = (0..=MAX_CONSTANTS)
+            .map(|num| format!("print {num};"))
+            .collect::<String>();
+        let errors = compile(&source).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            format!(
+                "[line 1] Error at '{MAX_CONSTANTS}': \
+                 Too many constants in one chunk (the limit is {MAX_CONSTANTS})."
+            )
+        );
+        assert!(compile(&source[..source.rfind("print").unwrap()]).is_ok());
+    }
+
+    #[test]
+    fn too_many_constants_are_reported_once() {
+        let source = (0..70_000)
+            .map(|num| format!("print {num}.5;"))
+            .collect::<String>();
+        let errors = compile(&source).unwrap_err();
+        assert_eq!(errors.0.len(), 1);
+        assert_eq!(
+            errors.0[0].message(),
+            format!("Too many constants in one chunk (the limit is {MAX_CONSTANTS}).")
+        );
+    }
+
+    #[test]
+    fn expression_with_params() {
+        let scanner = Scanner::new(String::from("a * b"));
+        let function = Compiler::new(&scanner)
+            .compile_expression(&["a", "b"])
+            .unwrap();
+        let ops = function
+            .chunk()
+            .code()
+            .iter()
+            .map(|op| format!("{op:?}"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(function.arity(), 2);
+        assert_eq!(ops, vec!["GetLocal(1)", "GetLocal(2)", "Mul", "Return"]);
+
+        let scanner = Scanner::new(String::from("a;"));
+        let errors = Compiler::new(&scanner)
+            .compile_expression(&["a"])
+            .unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "[line 1] Error at ';': Expect end of expression."
+        );
+    }
+}
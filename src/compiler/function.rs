@@ -0,0 +1,138 @@
+use super::chunk::Chunk;
+
+pub const SCRIPT_NAME: &str = "script";
+
+/// Debug information about a local variable: the variable lives in `slot` of
+/// the frame while the instruction pointer is in `start..end`.
+#[derive(Debug)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    name: String,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<LocalInfo>,
+    // Index of the module whose globals the function reads, 0 for the script
+    // the VM runs
+    module: usize,
+}
+
+impl Function {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: Vec::new(),
+            module: 0,
+        }
+    }
+
+    /// The implicit function wrapping the top-level code of a script.
+    pub fn script(chunk: Chunk) -> Self {
+        Self {
+            name: SCRIPT_NAME.to_string(),
+            arity: 0,
+            chunk,
+            locals: Vec::new(),
+            module: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn module(&self) -> usize {
+        self.module
+    }
+
+    pub fn set_module(&mut self, module: usize) {
+        self.module = module;
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.chunk
+    }
+
+    pub fn add_param(&mut self) -> usize {
+        self.arity += 1;
+        self.arity
+    }
+
+    /// Records that the local in `slot` becomes visible at the next
+    /// instruction.
+    pub fn open_local(&mut self, name: &str, slot: usize) {
+        self.locals.push(LocalInfo {
+            name: name.to_string(),
+            slot,
+            start: self.chunk.code_nb(),
+            end: usize::MAX,
+        });
+    }
+
+    /// Records that the local in `slot` goes out of scope at the next
+    /// instruction.
+    pub fn close_local(&mut self, slot: usize) {
+        let end = self.chunk.code_nb();
+        if let Some(local) = self
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot && local.end == usize::MAX)
+        {
+            local.end = end;
+        }
+    }
+
+    /// Moves the ranges of the locals after the code was rewritten,
+    /// `new_offset` gives the new offset of an instruction.
+    pub fn move_locals(&mut self, new_offset: impl Fn(usize) -> usize) {
+        for local in &mut self.locals {
+            local.start = new_offset(local.start);
+            if local.end != usize::MAX {
+                local.end = new_offset(local.end);
+            }
+        }
+    }
+
+    /// Locals visible when the instruction at `ip` is about to be executed,
+    /// in slot order.
+    pub fn locals_at(&self, ip: usize) -> Vec<&LocalInfo> {
+        let mut locals = self
+            .locals
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&ip))
+            .collect::<Vec<_>>();
+        locals.sort_by_key(|local| local.slot);
+        locals
+    }
+
+    pub fn into_chunk(self) -> Chunk {
+        self.chunk
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.name == SCRIPT_NAME {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
+}
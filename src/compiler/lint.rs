@@ -0,0 +1,336 @@
+use thiserror::Error;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use super::compilation::{CompileError, Compiler};
+use crate::scanner::token::{Token, TokenType};
+use crate::scanner::Scanner;
+
+/// Name of the configuration file, looked up in the directory of a script
+/// and its ancestors.
+pub const CONFIG_FILE: &str = "crox.toml";
+
+// Prefix of the comments silencing rules on their line, and on the next one
+// when the comment is on its own line
+const ALLOW_COMMENT: &str = "crox-allow:";
+
+/// Checks the compiler runs along with the compilation, reported as warnings.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Rule {
+    UnusedLocal,
+    UnreachableCode,
+    Shadowing,
+    AssignInCondition,
+    SelfComparison,
+    UndefinedGlobal,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [
+        Rule::UnusedLocal,
+        Rule::UnreachableCode,
+        Rule::Shadowing,
+        Rule::AssignInCondition,
+        Rule::SelfComparison,
+        Rule::UndefinedGlobal,
+    ];
+
+    /// Name of the rule in `crox.toml` and in the allow comments.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UnusedLocal => "unused-local",
+            Rule::UnreachableCode => "unreachable-code",
+            Rule::Shadowing => "shadowing",
+            Rule::AssignInCondition => "assign-in-condition",
+            Rule::SelfComparison => "self-comparison",
+            Rule::UndefinedGlobal => "undefined-global",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        Rule::ALL.into_iter().find(|rule| rule.name() == name)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("[line {line}] Error in {CONFIG_FILE}: {message}")]
+    Invalid { line: usize, message: String },
+}
+
+/// The rules turned off by a `crox.toml`:
+///
+/// ```toml
+/// [lint]
+/// shadowing = "allow"
+/// unused-local = "warn"
+/// ```
+///
+/// Other tables are ignored, every rule warns by default.
+#[derive(Default)]
+pub struct LintConfig {
+    allowed: HashSet<Rule>,
+}
+
+impl LintConfig {
+    pub fn parse(source: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let mut in_lint = false;
+        for (index, line) in source.lines().enumerate() {
+            let invalid = |message: String| ConfigError::Invalid {
+                line: index + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(table) = line.strip_prefix('[') {
+                match table.strip_suffix(']') {
+                    Some(table) => {
+                        in_lint = table.trim() == "lint";
+                        continue;
+                    }
+                    // Outside of [lint] it may be a value spanning lines
+                    None if !in_lint => continue,
+                    None => return Err(invalid(String::from("Expect ']' after table name."))),
+                }
+            }
+            // The other tables belong to other tools
+            if !in_lint {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid(String::from("Expect '=' after key.")));
+            };
+            let key = key.trim().trim_matches('"');
+            let rule =
+                Rule::from_name(key).ok_or_else(|| invalid(format!("Unknown rule '{key}'.")))?;
+            match value.trim().trim_matches('"') {
+                "allow" => config.allowed.insert(rule),
+                "warn" => config.allowed.remove(&rule),
+                value => {
+                    return Err(invalid(format!(
+                        "Expect \"allow\" or \"warn\" for '{key}', found '{value}'."
+                    )))
+                }
+            };
+        }
+        Ok(config)
+    }
+
+    /// Reads the `crox.toml` closest to `dir`, the default configuration
+    /// when there is none.
+    pub fn load(dir: &Path) -> Result<Self, ConfigError> {
+        match dir
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE))
+            .find(|path| path.is_file())
+        {
+            Some(path) => Self::parse(&std::fs::read_to_string(path)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn allows(&self, rule: Rule) -> bool {
+        self.allowed.contains(&rule)
+    }
+}
+
+// Rules listed by a `// crox-allow: rule, rule` comment
+fn allowed_rules(comment: &str) -> Vec<Rule> {
+    let text = comment.trim_start_matches('/').trim_start();
+    let Some(rules) = text.strip_prefix(ALLOW_COMMENT) else {
+        return Vec::new();
+    };
+    rules
+        .split(',')
+        .filter_map(|name| Rule::from_name(name.trim()))
+        .collect()
+}
+
+/// Compile errors and the warnings of the rules enabled by `config` and not
+/// silenced by a `// crox-allow:` comment, in source order. The token of
+/// each diagnostic is an index in the tokens of `Scanner::tokenize`.
+pub fn lint(source: &str, config: &LintConfig) -> Vec<CompileError> {
+    let scanner = Scanner::new(source.to_string());
+    lint_tokens(scanner.tokenize_with_comments(), config)
+}
+
+/// Same as `lint` with the tokens of `Scanner::tokenize_with_comments`.
+pub fn lint_tokens<'a>(
+    scanned: impl IntoIterator<Item = Token<'a>>,
+    config: &LintConfig,
+) -> Vec<CompileError> {
+    let mut tokens = Vec::new();
+    // Rules allowed on each line, 1-based like the diagnostics
+    let mut allowed = HashSet::new();
+    for token in scanned {
+        if token.ty() != TokenType::Comment {
+            tokens.push(token);
+            continue;
+        }
+        // A comment after some code only applies to its line
+        let line = token.line() + 1;
+        let trailing = tokens
+            .last()
+            .is_some_and(|last| last.line() == token.line());
+        let lines = if trailing {
+            line..=line
+        } else {
+            line..=line + 1
+        };
+        for rule in allowed_rules(token.lexeme()) {
+            allowed.extend(lines.clone().map(|line| (line, rule)));
+        }
+    }
+
+    Compiler::new(tokens)
+        .diagnostics()
+        .into_iter()
+        .filter(|diagnostic| match diagnostic.rule() {
+            Some(rule) => !config.allows(rule) && !allowed.contains(&(diagnostic.line(), rule)),
+            None => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn warnings(source: &str) -> Vec<String> {
+        lint(source, &LintConfig::default())
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn every_rule() {
+        let source = "fn f(a, _b) {
+  let unused = 1;
+  let x = a;
+  {
+    let x = 2;
+    print x;
+  }
+  if (x = 3) print x == x;
+  return x;
+  print a;
+}
+print g(f(1));";
+
+        assert_eq!(
+            warnings(source),
+            vec![
+                "[line 2] Warning at 'unused': Local variable 'unused' is never used. [unused-local]",
+                "[line 5] Warning at 'x': Variable 'x' shadows a variable of an enclosing scope. [shadowing]",
+                "[line 8] Warning at '=': Assignment in an 'if' condition, did you mean '=='? [assign-in-condition]",
+                "[line 8] Warning at '==': Comparison of a value with itself. [self-comparison]",
+                "[line 10] Warning at 'print': Unreachable code after 'return'. [unreachable-code]",
+                "[line 12] Warning at 'g': Call to undefined global 'g'. [undefined-global]",
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_are_not_self_comparisons() {
+        assert!(warnings("fn f() { return 1; } print f() == f(); print -1 < -1;").len() == 1);
+    }
+
+    #[test]
+    fn globals_can_be_declared_after_their_use() {
+        assert!(warnings("fn f() { return g(); } fn g() { return 1; } print f();").is_empty());
+        assert!(warnings("print approx_eq(1, 1.0, 0);").is_empty());
+    }
+
+    #[test]
+    fn errors_are_kept() {
+        assert_eq!(
+            warnings("{ let a = 1;\n print ; }"),
+            vec![
+                "[line 1] Warning at 'a': Local variable 'a' is never used. [unused-local]",
+                "[line 2] Error at ';': Expect expression."
+            ]
+        );
+    }
+
+    #[test]
+    fn allow_comments() {
+        let source = "{
+  // crox-allow: unused-local, shadowing
+  let a = 1;
+  let b = 2; // crox-allow: unused-local
+  let c = 3;
+}";
+
+        assert_eq!(
+            warnings(source),
+            vec!["[line 5] Warning at 'c': Local variable 'c' is never used. [unused-local]"]
+        );
+    }
+
+    #[test]
+    fn config_file() {
+        let config = LintConfig::parse(
+            "# Project settings
+[package]
+name = \"demo\"
+
+[lint]
+unused-local = \"allow\"   # scratch scripts
+shadowing = \"warn\"
+",
+        )
+        .unwrap();
+        assert!(config.allows(Rule::UnusedLocal));
+        assert!(!config.allows(Rule::Shadowing));
+        assert!(lint("{ let a = 1; }", &config).is_empty());
+
+        let error = LintConfig::parse("[lint]\nunused = \"allow\"")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "[line 2] Error in crox.toml: Unknown rule 'unused'."
+        );
+    }
+
+    #[test]
+    fn config_file_with_other_tables() {
+        let config = LintConfig::parse(
+            "[package]
+authors = [
+  \"a\",
+]
+matrix = [
+  [1, 2],
+  [3, 4]
+]
+
+[lint]
+shadowing = \"allow\"
+",
+        )
+        .unwrap();
+        assert!(config.allows(Rule::Shadowing));
+
+        let error = LintConfig::parse("[lint]\nshadowing\n").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "[line 2] Error in crox.toml: Expect '=' after key."
+        );
+    }
+}
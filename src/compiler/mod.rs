@@ -1,7 +1,13 @@
+pub mod ast;
 pub mod chunk;
-pub mod value;
 pub mod compilation;
+pub mod function;
+pub mod lint;
+pub mod optimizer;
+pub mod parser;
+pub mod value;
 
 pub use chunk::{Chunk, OpCode};
+pub use compilation::Compiler;
+pub use function::Function;
 pub use value::Value;
-pub use compilation::Compiler;
\ No newline at end of file
@@ -0,0 +1,446 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use super::ast::{BinaryOp, Expr, ExprKind, Literal, Program, Stmt, StmtKind, UnaryOp};
+use super::chunk::OpCode;
+use super::function::Function;
+use super::value::Value;
+
+/// How much work the compiler puts into the generated code, `-O0` and `-O1`
+/// on the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OptLevel {
+    /// The code follows the source, for debugging.
+    #[default]
+    O0,
+    /// Constant folding on the tree and peephole optimizations on the
+    /// chunks.
+    O1,
+}
+
+/// Replaces the arithmetic on literals by its result. The folding computes
+/// what the VM would: IEEE division, `%` with the sign of the dividend, and
+/// leaves the operations that fail at runtime, like `-"a"` or an integer
+/// overflow, to the VM.
+pub fn fold_constants(program: &mut Program) {
+    for stmt in &mut program.statements {
+        fold_stmt(stmt);
+    }
+}
+
+fn fold_stmt(stmt: &mut Stmt) {
+    match &mut stmt.kind {
+        StmtKind::Expression(expr) | StmtKind::Print(expr) => fold_expr(expr),
+        StmtKind::Let { initializer, .. } => {
+            if let Some(initializer) = initializer {
+                fold_expr(initializer);
+            }
+        }
+        StmtKind::Fn(function) => function.body.iter_mut().for_each(fold_stmt),
+        StmtKind::Block(statements) => statements.iter_mut().for_each(fold_stmt),
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            fold_expr(condition);
+            fold_stmt(then_branch);
+            if let Some(else_branch) = else_branch {
+                fold_stmt(else_branch);
+            }
+        }
+        StmtKind::While { condition, body } => {
+            fold_expr(condition);
+            fold_stmt(body);
+        }
+        StmtKind::Return(value) => {
+            if let Some(value) = value {
+                fold_expr(value);
+            }
+        }
+        StmtKind::Import { .. } => (),
+    }
+}
+
+fn fold_expr(expr: &mut Expr) {
+    let folded = match &mut expr.kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) => None,
+        ExprKind::Assign { value, .. } => {
+            fold_expr(value);
+            None
+        }
+        ExprKind::Unary {
+            operator, operand, ..
+        } => {
+            fold_expr(operand);
+            match (operator, &operand.kind) {
+                (UnaryOp::Negate, ExprKind::Literal(Literal::Number(num))) => {
+                    Some(Literal::Number(-num))
+                }
+                (UnaryOp::Negate, ExprKind::Literal(Literal::Int(num))) => {
+                    num.checked_neg().map(Literal::Int)
+                }
+                _ => None,
+            }
+        }
+        ExprKind::Binary {
+            left,
+            operator,
+            right,
+            ..
+        } => {
+            fold_expr(left);
+            fold_expr(right);
+            match (&left.kind, &right.kind) {
+                (ExprKind::Literal(left), ExprKind::Literal(right)) => {
+                    fold_binary(*operator, left, right)
+                }
+                _ => None,
+            }
+        }
+        ExprKind::Logical { left, right, .. } => {
+            fold_expr(left);
+            fold_expr(right);
+            None
+        }
+        ExprKind::Call { callee, arguments } => {
+            fold_expr(callee);
+            arguments.iter_mut().for_each(fold_expr);
+            None
+        }
+        ExprKind::Get { object, .. } => {
+            fold_expr(object);
+            None
+        }
+        ExprKind::Grouping(inner) => {
+            fold_expr(inner);
+            match &inner.kind {
+                ExprKind::Literal(literal) => Some(literal.clone()),
+                _ => None,
+            }
+        }
+    };
+    if let Some(literal) = folded {
+        expr.kind = ExprKind::Literal(literal);
+    }
+}
+
+fn fold_binary<'a>(
+    operator: BinaryOp,
+    left: &Literal<'a>,
+    right: &Literal<'a>,
+) -> Option<Literal<'a>> {
+    let number = |literal: &Literal| match literal {
+        Literal::Number(num) => Some(Value::Number(*num)),
+        Literal::Int(num) => Some(Value::Int(*num)),
+        _ => None,
+    };
+    if let (Literal::Str(lhs), Literal::Str(rhs)) = (left, right) {
+        return (operator == BinaryOp::Add)
+            .then(|| Literal::Str(Cow::Owned(format!("{lhs}{rhs}"))));
+    }
+    let op = match operator {
+        BinaryOp::Add => OpCode::Add,
+        BinaryOp::Sub => OpCode::Sub,
+        BinaryOp::Mul => OpCode::Mul,
+        BinaryOp::Div => OpCode::Div,
+        BinaryOp::Mod => OpCode::Mod,
+        BinaryOp::IntDiv => OpCode::IntDiv,
+        BinaryOp::Pow => OpCode::Pow,
+        _ => return None,
+    };
+    // Same computation as the VM, errors are left to it
+    match Value::arithmetic(op, &number(left)?, &number(right)?)? {
+        Ok(Value::Number(num)) => Some(Literal::Number(num)),
+        Ok(Value::Int(num)) => Some(Literal::Int(num)),
+        _ => None,
+    }
+}
+
+fn jump_target(instruction: OpCode) -> Option<usize> {
+    match instruction {
+        OpCode::Jump(target) | OpCode::JumpIfFalse(target) => Some(target),
+        _ => None,
+    }
+}
+
+// Instructions the execution can get to from the start of the chunk
+fn reachable(code: &[OpCode]) -> Vec<bool> {
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(offset) = pending.pop() {
+        if offset >= code.len() || reachable[offset] {
+            continue;
+        }
+        reachable[offset] = true;
+        match code[offset] {
+            OpCode::Return => (),
+            OpCode::Jump(target) => pending.push(target),
+            OpCode::JumpIfFalse(target) => pending.extend([target, offset + 1]),
+            _ => pending.push(offset + 1),
+        }
+    }
+    reachable
+}
+
+// Whether the instruction always leaves a number that can be negated twice
+// without error on the stack, `-i64::MIN` overflows
+fn pushes_number(instruction: OpCode, function: &Function) -> bool {
+    match instruction {
+        OpCode::Constant(index) => match function.chunk().get_constant(index) {
+            Value::Number(_) => true,
+            Value::Int(num) => num != i64::MIN,
+            _ => false,
+        },
+        // `/` always gives a float
+        OpCode::Div => true,
+        _ => false,
+    }
+}
+
+/// Rewrites the chunk of a function once it is generated: removes the
+/// unreachable instructions, the `Negate, Negate` pairs applied to a number
+/// and turns `Constant, Add` and `Constant, Sub` into `AddConstant` and
+/// `SubConstant`. Jump targets and the debug information of the locals are
+/// moved along.
+pub fn peephole(function: &mut Function) {
+    let chunk = function.chunk();
+    let code = chunk.code();
+    let targets = code
+        .iter()
+        .filter_map(|op| jump_target(*op))
+        .collect::<HashSet<_>>();
+    let reachable = reachable(code);
+
+    let mut new_code = Vec::with_capacity(code.len());
+    let mut new_lines = Vec::with_capacity(code.len());
+    // New offset of every instruction, the removed ones are mapped to the
+    // next instruction kept
+    let mut offsets = vec![0; code.len() + 1];
+    let mut offset = 0;
+    while offset < code.len() {
+        offsets[offset] = new_code.len();
+        if !reachable[offset] {
+            offset += 1;
+            continue;
+        }
+        let instruction = code[offset];
+        let next = code.get(offset + 1).copied();
+        // Nothing jumps between the two instructions of a pattern
+        let pair = next.filter(|_| !targets.contains(&(offset + 1)));
+        match (instruction, pair) {
+            (OpCode::Negate, Some(OpCode::Negate))
+                if offset > 0
+                    && !targets.contains(&offset)
+                    && pushes_number(code[offset - 1], function) =>
+            {
+                offsets[offset + 1] = new_code.len();
+                offset += 2;
+            }
+            (OpCode::Constant(index), Some(OpCode::Add | OpCode::Sub)) => {
+                let fused = match next {
+                    Some(OpCode::Add) => OpCode::AddConstant(index),
+                    _ => OpCode::SubConstant(index),
+                };
+                new_code.push(fused);
+                // Errors are reported on the line of the operator
+                new_lines.push(chunk.get_line(offset + 1));
+                offsets[offset + 1] = new_code.len();
+                offset += 2;
+            }
+            _ => {
+                new_code.push(instruction);
+                new_lines.push(chunk.get_line(offset));
+                offset += 1;
+            }
+        }
+    }
+    offsets[code.len()] = new_code.len();
+
+    for instruction in &mut new_code {
+        *instruction = match *instruction {
+            OpCode::Jump(target) => OpCode::Jump(offsets[target]),
+            OpCode::JumpIfFalse(target) => OpCode::JumpIfFalse(offsets[target]),
+            other => other,
+        };
+    }
+    function.chunk_mut().replace_code(new_code, new_lines);
+    function.move_locals(|offset| offsets[offset]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> Function {
+        let scanner = Scanner::new(source.to_string());
+        let mut compiler = Compiler::new(&scanner);
+        compiler.set_opt_level(OptLevel::O1);
+        compiler.compile_script().unwrap()
+    }
+
+    fn ops(function: &Function) -> Vec<String> {
+        function
+            .chunk()
+            .code()
+            .iter()
+            .map(|op| format!("{op:?}"))
+            .collect()
+    }
+
+    #[test]
+    fn folds_literals() {
+        let script = compile("print 2 * 3 + 1; print \"a\" + (\"b\" + \"c\"); print -(1 - 3);");
+
+        assert_eq!(
+            ops(&script),
+            vec![
+                "Constant(0)",
+                "Print",
+                "Constant(1)",
+                "Print",
+                "Constant(2)",
+                "Print",
+                "Null",
+                "Return"
+            ]
+        );
+        assert!(matches!(script.chunk().get_constant(0), Value::Int(7)));
+        assert_eq!(script.chunk().get_constant(1), Value::Str("abc".into()));
+        assert!(matches!(script.chunk().get_constant(2), Value::Int(2)));
+    }
+
+    #[test]
+    fn folding_keeps_ieee_semantics() {
+        let script =
+            compile("print 1 / 0; print -1 / 0; print 0 / 0; print -7 % 3; print 7.5 % -2;");
+        let constants = script.chunk().constants();
+
+        assert_eq!(constants[0].to_string(), "inf");
+        assert_eq!(constants[1].to_string(), "-inf");
+        assert_eq!(constants[2].to_string(), "NaN");
+        assert_eq!(constants[3].to_string(), "-1");
+        assert_eq!(constants[4].to_string(), "1.5");
+    }
+
+    #[test]
+    fn folds_integers() {
+        let script = compile("print 7 ~/ 2; print 7 / 2; print 1 + 0.5; print -7.5 ~/ 2;");
+        let constants = script.chunk().constants();
+
+        assert!(matches!(constants[0], Value::Int(3)));
+        assert!(matches!(constants[1], Value::Number(3.5)));
+        assert!(matches!(constants[2], Value::Number(1.5)));
+        assert!(matches!(constants[3], Value::Number(-3.0)));
+    }
+
+    #[test]
+    fn folds_powers() {
+        let script =
+            compile("print -2 ^ 2; print 2 ^ 3 ^ 2; print 2 ^ -1; print 2.5 ^ 2; print 2 ^ 63;");
+        let constants = script.chunk().constants();
+
+        assert!(matches!(constants[0], Value::Int(-4)));
+        assert!(matches!(constants[1], Value::Int(512)));
+        assert!(matches!(constants[2], Value::Number(0.5)));
+        assert!(matches!(constants[3], Value::Number(6.25)));
+        // Overflows at runtime
+        assert_eq!(
+            ops(&script)[8..],
+            [
+                "Constant(4)",
+                "Constant(5)",
+                "Pow",
+                "Print",
+                "Null",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors_are_not_folded() {
+        let script =
+            compile("print -\"a\"; print \"a\" * 2; print 9223372036854775807 + 1; print 1 ~/ 0;");
+
+        assert_eq!(
+            ops(&script),
+            vec![
+                "Constant(0)",
+                "Negate",
+                "Print",
+                "Constant(0)",
+                "Constant(1)",
+                "Mul",
+                "Print",
+                "Constant(2)",
+                "AddConstant(3)",
+                "Print",
+                "Constant(3)",
+                "Constant(4)",
+                "IntDiv",
+                "Print",
+                "Null",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn peephole_patterns() {
+        let script =
+            compile("let a = 1; print a + 1; print a - 2; print - -(a / 2); print - -(a * 2);");
+
+        assert_eq!(
+            ops(&script),
+            vec![
+                "Constant(1)",
+                "DefineGlobal(0)",
+                "GetGlobal(0)",
+                "AddConstant(1)",
+                "Print",
+                "GetGlobal(0)",
+                "SubConstant(2)",
+                "Print",
+                "GetGlobal(0)",
+                "Constant(2)",
+                "Div",
+                "Print",
+                // Kept, `- -(a * 2)` fails when the product is i64::MIN or
+                // a isn't a number
+                "GetGlobal(0)",
+                "Constant(2)",
+                "Mul",
+                "Negate",
+                "Negate",
+                "Print",
+                "Null",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_code_is_removed() {
+        let script = compile("fn f(a) {\n  if (a) return 1; else return 2;\n  print a;\n}");
+        let Value::Function(f) = script.chunk().get_constant(1) else {
+            panic!("Expected the function f");
+        };
+
+        assert_eq!(
+            ops(&f),
+            vec![
+                "GetLocal(1)",
+                "JumpIfFalse(5)",
+                "Pop",
+                "Constant(0)",
+                "Return",
+                "Pop",
+                "Constant(1)",
+                "Return"
+            ]
+        );
+    }
+}
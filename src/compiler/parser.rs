@@ -0,0 +1,718 @@
+use std::borrow::Cow;
+
+use super::ast::{
+    BinaryOp, Expr, ExprKind, FnDecl, Identifier, ImportKind, Literal, LogicalOp, Program, Span,
+    Stmt, StmtKind, UnaryOp,
+};
+use super::compilation::CompileError;
+use crate::scanner::token::{Token, TokenType};
+
+const MAX_ARGUMENTS: usize = 255;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Power,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        use Precedence::*;
+        match self {
+            None => Assignment,
+            Assignment => Or,
+            Or => And,
+            And => Equality,
+            Equality => Comparison,
+            Comparison => Term,
+            Term => Factor,
+            Factor => Unary,
+            Unary => Power,
+            Power => Call,
+            Call | Primary => Primary,
+        }
+    }
+
+    fn of(ty: TokenType) -> Self {
+        use TokenType::*;
+        match ty {
+            LeftParen | Dot => Precedence::Call,
+            Minus | Plus => Precedence::Term,
+            Star | Slash | Percent | TildeSlash => Precedence::Factor,
+            Carrot => Precedence::Power,
+            BangEq | DoubleEq => Precedence::Equality,
+            Greater | GreaterEq | Less | LessEq => Precedence::Comparison,
+            And => Precedence::And,
+            Or => Precedence::Or,
+            _ => Precedence::None,
+        }
+    }
+}
+
+// The error is already recorded, the statement is dropped
+struct SyntaxError;
+
+type Parsed<T> = Result<T, SyntaxError>;
+
+/// Builds the tree of a script, reporting the syntax errors once per
+/// statement like the compiler used to.
+pub struct Parser<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    index: usize,
+    // Index of the last token consumed, doc comments and errors are skipped
+    previous: usize,
+    // Doc comments right before the current token
+    docs: Vec<usize>,
+    errors: Vec<CompileError>,
+    panic_mode: bool,
+}
+
+impl<'t, 'a> Parser<'t, 'a> {
+    pub fn new(tokens: &'t [Token<'a>]) -> Self {
+        Self {
+            tokens,
+            index: 0,
+            previous: 0,
+            docs: Vec::new(),
+            errors: Vec::new(),
+            panic_mode: false,
+        }
+    }
+
+    pub fn parse(mut self) -> (Program<'a>, Vec<CompileError>) {
+        self.skip_tokens();
+        let mut statements = Vec::new();
+        while !self.matches(TokenType::Eof) {
+            statements.extend(self.declaration());
+        }
+        let program = Program {
+            statements,
+            eof: self.previous,
+        };
+        (program, self.errors)
+    }
+
+    /// Parses a single expression followed by the end of the tokens.
+    pub fn parse_expression(mut self) -> (Option<Expr<'a>>, Vec<CompileError>) {
+        self.skip_tokens();
+        let expr = self.expression().ok();
+        self.consume(TokenType::Eof, "Expect end of expression.")
+            .ok();
+        let expr = expr.filter(|_| self.errors.is_empty());
+        (expr, self.errors)
+    }
+
+    fn declaration(&mut self) -> Option<Stmt<'a>> {
+        let doc = self.take_doc();
+        let stmt = if self.matches(TokenType::Fn) {
+            self.fn_declaration(doc)
+        } else if self.matches(TokenType::Let) {
+            self.let_declaration(doc)
+        } else if self.matches(TokenType::Import) {
+            self.import_declaration()
+        } else if self.matches(TokenType::From) {
+            self.import_names_declaration()
+        } else {
+            self.statement()
+        };
+        if self.panic_mode {
+            self.synchronize();
+            return None;
+        }
+        stmt.ok()
+    }
+
+    fn fn_declaration(&mut self, doc: Vec<&'a str>) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        let name = self.identifier("Expect function name.")?;
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        let mut params = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                params.push(self.identifier("Expect parameter name.")?);
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        let body = self.block()?;
+        Ok(self.stmt(
+            StmtKind::Fn(FnDecl {
+                name,
+                params,
+                body,
+                doc,
+            }),
+            start,
+        ))
+    }
+
+    fn let_declaration(&mut self, doc: Vec<&'a str>) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        let name = self.identifier("Expect variable name.")?;
+        let initializer = if self.matches(TokenType::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::SemiColon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(self.stmt(
+            StmtKind::Let {
+                name,
+                initializer,
+                doc,
+            },
+            start,
+        ))
+    }
+
+    // `import "path" as name;`
+    fn import_declaration(&mut self) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        self.consume(TokenType::CroxStr, "Expect module path after 'import'.")?;
+        let (path, token) = (self.previous().lexeme(), self.previous);
+        self.consume(TokenType::As, "Expect 'as' after module path.")?;
+        let name = self.identifier("Expect module name after 'as'.")?;
+        self.consume(TokenType::SemiColon, "Expect ';' after import.")?;
+        let kind = ImportKind::Module(name);
+        Ok(self.stmt(StmtKind::Import { path, token, kind }, start))
+    }
+
+    // `from "path" import a, b;`
+    fn import_names_declaration(&mut self) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        self.consume(TokenType::CroxStr, "Expect module path after 'from'.")?;
+        let (path, token) = (self.previous().lexeme(), self.previous);
+        self.consume(TokenType::Import, "Expect 'import' after module path.")?;
+        let mut names = Vec::new();
+        loop {
+            names.push(self.identifier("Expect name to import.")?);
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::SemiColon, "Expect ';' after import.")?;
+        let kind = ImportKind::Names(names);
+        Ok(self.stmt(StmtKind::Import { path, token, kind }, start))
+    }
+
+    fn identifier(&mut self, message: &str) -> Parsed<Identifier<'a>> {
+        self.consume(TokenType::Identifier, message)?;
+        Ok(Identifier {
+            name: self.previous().lexeme(),
+            token: self.previous,
+        })
+    }
+
+    fn statement(&mut self) -> Parsed<Stmt<'a>> {
+        let start = self.index;
+        if self.matches(TokenType::Print) {
+            let value = self.expression()?;
+            self.consume(TokenType::SemiColon, "Expect ';' after value.")?;
+            Ok(self.stmt(StmtKind::Print(value), start))
+        } else if self.matches(TokenType::If) {
+            self.if_statement()
+        } else if self.matches(TokenType::While) {
+            self.while_statement()
+        } else if self.matches(TokenType::Return) {
+            self.return_statement()
+        } else if self.matches(TokenType::LeftBrace) {
+            let statements = self.block()?;
+            Ok(self.stmt(StmtKind::Block(statements), start))
+        } else {
+            let expr = self.expression()?;
+            self.consume(TokenType::SemiColon, "Expect ';' after expression.")?;
+            Ok(self.stmt(StmtKind::Expression(expr), start))
+        }
+    }
+
+    // The statements up to the closing brace, the opening one is consumed
+    fn block(&mut self) -> Parsed<Vec<Stmt<'a>>> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            statements.extend(self.declaration());
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn if_statement(&mut self) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.matches(TokenType::Else) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        let kind = StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        };
+        Ok(self.stmt(kind, start))
+    }
+
+    fn while_statement(&mut self) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let body = Box::new(self.statement()?);
+        Ok(self.stmt(StmtKind::While { condition, body }, start))
+    }
+
+    fn return_statement(&mut self) -> Parsed<Stmt<'a>> {
+        let start = self.previous;
+        let value = if self.matches(TokenType::SemiColon) {
+            None
+        } else {
+            let value = self.expression()?;
+            self.consume(TokenType::SemiColon, "Expect ';' after return value.")?;
+            Some(value)
+        };
+        Ok(self.stmt(StmtKind::Return(value), start))
+    }
+
+    fn stmt(&self, kind: StmtKind<'a>, start: usize) -> Stmt<'a> {
+        Stmt {
+            kind,
+            span: Span::new(start, self.previous + 1),
+        }
+    }
+
+    fn expr(&self, kind: ExprKind<'a>, start: usize) -> Expr<'a> {
+        Expr {
+            kind,
+            span: Span::new(start, self.previous + 1),
+        }
+    }
+
+    fn expression(&mut self) -> Parsed<Expr<'a>> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Parsed<Expr<'a>> {
+        let start = self.index;
+        self.advance();
+        let can_assign = precedence <= Precedence::Assignment;
+        let mut expr = self.prefix(can_assign)?;
+
+        while precedence <= Precedence::of(self.current().ty()) {
+            self.advance();
+            expr = self.infix(expr, start)?;
+        }
+
+        if can_assign && self.matches(TokenType::Equal) {
+            return Err(self.error("Invalid assignment target."));
+        }
+        Ok(expr)
+    }
+
+    fn prefix(&mut self, can_assign: bool) -> Parsed<Expr<'a>> {
+        use TokenType::*;
+
+        let start = self.previous;
+        let token = self.previous();
+        let kind = match token.ty() {
+            LeftParen => {
+                let expr = self.expression()?;
+                self.consume(RightParen, "Expect ')' after expression.")?;
+                ExprKind::Grouping(Box::new(expr))
+            }
+            Minus | Bang => {
+                let operator = match token.ty() {
+                    Minus => UnaryOp::Negate,
+                    _ => UnaryOp::Not,
+                };
+                let operand = self.parse_precedence(Precedence::Unary)?;
+                ExprKind::Unary {
+                    operator,
+                    token: start,
+                    operand: Box::new(operand),
+                }
+            }
+            Number(num) => ExprKind::Literal(Literal::Number(num)),
+            Integer(num) => ExprKind::Literal(Literal::Int(num)),
+            CroxStr => ExprKind::Literal(Literal::Str(Cow::Borrowed(token.lexeme()))),
+            True => ExprKind::Literal(Literal::True),
+            False => ExprKind::Literal(Literal::False),
+            Null => ExprKind::Literal(Literal::Null),
+            Identifier => {
+                let name = self::Identifier {
+                    name: token.lexeme(),
+                    token: start,
+                };
+                if can_assign && self.matches(Equal) {
+                    let token = self.previous;
+                    let value = self.expression()?;
+                    ExprKind::Assign {
+                        name,
+                        token,
+                        value: Box::new(value),
+                    }
+                } else {
+                    ExprKind::Variable(name)
+                }
+            }
+            _ => return Err(self.error("Expect expression.")),
+        };
+        Ok(self.expr(kind, start))
+    }
+
+    fn infix(&mut self, left: Expr<'a>, start: usize) -> Parsed<Expr<'a>> {
+        use TokenType::*;
+
+        let token = self.previous;
+        let ty = self.previous().ty();
+        let left = Box::new(left);
+        let kind = match ty {
+            LeftParen => ExprKind::Call {
+                callee: left,
+                arguments: self.arguments()?,
+            },
+            Dot => ExprKind::Get {
+                object: left,
+                name: self.identifier("Expect property name after '.'.")?,
+            },
+            And | Or => {
+                let operator = if ty == And {
+                    LogicalOp::And
+                } else {
+                    LogicalOp::Or
+                };
+                let right = self.parse_precedence(Precedence::of(ty))?;
+                ExprKind::Logical {
+                    left,
+                    operator,
+                    token,
+                    right: Box::new(right),
+                }
+            }
+            _ => {
+                let operator = match ty {
+                    Plus => BinaryOp::Add,
+                    Minus => BinaryOp::Sub,
+                    Star => BinaryOp::Mul,
+                    Slash => BinaryOp::Div,
+                    Percent => BinaryOp::Mod,
+                    TildeSlash => BinaryOp::IntDiv,
+                    Carrot => BinaryOp::Pow,
+                    DoubleEq => BinaryOp::Equal,
+                    BangEq => BinaryOp::NotEqual,
+                    Greater => BinaryOp::Greater,
+                    GreaterEq => BinaryOp::GreaterEqual,
+                    Less => BinaryOp::Less,
+                    LessEq => BinaryOp::LessEqual,
+                    _ => unreachable!(),
+                };
+                // `^` is right-associative, 2^3^2 is 2^(3^2)
+                let precedence = match operator {
+                    BinaryOp::Pow => Precedence::Power,
+                    _ => Precedence::of(ty).next(),
+                };
+                let right = self.parse_precedence(precedence)?;
+                ExprKind::Binary {
+                    left,
+                    operator,
+                    token,
+                    right: Box::new(right),
+                }
+            }
+        };
+        Ok(self.expr(kind, start))
+    }
+
+    fn arguments(&mut self) -> Parsed<Vec<Expr<'a>>> {
+        let mut arguments = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arguments.push(self.expression()?);
+                if arguments.len() == MAX_ARGUMENTS + 1 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+        Ok(arguments)
+    }
+
+    fn current(&self) -> Token<'a> {
+        self.tokens[self.index.min(self.tokens.len() - 1)]
+    }
+
+    fn previous(&self) -> Token<'a> {
+        self.tokens[self.previous]
+    }
+
+    fn advance(&mut self) {
+        if self.index < self.tokens.len() {
+            self.previous = self.index;
+            self.index += 1;
+        }
+        self.docs.clear();
+        self.skip_tokens();
+    }
+
+    // The scanner reports lexical errors as tokens, we report them here and
+    // move on to the next meaningful token. Doc comments are put aside for
+    // the declaration that may follow.
+    fn skip_tokens(&mut self) {
+        loop {
+            match self.current().ty() {
+                TokenType::Error(message) => {
+                    self.error_at_current(message);
+                }
+                TokenType::DocComment => self.docs.push(self.index),
+                _ => return,
+            }
+            self.index += 1;
+        }
+    }
+
+    // Text of the doc comments before the current token, one line each
+    fn take_doc(&mut self) -> Vec<&'a str> {
+        let docs = std::mem::take(&mut self.docs);
+        docs.iter()
+            .filter_map(|index| self.tokens[*index].doc_text())
+            .collect()
+    }
+
+    fn check(&self, ty: TokenType) -> bool {
+        self.current().ty() == ty
+    }
+
+    fn matches(&mut self, ty: TokenType) -> bool {
+        if !self.check(ty) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    // Skips tokens until a statement boundary so that one mistake doesn't
+    // produce a cascade of errors.
+    fn synchronize(&mut self) {
+        use TokenType::*;
+
+        self.panic_mode = false;
+        while !self.check(Eof) {
+            if self.previous().ty() == SemiColon {
+                return;
+            }
+            match self.current().ty() {
+                Class | Fn | Let | For | If | While | Print | Return | Import | From => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn consume(&mut self, ty: TokenType, message: &str) -> Parsed<()> {
+        if self.check(ty) {
+            self.advance();
+            Ok(())
+        } else {
+            Err(self.error_at_current(message))
+        }
+    }
+
+    fn error(&mut self, message: &str) -> SyntaxError {
+        self.error_at(self.previous, message)
+    }
+
+    fn error_at_current(&mut self, message: &str) -> SyntaxError {
+        self.error_at(self.index.min(self.tokens.len() - 1), message)
+    }
+
+    fn error_at(&mut self, index: usize, message: &str) -> SyntaxError {
+        if !self.panic_mode {
+            self.panic_mode = true;
+            self.errors.push(CompileError::new(
+                self.tokens,
+                index,
+                message.to_string(),
+                None,
+            ));
+        }
+        SyntaxError
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    #[test]
+    fn spans_and_precedence() {
+        let scanner = Scanner::new(String::from("x = -a + b * c;"));
+        let tokens = scanner.tokenize();
+        let (program, errors) = Parser::new(&tokens).parse();
+        assert!(errors.is_empty());
+
+        let StmtKind::Expression(expr) = &program.statements[0].kind else {
+            panic!("Expected an expression statement");
+        };
+        assert_eq!(program.statements[0].span, Span::new(0, 9));
+        let ExprKind::Assign { name, value, .. } = &expr.kind else {
+            panic!("Expected an assignment");
+        };
+        assert_eq!(name.name, "x");
+        let ExprKind::Binary {
+            left,
+            operator: BinaryOp::Add,
+            right,
+            ..
+        } = &value.kind
+        else {
+            panic!("Expected an addition");
+        };
+        assert_eq!(left.span, Span::new(2, 4));
+        assert!(matches!(left.kind, ExprKind::Unary { .. }));
+        assert_eq!(right.span, Span::new(5, 8));
+    }
+
+    #[test]
+    fn power_precedence() {
+        let scanner = Scanner::new(String::from("-2 ^ 3 ^ 2"));
+        let tokens = scanner.tokenize();
+        let (expr, _) = Parser::new(&tokens).parse_expression();
+        let expr = expr.unwrap();
+
+        // -(2 ^ (3 ^ 2))
+        let ExprKind::Unary { operand, .. } = &expr.kind else {
+            panic!("Expected a negation");
+        };
+        let ExprKind::Binary {
+            left,
+            operator: BinaryOp::Pow,
+            right,
+            ..
+        } = &operand.kind
+        else {
+            panic!("Expected a power");
+        };
+        assert_eq!(left.span, Span::new(1, 2));
+        assert_eq!(right.span, Span::new(3, 6));
+        assert!(matches!(
+            right.kind,
+            ExprKind::Binary {
+                operator: BinaryOp::Pow,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn doc_comments_are_attached_to_declarations() {
+        let scanner = Scanner::new(String::from(
+            "/// Adds
+/// two numbers.
+fn add(a, b) { return a /// ignored
+ + b; }
+/// The answer.
+let answer = 42;
+/// Not a declaration.
+print answer;
+let undocumented = 1;",
+        ));
+        let tokens = scanner.tokenize();
+        let (program, errors) = Parser::new(&tokens).parse();
+        assert!(errors.is_empty());
+
+        let docs = program
+            .statements
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::Fn(function) => function.doc.clone(),
+                StmtKind::Let { doc, .. } => doc.clone(),
+                _ => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            docs,
+            vec![
+                vec!["Adds", "two numbers."],
+                vec!["The answer."],
+                vec![],
+                vec![]
+            ]
+        );
+        // Spans end on the last token of the statement, not on a comment
+        assert_eq!(tokens[program.statements[2].span.last()].lexeme(), ";");
+    }
+
+    #[test]
+    fn statements_with_errors_are_dropped() {
+        let scanner = Scanner::new(String::from("print 1; let = 2; print 3;"));
+        let tokens = scanner.tokenize();
+        let (program, errors) = Parser::new(&tokens).parse();
+
+        assert_eq!(program.statements.len(), 2);
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 1] Error at '=': Expect variable name."
+        );
+    }
+
+    #[test]
+    fn imports_and_properties() {
+        let scanner = Scanner::new(String::from(
+            "import \"lib/math.crox\" as math; from \"util\" import a, b; print math.pi;",
+        ));
+        let tokens = scanner.tokenize();
+        let (program, errors) = Parser::new(&tokens).parse();
+        assert!(errors.is_empty());
+
+        let StmtKind::Import {
+            path,
+            kind: ImportKind::Module(name),
+            ..
+        } = &program.statements[0].kind
+        else {
+            panic!("Expected an import");
+        };
+        assert_eq!((*path, name.name), ("lib/math.crox", "math"));
+        let StmtKind::Import {
+            path,
+            kind: ImportKind::Names(names),
+            ..
+        } = &program.statements[1].kind
+        else {
+            panic!("Expected a from-import");
+        };
+        assert_eq!(*path, "util");
+        assert_eq!(names.iter().map(|n| n.name).collect::<Vec<_>>(), ["a", "b"]);
+        let StmtKind::Print(expr) = &program.statements[2].kind else {
+            panic!("Expected a print statement");
+        };
+        let ExprKind::Get { object, name } = &expr.kind else {
+            panic!("Expected a property access");
+        };
+        assert!(matches!(object.kind, ExprKind::Variable(_)));
+        assert_eq!(name.name, "pi");
+    }
+}
@@ -1,31 +1,188 @@
-#[derive(Debug, Clone, Copy)]
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use super::chunk::OpCode;
+use super::function::Function;
+
+pub const INTEGER_OVERFLOW: &str = "Integer overflow.";
+pub const DIVISION_BY_ZERO: &str = "Integer division by zero.";
+
+#[derive(Debug, Clone)]
 pub enum Value {
+    Null,
+    Bool(bool),
     Number(f64),
+    Int(i64),
+    Str(Rc<str>),
+    Function(Rc<Function>),
+    Native(Native),
+    Module(Rc<Module>),
 }
 
-impl PartialEq for Value {
-    fn eq(&self, other: &Self) -> bool {
-        let Value::Number(lhs) = self;
-        let Value::Number(rhs) = other;
-        lhs.approximate_eq(*rhs)
+/// A module imported by a script, the VM keeps its globals.
+#[derive(Debug)]
+pub struct Module {
+    // Index of the module in the VM
+    pub id: usize,
+    // Path of the first import of the module
+    pub name: Rc<str>,
+}
+
+/// A function of the standard library, written in Rust.
+#[derive(Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: fn(&[Value]) -> Result<Value, String>,
+}
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+// `base ^ exp` for a non-negative `exp`, `None` on overflow
+fn int_pow(base: i64, exp: i64) -> Option<i64> {
+    match u32::try_from(exp) {
+        Ok(exp) => base.checked_pow(exp),
+        // Only these bases don't overflow past 2^32
+        Err(_) => match base {
+            0 | 1 => Some(base),
+            -1 => Some(if exp % 2 == 0 { 1 } else { -1 }),
+            _ => None,
+        },
+    }
+}
+
+// Compares an int and a float without rounding the int, `None` for NaN
+fn compare_int_float(int: i64, float: f64) -> Option<Ordering> {
+    // 2^63, the first float past i64::MAX
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if float.is_nan() {
+        return None;
+    }
+    if float >= LIMIT {
+        return Some(Ordering::Less);
     }
+    if float < -LIMIT {
+        return Some(Ordering::Greater);
+    }
+    let truncated = float.trunc();
+    // The part after the point breaks the tie
+    let fraction = 0.0.partial_cmp(&(float - truncated))?;
+    Some(int.cmp(&(truncated as i64)).then(fraction))
 }
 
-pub trait ApproximateEq {
-    const EPSILON: f64 = 0.00000001;
-    fn approximate_eq(self, other: Self) -> bool;
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Null | Value::Bool(false))
+    }
+
+    /// Numeric order of two numbers, ints and floats compare exactly. `None`
+    /// when a value isn't a number or is NaN, which is neither equal to,
+    /// greater nor less than any number, itself included.
+    pub fn compare_numbers(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+        match (lhs, rhs) {
+            (Value::Int(lhs), Value::Int(rhs)) => Some(lhs.cmp(rhs)),
+            (Value::Number(lhs), Value::Number(rhs)) => lhs.partial_cmp(rhs),
+            (Value::Int(lhs), Value::Number(rhs)) => compare_int_float(*lhs, *rhs),
+            (Value::Number(lhs), Value::Int(rhs)) => {
+                compare_int_float(*rhs, *lhs).map(Ordering::reverse)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Number(num) => Some(*num),
+            Value::Int(num) => Some(*num as f64),
+            _ => None,
+        }
+    }
+
+    /// Applies an arithmetic or comparison operator of the VM (`Add`, `Sub`,
+    /// `Mul`, `Div`, `IntDiv`, `Mod`, `Pow`, `Greater` or `Less`) to two numbers,
+    /// `None` when an operand isn't a number. Ints stay ints, the overflows
+    /// are errors, and an int meeting a float is converted to a float. `/`
+    /// always divides floats and an int to a negative power is a float, the
+    /// comparisons follow `compare_numbers`.
+    pub fn arithmetic(op: OpCode, lhs: &Value, rhs: &Value) -> Option<Result<Value, &'static str>> {
+        use OpCode::*;
+
+        if matches!(op, Greater | Less) {
+            if lhs.as_float().is_none() || rhs.as_float().is_none() {
+                return None;
+            }
+            let ordering = Value::compare_numbers(lhs, rhs);
+            let expected = match op {
+                Greater => Ordering::Greater,
+                _ => Ordering::Less,
+            };
+            return Some(Ok(Value::Bool(ordering == Some(expected))));
+        }
+        if let (Value::Int(lhs), Value::Int(rhs)) = (lhs, rhs) {
+            let (lhs, rhs) = (*lhs, *rhs);
+            let result = match op {
+                Add => lhs.checked_add(rhs),
+                Sub => lhs.checked_sub(rhs),
+                Mul => lhs.checked_mul(rhs),
+                IntDiv | Mod if rhs == 0 => return Some(Err(DIVISION_BY_ZERO)),
+                IntDiv => lhs.checked_div(rhs),
+                // i64::MIN % -1 is 0, only the division overflows
+                Mod => Some(lhs.wrapping_rem(rhs)),
+                Div => return Some(Ok(Value::Number(lhs as f64 / rhs as f64))),
+                Pow if rhs < 0 => return Some(Ok(Value::Number((lhs as f64).powf(rhs as f64)))),
+                Pow => int_pow(lhs, rhs),
+                _ => return None,
+            };
+            return Some(result.map(Value::Int).ok_or(INTEGER_OVERFLOW));
+        }
+        let (lhs, rhs) = (lhs.as_float()?, rhs.as_float()?);
+        let result = match op {
+            Add => Value::Number(lhs + rhs),
+            Sub => Value::Number(lhs - rhs),
+            Mul => Value::Number(lhs * rhs),
+            Div => Value::Number(lhs / rhs),
+            IntDiv => Value::Number((lhs / rhs).trunc()),
+            Mod => Value::Number(lhs % rhs),
+            Pow => Value::Number(lhs.powf(rhs)),
+            _ => return None,
+        };
+        Some(Ok(result))
+    }
 }
 
-impl ApproximateEq for f64 {
-    fn approximate_eq(self, other: Self) -> bool {
-        (self - other).abs() < <Self as ApproximateEq>::EPSILON
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => true,
+            (Value::Bool(lhs), Value::Bool(rhs)) => lhs == rhs,
+            // IEEE equality: NaN is never equal, 0 and -0 are
+            (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => {
+                Value::compare_numbers(self, other) == Some(Ordering::Equal)
+            }
+            (Value::Str(lhs), Value::Str(rhs)) => lhs == rhs,
+            (Value::Function(lhs), Value::Function(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Value::Native(lhs), Value::Native(rhs)) => lhs.name == rhs.name,
+            (Value::Module(lhs), Value::Module(rhs)) => lhs.id == rhs.id,
+            _ => false,
+        }
     }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
             Value::Number(n) => write!(f, "{}", n),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name),
+            Value::Module(module) => write!(f, "<module \"{}\">", module.name),
         }
     }
 }
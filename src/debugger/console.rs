@@ -0,0 +1,330 @@
+use std::io::{self, BufRead, Write};
+
+use super::session::{location, Breakpoint, Resume, Session, Stop};
+use crate::compiler::{Compiler, Function};
+use crate::interpreter::virtual_machine::{ExecutionState, VM};
+use crate::scanner::Scanner;
+
+const HELP: &str = "Commands:
+    break, b     [line|function] stop at a line or when a function is called,
+                 list the breakpoints without argument
+    delete, d    [n] delete a breakpoint, all of them without argument
+    step, s      run until the next line, entering calls
+    next, n      run until the next line of this function
+    finish, out  run until this function returns
+    continue, c  run until a breakpoint or the end of the script
+    print, p     [name] print a variable, locals first then globals
+    locals       print the locals of the current function
+    globals      print the global variables
+    backtrace,bt print the call stack
+    eval, e      [expression] print the value of an expression
+    list, l      print the source around the current line
+    help, h      print this message
+    quit, q      stop debugging";
+
+// Lines printed around the current one by `list`
+const LIST_CONTEXT: usize = 5;
+
+/// Command line debugger: runs a script instruction by instruction and
+/// reads commands whenever the script is paused.
+pub struct Debugger<'a> {
+    session: Session,
+    source: Vec<&'a str>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: VM, source: &'a str) -> Self {
+        Self {
+            session: Session::new(vm),
+            source: source.lines().collect(),
+        }
+    }
+
+    /// Loads `script` paused on its first line and executes the commands
+    /// read from `input` until `quit` or the end of the input.
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        script: Function,
+        input: R,
+        mut out: W,
+    ) -> io::Result<()> {
+        if let ExecutionState::Errored(error) = self.session.load(script) {
+            writeln!(out, "{error}")?;
+            writeln!(out, "The script stopped on a runtime error.")?;
+            return Ok(());
+        }
+        self.show_location(&mut out)?;
+
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(crox) ")?;
+            out.flush()?;
+            let Some(line) = lines.next() else {
+                writeln!(out)?;
+                return Ok(());
+            };
+            let line = line?;
+            let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+                Some((command, argument)) => (command, argument.trim()),
+                None => (line.trim(), ""),
+            };
+            match command {
+                "" => (),
+                "break" | "b" => self.add_breakpoint(argument, &mut out)?,
+                "delete" | "d" => self.delete_breakpoint(argument, &mut out)?,
+                "step" | "s" => self.resume(Resume::StepIn, &mut out)?,
+                "next" | "n" => self.resume(Resume::StepOver, &mut out)?,
+                "finish" | "out" => self.resume(Resume::StepOut, &mut out)?,
+                "continue" | "c" => self.resume(Resume::Continue, &mut out)?,
+                "print" | "p" => self.print(argument, &mut out)?,
+                "locals" => self.locals(&mut out)?,
+                "globals" => {
+                    for (name, value) in self.session.vm().globals() {
+                        writeln!(out, "{name} = {value}")?;
+                    }
+                }
+                "backtrace" | "bt" => {
+                    for (index, frame) in self.session.vm().frames().iter().rev().enumerate() {
+                        writeln!(out, "#{index} {}", location(frame))?;
+                    }
+                }
+                "eval" | "e" => self.eval(argument, &mut out)?,
+                "list" | "l" => self.list(&mut out)?,
+                "help" | "h" => writeln!(out, "{HELP}")?,
+                "quit" | "q" => return Ok(()),
+                command => writeln!(out, "Unknown command '{command}', try 'help'.")?,
+            }
+        }
+    }
+
+    fn resume<W: Write>(&mut self, mode: Resume, out: &mut W) -> io::Result<()> {
+        match self.session.resume(mode) {
+            None => writeln!(out, "The script is not running."),
+            Some(Stop::Finished) => writeln!(out, "The script finished."),
+            Some(Stop::Errored(error)) => {
+                writeln!(out, "{error}")?;
+                writeln!(out, "The script stopped on a runtime error.")
+            }
+            Some(Stop::Breakpoint(number)) => {
+                if let Some((_, breakpoint)) = self
+                    .session
+                    .breakpoints()
+                    .iter()
+                    .find(|(n, _)| *n == number)
+                {
+                    writeln!(out, "Breakpoint {number} at {breakpoint}.")?;
+                }
+                self.show_location(out)
+            }
+            Some(Stop::Step) => self.show_location(out),
+        }
+    }
+
+    fn add_breakpoint<W: Write>(&mut self, argument: &str, out: &mut W) -> io::Result<()> {
+        if argument.is_empty() {
+            if self.session.breakpoints().is_empty() {
+                return writeln!(out, "No breakpoints.");
+            }
+            for (number, breakpoint) in self.session.breakpoints() {
+                writeln!(out, "{number}: {breakpoint}")?;
+            }
+            return Ok(());
+        }
+        let breakpoint = match argument.parse::<usize>() {
+            Ok(0) => return writeln!(out, "Lines start at 1."),
+            Ok(line) => Breakpoint::Line(line - 1),
+            Err(_) => Breakpoint::Function(argument.to_string()),
+        };
+        let description = breakpoint.to_string();
+        let number = self.session.add_breakpoint(breakpoint);
+        writeln!(out, "Breakpoint {number} at {description}.")
+    }
+
+    fn delete_breakpoint<W: Write>(&mut self, argument: &str, out: &mut W) -> io::Result<()> {
+        if argument.is_empty() {
+            self.session.clear_breakpoints();
+            return writeln!(out, "Deleted all breakpoints.");
+        }
+        let deleted = argument
+            .parse()
+            .is_ok_and(|number| self.session.remove_breakpoint(number));
+        if deleted {
+            writeln!(out, "Deleted breakpoint {argument}.")
+        } else {
+            writeln!(out, "No breakpoint number {argument}.")
+        }
+    }
+
+    fn print<W: Write>(&self, name: &str, out: &mut W) -> io::Result<()> {
+        let frame = self.session.vm().current_frame();
+        // Inner blocks come last and shadow the outer ones
+        let local = frame
+            .as_ref()
+            .and_then(|frame| frame.locals.iter().rev().find(|(local, _)| *local == name))
+            .map(|(_, value)| *value);
+        match local.or_else(|| self.session.vm().global(name)) {
+            Some(value) => writeln!(out, "{name} = {value}"),
+            None => writeln!(out, "Undefined variable '{name}'."),
+        }
+    }
+
+    fn locals<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let Some(frame) = self.session.vm().current_frame() else {
+            return writeln!(out, "The script is not running.");
+        };
+        if frame.locals.is_empty() {
+            return writeln!(out, "No locals.");
+        }
+        for (name, value) in &frame.locals {
+            writeln!(out, "{name} = {value}")?;
+        }
+        Ok(())
+    }
+
+    // The expression is compiled as a function taking the locals in scope as
+    // parameters and called on a copy of the globals.
+    fn eval<W: Write>(&self, expression: &str, out: &mut W) -> io::Result<()> {
+        let frame = self.session.vm().current_frame();
+        let locals = frame.as_ref().map_or(&[][..], |frame| &frame.locals[..]);
+        let names = locals.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        let args = locals.iter().map(|(_, value)| (*value).clone()).collect();
+
+        let scanner = Scanner::new(expression.to_string());
+        match Compiler::new(&scanner).compile_expression(&names) {
+            Ok(function) => match self.session.vm().evaluate(function, args) {
+                Ok(value) => writeln!(out, "{value}"),
+                Err(error) => writeln!(out, "{error}"),
+            },
+            Err(errors) => writeln!(out, "{errors}"),
+        }
+    }
+
+    fn list<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let Some(frame) = self.session.vm().current_frame() else {
+            return writeln!(out, "The script is not running.");
+        };
+        let from = frame.line.saturating_sub(LIST_CONTEXT);
+        let to = (frame.line + LIST_CONTEXT + 1).min(self.source.len());
+        for line in from..to {
+            let marker = if line == frame.line { "->" } else { "  " };
+            writeln!(out, "{marker} {:4} | {}", line + 1, self.source[line])?;
+        }
+        Ok(())
+    }
+
+    fn show_location<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let Some(frame) = self.session.vm().current_frame() else {
+            return Ok(());
+        };
+        writeln!(out, "{}", location(&frame))?;
+        if let Some(text) = self.source.get(frame.line) {
+            writeln!(out, "{:4} | {}", frame.line + 1, text.trim())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::output::MemorySink;
+
+    const SCRIPT: &str = "fn add(a, b) {
+  let sum = a + b;
+  return sum;
+}
+let x = 1;
+let y = add(x, 2);
+print y;
+";
+
+    // Runs the commands and returns what the debugger printed, without the
+    // prompts, along with the output of the script.
+    fn debug(commands: &str) -> (String, String) {
+        let scanner = Scanner::new(SCRIPT.to_string());
+        let script = Compiler::new(&scanner).compile_script().unwrap();
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+
+        let mut out = Vec::new();
+        Debugger::new(vm, SCRIPT)
+            .run(script, commands.as_bytes(), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap().replace("(crox) ", "");
+        (out, output.contents())
+    }
+
+    #[test]
+    fn step_into_and_out_of_a_call() {
+        let (out, output) = debug("n\nn\ns\nlocals\nbt\nfinish\nc\n");
+        assert_eq!(
+            out,
+            "[line 4] in script
+   4 | }
+[line 5] in script
+   5 | let x = 1;
+[line 6] in script
+   6 | let y = add(x, 2);
+[line 2] in add()
+   2 | let sum = a + b;
+a = 1
+b = 2
+#0 [line 2] in add()
+#1 [line 6] in script
+[line 6] in script
+   6 | let y = add(x, 2);
+The script finished.
+
+"
+        );
+        assert_eq!(output, "3\n");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (out, _) = debug("b add\nb 7\nb\nc\nc\np y\nc\n");
+        assert_eq!(
+            out,
+            "[line 4] in script
+   4 | }
+Breakpoint 1 at add().
+Breakpoint 2 at line 7.
+1: add()
+2: line 7
+Breakpoint 1 at add().
+[line 2] in add()
+   2 | let sum = a + b;
+Breakpoint 2 at line 7.
+[line 7] in script
+   7 | print y;
+y = 3
+The script finished.
+
+"
+        );
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let (out, _) = debug("b 3\nd 1\nn\nn\nn\nglobals\n");
+        assert!(out.ends_with(
+            "[line 7] in script
+   7 | print y;
+add = <fn add>
+x = 1
+y = 3
+
+"
+        ));
+        assert!(!out.contains("in add()"));
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        let (out, _) = debug("b 3\nc\ne sum * 10 + x\ne sum +\ne undefined\np sum\n");
+        assert!(out.contains("[line 3] in add()\n   3 | return sum;\n31\n"));
+        assert!(out.contains("[line 1] Error at end: Expect expression.\n"));
+        assert!(out.ends_with("sum = 3\n\n"));
+    }
+}
@@ -0,0 +1,529 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use serde_json::{json, Value as Json};
+
+use super::session::{Breakpoint, Resume, Session, Stop};
+use crate::compiler::Compiler;
+use crate::framing::{read_message, write_message};
+use crate::interpreter::output::MemorySink;
+use crate::interpreter::virtual_machine::{ExecutionState, VM};
+use crate::scanner::Scanner;
+
+// Scripts run on a single thread
+const THREAD_ID: u64 = 1;
+// Variables reference of the globals, the locals of a frame use the depth of
+// the frame plus one
+const GLOBALS_REFERENCE: u64 = 1;
+
+/// Debug Adapter Protocol server: editors send requests and receive
+/// responses and events, both framed like LSP messages.
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: u64,
+    session: Session,
+    // What the script printed, sent to the client as output events
+    output: MemorySink,
+    program: Option<String>,
+    stop_on_entry: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> Self {
+        let output = MemorySink::default();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(output.clone()));
+        Self {
+            out,
+            seq: 0,
+            session: Session::new(vm),
+            output,
+            program: None,
+            stop_on_entry: false,
+        }
+    }
+
+    /// Handles the requests read from `input` until `disconnect` or the end
+    /// of the input.
+    pub fn run<R: BufRead>(&mut self, mut input: R) -> io::Result<()> {
+        while let Some(message) = read_message(&mut input)? {
+            if message["type"] == "request" && !self.handle(&message)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false once the client disconnected
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        match command {
+            "initialize" => {
+                let capabilities = json!({ "supportsConfigurationDoneRequest": true });
+                self.respond(request, Ok(capabilities))?;
+            }
+            "launch" => {
+                let result = self.launch(arguments);
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                // Breakpoints are mapped to the lines of the compiled script,
+                // the client sends them once it is launched
+                if launched {
+                    self.event("initialized", json!({}))?;
+                }
+            }
+            "setBreakpoints" => {
+                let breakpoints = self.set_breakpoints(arguments);
+                self.respond(request, Ok(breakpoints))?;
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(json!({})))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    self.resume(Resume::Continue)?;
+                }
+            }
+            "threads" => {
+                let threads = json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] });
+                self.respond(request, Ok(threads))?;
+            }
+            "stackTrace" => {
+                let stack_trace = self.stack_trace();
+                self.respond(request, Ok(stack_trace))?;
+            }
+            "scopes" => {
+                let scopes = self.scopes(arguments);
+                self.respond(request, Ok(scopes))?;
+            }
+            "variables" => {
+                let variables = self.variables(arguments);
+                self.respond(request, variables)?;
+            }
+            "next" | "stepIn" | "stepOut" | "continue" => {
+                let mode = match command {
+                    "next" => Resume::StepOver,
+                    "stepIn" => Resume::StepIn,
+                    "stepOut" => Resume::StepOut,
+                    _ => Resume::Continue,
+                };
+                self.respond(request, Ok(json!({ "allThreadsContinued": true })))?;
+                self.resume(mode)?;
+            }
+            "disconnect" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            command => {
+                self.respond(request, Err(format!("Unsupported request '{command}'.")))?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.out, &message)
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("Expected the path of the program to launch.")?;
+        let source = fs::read_to_string(program)
+            .map_err(|error| format!("Can't read {program}: {error}."))?;
+        let scanner = Scanner::new(source);
+        let script = Compiler::new(&scanner)
+            .compile_script()
+            .map_err(|errors| errors.to_string())?;
+
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.program = Some(program.to_string());
+        self.session.vm_mut().set_script_path(program);
+        match self.session.load(script) {
+            ExecutionState::Errored(error) => Err(error.to_string()),
+            _ => Ok(json!({})),
+        }
+    }
+
+    // The breakpoints of the request replace the previous ones, each one is
+    // moved to the first line with code from its line on.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        self.session.clear_breakpoints();
+        let lines = arguments["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let breakpoints = lines
+            .into_iter()
+            .map(|line| {
+                let code_line = (line as usize)
+                    .checked_sub(1)
+                    .and_then(|line| self.session.code_line(line));
+                match code_line {
+                    Some(code_line) => {
+                        let id = self.session.add_breakpoint(Breakpoint::Line(code_line));
+                        json!({ "id": id, "verified": true, "line": code_line + 1 })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "No code on or after this line.",
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn source(&self) -> Json {
+        let path = self.program.as_deref().unwrap_or_default();
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        json!({ "name": name, "path": path })
+    }
+
+    // Frames are identified by their depth, the innermost frame comes first
+    fn stack_trace(&self) -> Json {
+        let frames = self.session.vm().frames();
+        let stack_frames = frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                json!({
+                    "id": frame.depth,
+                    "name": frame.function.name(),
+                    "line": frame.line + 1,
+                    "column": 1,
+                    "source": self.source(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "stackFrames": stack_frames, "totalFrames": frames.len() })
+    }
+
+    fn scopes(&self, arguments: &Json) -> Json {
+        let depth = arguments["frameId"].as_u64().unwrap_or_default();
+        json!({
+            "scopes": [
+                { "name": "Locals", "variablesReference": depth + 1, "expensive": false },
+                { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+            ]
+        })
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let reference = arguments["variablesReference"].as_u64().unwrap_or_default();
+        let vm = self.session.vm();
+        let variables = if reference == GLOBALS_REFERENCE {
+            vm.globals()
+        } else {
+            let frames = vm.frames();
+            let frame = (reference as usize)
+                .checked_sub(2)
+                .and_then(|index| frames.get(index))
+                .ok_or_else(|| format!("No variables with reference {reference}."))?;
+            frame.locals.clone()
+        };
+        let variables = variables
+            .into_iter()
+            .map(|(name, value)| {
+                json!({ "name": name, "value": value.to_string(), "variablesReference": 0 })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn resume(&mut self, mode: Resume) -> io::Result<()> {
+        let stop = self.session.resume(mode);
+        let output = self.output.take();
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+        match stop {
+            None => Ok(()),
+            Some(Stop::Step) => self.stopped("step", None),
+            Some(Stop::Breakpoint(number)) => self.stopped("breakpoint", Some(number)),
+            Some(Stop::Finished) => self.terminated(0),
+            // Same output and exit code as `crox` on a runtime error
+            Some(Stop::Errored(error)) => {
+                let output = format!("{error}\n");
+                self.event("output", json!({ "category": "stderr", "output": output }))?;
+                self.terminated(70)
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, breakpoint: Option<usize>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(number) = breakpoint {
+            body["hitBreakpointIds"] = json!([number]);
+        }
+        self.event("stopped", body)
+    }
+
+    fn terminated(&mut self, exit_code: i32) -> io::Result<()> {
+        self.event("exited", json!({ "exitCode": exit_code }))?;
+        self.event("terminated", json!({}))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const SCRIPT: &str = "fn add(a, b) {
+  let sum = a + b;
+  return sum;
+}
+
+let x = 1;
+print add(x, 2);
+";
+
+    // Plays the requests as a client would and returns the messages sent by
+    // the server
+    fn play(name: &str, script: &str, requests: &[Json]) -> Vec<Json> {
+        let path = env::temp_dir().join(format!("crox-dap-{}-{name}.crox", process::id()));
+        fs::write(&path, script).unwrap();
+
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            if request["command"] == "launch" {
+                request["arguments"]["program"] = json!(path.to_str().unwrap());
+            }
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut out = Vec::new();
+        DapServer::new(&mut out).run(&input[..]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut out = &out[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut out).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    // `response:command` or `event:name` for each message
+    fn kinds(messages: &[Json]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| match message["type"].as_str().unwrap() {
+                "response" => format!("response:{}", message["command"].as_str().unwrap()),
+                _ => format!("event:{}", message["event"].as_str().unwrap()),
+            })
+            .collect()
+    }
+
+    fn response(messages: &[Json], request_seq: u64) -> &Json {
+        messages
+            .iter()
+            .find(|message| message["request_seq"] == request_seq)
+            .unwrap()
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let messages = play(
+            "stepping",
+            SCRIPT,
+            &[
+                json!({ "command": "initialize", "arguments": { "adapterID": "crox" } }),
+                json!({ "command": "launch", "arguments": {} }),
+                json!({
+                    "command": "setBreakpoints",
+                    "arguments": { "breakpoints": [{ "line": 2 }, { "line": 5 }] },
+                }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+                json!({ "command": "scopes", "arguments": { "frameId": 2 } }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "variables", "arguments": { "variablesReference": 3 } }),
+                json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+                json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "disconnect" }),
+            ],
+        );
+
+        assert_eq!(
+            kinds(&messages),
+            vec![
+                "response:initialize",
+                "response:launch",
+                "event:initialized",
+                "response:setBreakpoints",
+                "response:configurationDone",
+                "event:stopped",
+                "response:continue",
+                "event:stopped",
+                "response:stackTrace",
+                "response:scopes",
+                "response:next",
+                "event:stopped",
+                "response:variables",
+                "response:stepOut",
+                "event:stopped",
+                "response:stepIn",
+                "event:output",
+                "event:stopped",
+                "response:continue",
+                "event:exited",
+                "event:terminated",
+                "response:disconnect",
+            ]
+        );
+        // The empty line 5 is moved to the declaration of x
+        assert_eq!(
+            response(&messages, 3)["body"]["breakpoints"],
+            json!([
+                { "id": 1, "verified": true, "line": 2 },
+                { "id": 2, "verified": true, "line": 6 },
+            ])
+        );
+        assert_eq!(messages[5]["body"]["hitBreakpointIds"], json!([2]));
+        assert_eq!(messages[7]["body"]["hitBreakpointIds"], json!([1]));
+
+        let frames = &response(&messages, 6)["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "add");
+        assert_eq!(frames[0]["line"], 2);
+        assert_eq!(frames[1]["name"], "script");
+        assert_eq!(frames[1]["line"], 7);
+        assert_eq!(
+            response(&messages, 7)["body"]["scopes"][0]["variablesReference"],
+            3
+        );
+        assert_eq!(
+            response(&messages, 9)["body"]["variables"],
+            json!([
+                { "name": "a", "value": "1", "variablesReference": 0 },
+                { "name": "b", "value": "2", "variablesReference": 0 },
+                { "name": "sum", "value": "3", "variablesReference": 0 },
+            ])
+        );
+        assert_eq!(messages[14]["body"]["reason"], "step");
+        assert_eq!(messages[16]["body"]["output"], "3\n");
+        assert_eq!(messages[19]["body"]["exitCode"], 0);
+    }
+
+    #[test]
+    fn launch_errors() {
+        let messages = play(
+            "errors",
+            "print 1 +;",
+            &[
+                json!({ "command": "launch", "arguments": {} }),
+                json!({ "command": "evaluate", "arguments": { "expression": "1" } }),
+            ],
+        );
+
+        assert_eq!(messages[0]["success"], false);
+        assert_eq!(
+            messages[0]["message"],
+            "[line 1] Error at ';': Expect expression."
+        );
+        assert_eq!(messages[1]["success"], false);
+        assert_eq!(messages[1]["message"], "Unsupported request 'evaluate'.");
+    }
+
+    #[test]
+    fn runtime_errors_are_output() {
+        let messages = play(
+            "runtime",
+            "print 1;\nprint -null;\n",
+            &[
+                json!({ "command": "launch", "arguments": {} }),
+                json!({ "command": "configurationDone" }),
+            ],
+        );
+
+        assert_eq!(
+            kinds(&messages[2..]),
+            vec![
+                "response:configurationDone",
+                "event:output",
+                "event:output",
+                "event:exited",
+                "event:terminated",
+            ]
+        );
+        assert_eq!(messages[3]["body"]["category"], "stdout");
+        assert_eq!(
+            messages[4]["body"],
+            json!({
+                "category": "stderr",
+                "output": "Operand must be a number.\n[line 2] in script\n",
+            })
+        );
+        assert_eq!(messages[5]["body"]["exitCode"], 70);
+    }
+
+    #[test]
+    fn stop_on_entry_and_globals() {
+        let messages = play(
+            "entry",
+            "let a = \"text\";\nlet b = a + a;\n",
+            &[
+                json!({ "command": "launch", "arguments": { "stopOnEntry": true } }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            ],
+        );
+
+        assert_eq!(messages[3]["body"]["reason"], "entry");
+        assert_eq!(
+            response(&messages, 5)["body"]["variables"],
+            json!([
+                { "name": "a", "value": "text", "variablesReference": 0 },
+                { "name": "b", "value": "texttext", "variablesReference": 0 },
+            ])
+        );
+    }
+}
@@ -0,0 +1,6 @@
+pub mod console;
+pub mod dap;
+pub mod session;
+
+pub use console::Debugger;
+pub use dap::DapServer;
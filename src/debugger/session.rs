@@ -0,0 +1,193 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::compiler::function::SCRIPT_NAME;
+use crate::compiler::{Chunk, Function, Value};
+use crate::interpreter::virtual_machine::{ExecutionState, FrameSnapshot, RuntimeError, VM};
+
+#[derive(Clone, PartialEq)]
+pub enum Breakpoint {
+    // 0-based like the lines of the chunks
+    Line(usize),
+    Function(String),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Line(line) => write!(f, "line {}", line + 1),
+            Breakpoint::Function(name) => write!(f, "{name}()"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Resume {
+    // Until the next line, entering calls
+    StepIn,
+    // Until the next line of the current function
+    StepOver,
+    // Until the current function returns
+    StepOut,
+    // Until a breakpoint
+    Continue,
+}
+
+/// Why the script stopped after `Session::resume`.
+pub enum Stop {
+    Step,
+    // With the number of the breakpoint
+    Breakpoint(usize),
+    Finished,
+    Errored(RuntimeError),
+}
+
+// Where the script is paused, stepping stops when it changes
+#[derive(Clone, Copy, PartialEq)]
+struct Position {
+    depth: usize,
+    line: usize,
+}
+
+/// `[line N] in f()`, like the frames of a runtime error.
+pub fn location(frame: &FrameSnapshot) -> String {
+    let name = frame.function.name();
+    if name == SCRIPT_NAME {
+        format!("[line {}] in script", frame.line + 1)
+    } else {
+        format!("[line {}] in {name}()", frame.line + 1)
+    }
+}
+
+// Lines with at least one instruction, in the chunk and the functions
+// declared in it
+fn code_lines(chunk: &Chunk, lines: &mut BTreeSet<usize>) {
+    lines.extend((0..chunk.code_nb()).map(|offset| chunk.get_line(offset)));
+    for constant in chunk.constants() {
+        if let Value::Function(function) = constant {
+            code_lines(function.chunk(), lines);
+        }
+    }
+}
+
+/// Drives a script loaded in a VM for a debugger front end: breakpoints and
+/// the stepping commands, the VM is left paused between two instructions.
+pub struct Session {
+    vm: VM,
+    // Breakpoints with the number they are listed and deleted with
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_breakpoint: usize,
+    code_lines: BTreeSet<usize>,
+}
+
+impl Session {
+    pub fn new(vm: VM) -> Self {
+        Self {
+            vm,
+            breakpoints: Vec::new(),
+            next_breakpoint: 1,
+            code_lines: BTreeSet::new(),
+        }
+    }
+
+    /// Loads `script` paused before its first instruction.
+    pub fn load(&mut self, script: Function) -> ExecutionState {
+        self.code_lines.clear();
+        code_lines(script.chunk(), &mut self.code_lines);
+        self.vm.load_script(script)
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// First line from `line` on where the loaded script has code, where a
+    /// breakpoint on `line` actually stops.
+    pub fn code_line(&self, line: usize) -> Option<usize> {
+        self.code_lines.range(line..).next().copied()
+    }
+
+    pub fn breakpoints(&self) -> &[(usize, Breakpoint)] {
+        &self.breakpoints
+    }
+
+    /// Returns the number of the new breakpoint.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let number = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.breakpoints.push((number, breakpoint));
+        number
+    }
+
+    pub fn remove_breakpoint(&mut self, number: usize) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|(n, _)| *n != number);
+        self.breakpoints.len() != count
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Checked after every instruction, so it doesn't build a snapshot of
+    // the frame
+    fn position(&self) -> Option<Position> {
+        Some(Position {
+            depth: self.vm.frame_depth(),
+            line: self.vm.current_line()?,
+        })
+    }
+
+    /// Runs the script until `mode` or a breakpoint stops it, None when no
+    /// script is running.
+    pub fn resume(&mut self, mode: Resume) -> Option<Stop> {
+        let start = self.position()?;
+        let mut last = start;
+        loop {
+            match self.vm.step() {
+                ExecutionState::Running => (),
+                ExecutionState::Finished(_) => return Some(Stop::Finished),
+                ExecutionState::Errored(error) => return Some(Stop::Errored(error)),
+            }
+            let Some(position) = self.position() else {
+                continue;
+            };
+            if let Some(number) = self.breakpoint_hit(last, position) {
+                return Some(Stop::Breakpoint(number));
+            }
+            let stop = match mode {
+                Resume::StepIn => position != start,
+                Resume::StepOver => {
+                    position.depth < start.depth
+                        || (position.depth == start.depth && position.line != start.line)
+                }
+                Resume::StepOut => position.depth < start.depth,
+                Resume::Continue => false,
+            };
+            if stop {
+                return Some(Stop::Step);
+            }
+            last = position;
+        }
+    }
+
+    // A line breakpoint is hit when the line is reached from another line of
+    // the same function or when a call starts on it, not when a call made
+    // from it returns.
+    fn breakpoint_hit(&self, last: Position, position: Position) -> Option<usize> {
+        let (function, offset) = self.vm.current_function()?;
+        let entered = position.depth > last.depth;
+        let new_line = entered || (position.depth == last.depth && position.line != last.line);
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint)| match breakpoint {
+                Breakpoint::Line(line) => new_line && *line == position.line,
+                Breakpoint::Function(name) => entered && offset == 0 && function.name() == name,
+            })
+            .map(|(number, _)| *number)
+    }
+}
@@ -0,0 +1,461 @@
+use std::fmt::Write;
+
+use serde_json::{json, Value as Json};
+
+use crate::compiler::ast::{
+    walk_expr, walk_program, walk_stmt, Expr, ExprKind, ImportKind, Literal, Program, Stmt,
+    StmtKind, Visitor,
+};
+use crate::compiler::{Chunk, Function, OpCode, Value};
+use crate::scanner::Token;
+
+// The output of this module is compared in code reviews, any change to the
+// layout below has to be done on purpose.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    Text,
+    Json,
+}
+
+enum Operand {
+    None,
+    Constant(usize),
+    Slot(usize),
+    Jump(usize),
+    ArgCount(usize),
+}
+
+fn decode(instruction: &OpCode) -> (&'static str, Operand) {
+    use OpCode::*;
+    match instruction {
+        Return => ("RETURN", Operand::None),
+        Print => ("PRINT", Operand::None),
+        Pop => ("POP", Operand::None),
+        Null => ("NULL", Operand::None),
+        True => ("TRUE", Operand::None),
+        False => ("FALSE", Operand::None),
+        Negate => ("NEGATE", Operand::None),
+        Not => ("NOT", Operand::None),
+        Add => ("ADD", Operand::None),
+        Sub => ("SUB", Operand::None),
+        Mul => ("MUL", Operand::None),
+        Div => ("DIV", Operand::None),
+        Mod => ("MOD", Operand::None),
+        IntDiv => ("INT_DIV", Operand::None),
+        Pow => ("POW", Operand::None),
+        Equal => ("EQUAL", Operand::None),
+        Greater => ("GREATER", Operand::None),
+        Less => ("LESS", Operand::None),
+        Constant(index) => ("CONSTANT", Operand::Constant(*index)),
+        AddConstant(index) => ("ADD_CONSTANT", Operand::Constant(*index)),
+        SubConstant(index) => ("SUB_CONSTANT", Operand::Constant(*index)),
+        DefineGlobal(index) => ("DEFINE_GLOBAL", Operand::Constant(*index)),
+        GetGlobal(index) => ("GET_GLOBAL", Operand::Constant(*index)),
+        SetGlobal(index) => ("SET_GLOBAL", Operand::Constant(*index)),
+        GetLocal(slot) => ("GET_LOCAL", Operand::Slot(*slot)),
+        SetLocal(slot) => ("SET_LOCAL", Operand::Slot(*slot)),
+        Jump(target) => ("JUMP", Operand::Jump(*target)),
+        JumpIfFalse(target) => ("JUMP_IF_FALSE", Operand::Jump(*target)),
+        Call(arg_count) => ("CALL", Operand::ArgCount(*arg_count)),
+        Import(index) => ("IMPORT", Operand::Constant(*index)),
+        GetProperty(index) => ("GET_PROPERTY", Operand::Constant(*index)),
+    }
+}
+
+/// Name and operands of the instruction at `offset`, without the offset and
+/// line columns.
+pub fn instruction(chunk: &Chunk, offset: usize) -> String {
+    let (name, operand) = decode(&chunk.get_instruction(offset));
+    match operand {
+        Operand::None => name.to_string(),
+        Operand::Constant(index) => {
+            format!("{name:<16} {index:4} '{}'", chunk.get_constant(index))
+        }
+        Operand::Slot(value) | Operand::ArgCount(value) => format!("{name:<16} {value:4}"),
+        Operand::Jump(target) => format!("{name:<16} {offset:04} -> {target:04}"),
+    }
+}
+
+// Functions declared in a chunk are stored in its constants
+fn functions(chunk: &Chunk) -> impl Iterator<Item = &Function> {
+    chunk.constants().iter().filter_map(|value| match value {
+        Value::Function(function) => Some(function.as_ref()),
+        _ => None,
+    })
+}
+
+pub fn disassemble(chunk: &Chunk, name: &str, source: Option<&str>, format: DumpFormat) -> String {
+    match format {
+        DumpFormat::Text => {
+            let mut out = String::new();
+            disassemble_text(&mut out, chunk, name, source);
+            out
+        }
+        DumpFormat::Json => {
+            let json = disassemble_json(chunk, name, source);
+            serde_json::to_string_pretty(&json).expect("Expected a serializable chunk")
+        }
+    }
+}
+
+fn disassemble_text(out: &mut String, chunk: &Chunk, name: &str, source: Option<&str>) {
+    let source_lines = source.map(|s| s.lines().collect::<Vec<_>>());
+    let mut previous_line = None;
+
+    writeln!(out, "== {name} ==").unwrap();
+    for offset in 0..chunk.code_nb() {
+        let line = chunk.get_line(offset);
+        if previous_line == Some(line) {
+            write!(out, "{offset:04}    | ").unwrap();
+        } else {
+            if let Some(text) = source_lines.as_ref().and_then(|l| l.get(line)) {
+                writeln!(out, "        // {}", text.trim()).unwrap();
+            }
+            write!(out, "{offset:04} {:4} ", line + 1).unwrap();
+        }
+        writeln!(out, "{}", instruction(chunk, offset)).unwrap();
+        previous_line = Some(line);
+    }
+    for function in functions(chunk) {
+        writeln!(out).unwrap();
+        disassemble_text(out, function.chunk(), function.name(), source);
+    }
+}
+
+fn disassemble_json(chunk: &Chunk, name: &str, source: Option<&str>) -> Json {
+    let source_lines = source.map(|s| s.lines().collect::<Vec<_>>());
+    let code = chunk
+        .code()
+        .iter()
+        .enumerate()
+        .map(|(offset, op)| {
+            let line = chunk.get_line(offset);
+            let (op_name, operand) = decode(op);
+            let mut entry = json!({
+                "offset": offset,
+                "line": line + 1,
+                "op": op_name,
+            });
+            match operand {
+                Operand::None => (),
+                Operand::Constant(index) => {
+                    entry["operand"] = json!(index);
+                    entry["value"] = json!(chunk.get_constant(index).to_string());
+                }
+                Operand::Slot(value) | Operand::ArgCount(value) => {
+                    entry["operand"] = json!(value);
+                }
+                Operand::Jump(target) => entry["target"] = json!(target),
+            }
+            if let Some(text) = source_lines.as_ref().and_then(|l| l.get(line)) {
+                entry["source"] = json!(text.trim());
+            }
+            entry
+        })
+        .collect::<Vec<_>>();
+    let constants = chunk
+        .constants()
+        .iter()
+        .map(|value| json!(value.to_string()))
+        .collect::<Vec<_>>();
+    let functions = functions(chunk)
+        .map(|function| disassemble_json(function.chunk(), function.name(), source))
+        .collect::<Vec<_>>();
+
+    json!({
+        "name": name,
+        "constants": constants,
+        "code": code,
+        "functions": functions,
+    })
+}
+
+pub fn dump_tokens(tokens: &[Token], format: DumpFormat) -> String {
+    match format {
+        DumpFormat::Text => tokens.iter().fold(String::new(), |mut out, token| {
+            let ty = format!("{:?}", token.ty());
+            writeln!(out, "{:4} {ty:<24} '{}'", token.line() + 1, token.lexeme()).unwrap();
+            out
+        }),
+        DumpFormat::Json => {
+            let tokens = tokens
+                .iter()
+                .map(|token| {
+                    json!({
+                        "line": token.line() + 1,
+                        "type": format!("{:?}", token.ty()),
+                        "lexeme": token.lexeme(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_string_pretty(&tokens).expect("Expected serializable tokens")
+        }
+    }
+}
+
+fn stmt_label(stmt: &Stmt) -> String {
+    match &stmt.kind {
+        StmtKind::Expression(_) => String::from("Expression"),
+        StmtKind::Print(_) => String::from("Print"),
+        StmtKind::Let { name, .. } => format!("Let {}", name.name),
+        StmtKind::Fn(function) => {
+            let params = function.params.iter().map(|param| param.name);
+            format!(
+                "Fn {}({})",
+                function.name.name,
+                params.collect::<Vec<_>>().join(", ")
+            )
+        }
+        StmtKind::Block(_) => String::from("Block"),
+        StmtKind::If { .. } => String::from("If"),
+        StmtKind::While { .. } => String::from("While"),
+        StmtKind::Return(_) => String::from("Return"),
+        StmtKind::Import {
+            path,
+            kind: ImportKind::Module(name),
+            ..
+        } => format!("Import \"{path}\" as {}", name.name),
+        StmtKind::Import {
+            path,
+            kind: ImportKind::Names(names),
+            ..
+        } => {
+            let names = names.iter().map(|name| name.name);
+            format!(
+                "Import {} from \"{path}\"",
+                names.collect::<Vec<_>>().join(", ")
+            )
+        }
+    }
+}
+
+fn expr_label(expr: &Expr, tokens: &[Token]) -> String {
+    match &expr.kind {
+        ExprKind::Literal(Literal::Number(num)) => format!("Literal {num}"),
+        ExprKind::Literal(Literal::Int(num)) => format!("Literal {num}"),
+        ExprKind::Literal(Literal::Str(string)) => format!("Literal \"{string}\""),
+        ExprKind::Literal(Literal::True) => String::from("Literal true"),
+        ExprKind::Literal(Literal::False) => String::from("Literal false"),
+        ExprKind::Literal(Literal::Null) => String::from("Literal null"),
+        ExprKind::Variable(name) => format!("Variable {}", name.name),
+        ExprKind::Assign { name, .. } => format!("Assign {}", name.name),
+        ExprKind::Unary { token, .. } => format!("Unary {}", tokens[*token].lexeme()),
+        ExprKind::Binary { token, .. } => format!("Binary {}", tokens[*token].lexeme()),
+        ExprKind::Logical { token, .. } => format!("Logical {}", tokens[*token].lexeme()),
+        ExprKind::Call { .. } => String::from("Call"),
+        ExprKind::Grouping(_) => String::from("Grouping"),
+        ExprKind::Get { name, .. } => format!("Get {}", name.name),
+    }
+}
+
+// One node per line, children indented under their parent
+struct TextTree<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    out: String,
+    depth: usize,
+}
+
+impl TextTree<'_, '_> {
+    fn node(&mut self, label: String, token: usize) {
+        let line = self.tokens[token].line() + 1;
+        writeln!(self.out, "{line:4} {}{label}", "  ".repeat(self.depth)).unwrap();
+    }
+}
+
+impl<'a> Visitor<'a> for TextTree<'_, 'a> {
+    fn visit_stmt(&mut self, stmt: &Stmt<'a>) {
+        self.node(stmt_label(stmt), stmt.span.start);
+        self.depth += 1;
+        walk_stmt(self, stmt);
+        self.depth -= 1;
+    }
+
+    fn visit_expr(&mut self, expr: &Expr<'a>) {
+        self.node(expr_label(expr, self.tokens), expr.span.start);
+        self.depth += 1;
+        walk_expr(self, expr);
+        self.depth -= 1;
+    }
+}
+
+// Nodes being built, the children of the last one are being visited
+struct JsonTree<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    stack: Vec<Json>,
+}
+
+impl JsonTree<'_, '_> {
+    fn open(&mut self, label: String, token: usize) {
+        let line = self.tokens[token].line() + 1;
+        self.stack
+            .push(json!({ "node": label, "line": line, "children": [] }));
+    }
+
+    fn close(&mut self) {
+        let node = self.stack.pop().expect("Expected an open node");
+        let parent = self.stack.last_mut().expect("Expected the root node");
+        parent["children"]
+            .as_array_mut()
+            .expect("Expected children")
+            .push(node);
+    }
+}
+
+impl<'a> Visitor<'a> for JsonTree<'_, 'a> {
+    fn visit_stmt(&mut self, stmt: &Stmt<'a>) {
+        self.open(stmt_label(stmt), stmt.span.start);
+        walk_stmt(self, stmt);
+        self.close();
+    }
+
+    fn visit_expr(&mut self, expr: &Expr<'a>) {
+        self.open(expr_label(expr, self.tokens), expr.span.start);
+        walk_expr(self, expr);
+        self.close();
+    }
+}
+
+/// The syntax tree of a script, `tokens` are the ones it was parsed from.
+pub fn dump_ast(program: &Program, tokens: &[Token], format: DumpFormat) -> String {
+    match format {
+        DumpFormat::Text => {
+            let mut tree = TextTree {
+                tokens,
+                out: String::new(),
+                depth: 0,
+            };
+            walk_program(&mut tree, program);
+            tree.out
+        }
+        DumpFormat::Json => {
+            let mut tree = JsonTree {
+                tokens,
+                stack: vec![json!({ "children": [] })],
+            };
+            walk_program(&mut tree, program);
+            let root = tree.stack.pop().expect("Expected the root node");
+            serde_json::to_string_pretty(&root["children"]).expect("Expected a serializable tree")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::parser::Parser;
+    use crate::compiler::Compiler;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> Chunk {
+        let scanner = Scanner::new(source.to_string());
+        Compiler::new(&scanner).compile().unwrap()
+    }
+
+    #[test]
+    fn text_format() {
+        let source = "1 +\n2;";
+        let chunk = compile(source);
+
+        assert_eq!(
+            disassemble(&chunk, "script", Some(source), DumpFormat::Text),
+            "== script ==
+        // 1 +
+0000    1 CONSTANT            0 '1'
+        // 2;
+0001    2 CONSTANT            1 '2'
+0002    | ADD
+0003    | POP
+0004    | NULL
+0005    | RETURN
+"
+        );
+    }
+
+    #[test]
+    fn jumps_and_functions() {
+        let chunk = compile("fn f(a) {\n  if (a) return 1;\n}\nf(2);");
+
+        assert_eq!(
+            disassemble(&chunk, "script", None, DumpFormat::Text),
+            "== script ==
+0000    3 CONSTANT            1 '<fn f>'
+0001    | DEFINE_GLOBAL       0 'f'
+0002    4 GET_GLOBAL          0 'f'
+0003    | CONSTANT            2 '2'
+0004    | CALL                1
+0005    | POP
+0006    | NULL
+0007    | RETURN
+
+== f ==
+0000    2 GET_LOCAL           1
+0001    | JUMP_IF_FALSE    0001 -> 0006
+0002    | POP
+0003    | CONSTANT            0 '1'
+0004    | RETURN
+0005    | JUMP             0005 -> 0007
+0006    | POP
+0007    3 NULL
+0008    | RETURN
+"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let chunk = compile("print -4;");
+        let json: Json =
+            serde_json::from_str(&disassemble(&chunk, "script", None, DumpFormat::Json)).unwrap();
+
+        assert_eq!(
+            json,
+            json!({
+                "name": "script",
+                "constants": ["4"],
+                "code": [
+                    { "offset": 0, "line": 1, "op": "CONSTANT", "operand": 0, "value": "4" },
+                    { "offset": 1, "line": 1, "op": "NEGATE" },
+                    { "offset": 2, "line": 1, "op": "PRINT" },
+                    { "offset": 3, "line": 1, "op": "NULL" },
+                    { "offset": 4, "line": 1, "op": "RETURN" },
+                ],
+                "functions": [],
+            })
+        );
+    }
+
+    #[test]
+    fn syntax_tree() {
+        let scanner = Scanner::new(String::from(
+            "fn f(a) {\n  return -a * 2;\n}\nprint f(1) or \"no\";",
+        ));
+        let tokens = scanner.tokenize();
+        let (program, _) = Parser::new(&tokens).parse();
+
+        assert_eq!(
+            dump_ast(&program, &tokens, DumpFormat::Text),
+            "   1 Fn f(a)
+   2   Return
+   2     Binary *
+   2       Unary -
+   2         Variable a
+   2       Literal 2
+   4 Print
+   4   Logical or
+   4     Call
+   4       Variable f
+   4       Literal 1
+   4     Literal \"no\"
+"
+        );
+
+        let json: Json =
+            serde_json::from_str(&dump_ast(&program, &tokens, DumpFormat::Json)).unwrap();
+        assert_eq!(json[1]["node"], "Print");
+        assert_eq!(
+            json[1]["children"][0]["children"][1]["node"],
+            "Literal \"no\""
+        );
+    }
+}
@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json::{json, Value as Json};
+
+use crate::compiler::ast::{Span, StmtKind};
+use crate::compiler::compilation::CompileError;
+use crate::compiler::parser::Parser;
+use crate::scanner::token::{Token, TokenType};
+use crate::scanner::Scanner;
+
+const SEARCH_INDEX: &str = "search-index.json";
+
+#[derive(Debug, PartialEq)]
+pub enum ItemKind {
+    Function { params: Vec<String> },
+    // A module-level `let`, with the source of its initializer
+    Constant { value: Option<String> },
+}
+
+/// A top-level declaration of a module.
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub name: String,
+    pub kind: ItemKind,
+    // Lines of the `///` comments before the declaration
+    pub doc: Vec<String>,
+    // 1-based
+    pub line: usize,
+}
+
+impl Item {
+    fn kind_name(&self) -> &'static str {
+        match self.kind {
+            ItemKind::Function { .. } => "function",
+            ItemKind::Constant { .. } => "constant",
+        }
+    }
+
+    fn signature(&self) -> String {
+        match &self.kind {
+            ItemKind::Function { params } => format!("fn {}({})", self.name, params.join(", ")),
+            ItemKind::Constant { value: Some(value) } => format!("let {} = {value};", self.name),
+            ItemKind::Constant { value: None } => format!("let {};", self.name),
+        }
+    }
+
+    // First paragraph of the doc, shown in the lists and the search index
+    fn summary(&self) -> String {
+        self.doc
+            .iter()
+            .take_while(|line| !line.trim().is_empty())
+            .map(|line| line.trim())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The documented items of a source file, `name` is its path relative to
+/// the documented directory, without the extension.
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub items: Vec<Item>,
+}
+
+impl Module {
+    /// Collects the top-level functions and variables of `source`, fails
+    /// with the errors of the parser if the source does not parse.
+    pub fn extract(name: &str, source: &str) -> Result<Self, Vec<CompileError>> {
+        let scanner = Scanner::new(source.to_string());
+        let tokens = scanner.tokenize();
+        let (program, errors) = Parser::new(&tokens).parse();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let items = program
+            .statements
+            .iter()
+            .filter_map(|stmt| {
+                let (name, kind, doc) = match &stmt.kind {
+                    StmtKind::Fn(function) => {
+                        let params = function.params.iter().map(|p| p.name.to_string());
+                        let kind = ItemKind::Function {
+                            params: params.collect(),
+                        };
+                        (function.name, kind, &function.doc)
+                    }
+                    StmtKind::Let {
+                        name,
+                        initializer,
+                        doc,
+                    } => {
+                        let value = initializer
+                            .as_ref()
+                            .map(|value| source_text(&tokens, value.span, scanner.source()));
+                        (*name, ItemKind::Constant { value }, doc)
+                    }
+                    _ => return None,
+                };
+                Some(Item {
+                    name: name.name.to_string(),
+                    kind,
+                    doc: doc.iter().map(|line| line.to_string()).collect(),
+                    line: tokens[name.token].line() + 1,
+                })
+            })
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            items,
+        })
+    }
+
+    // Name of the pages of the module, without the extension
+    fn page(&self) -> String {
+        self.name.replace('/', ".")
+    }
+}
+
+// Source of the tokens of a span, strings keep their quotes
+fn source_text(tokens: &[Token], span: Span, source: &str) -> String {
+    let (first, last) = (&tokens[span.start], &tokens[span.last()]);
+    let quote = |token: &Token| usize::from(token.ty() == TokenType::CroxStr);
+    let start = first.offset(source) - quote(first);
+    let end = last.offset(source) + last.lexeme().len() + quote(last);
+    source[start..end].to_string()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Html,
+    Markdown,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Markdown => "md",
+        }
+    }
+}
+
+// Finds the targets of the `[name]` links of the docs, the items of the
+// module being documented come before the ones of the other modules
+struct Links<'m> {
+    modules: &'m [Module],
+    pages: HashMap<&'m str, &'m Module>,
+}
+
+impl<'m> Links<'m> {
+    fn new(modules: &'m [Module]) -> Self {
+        let pages = modules
+            .iter()
+            .map(|module| (module.name.as_str(), module))
+            .collect();
+        Self { modules, pages }
+    }
+
+    fn href(&self, target: &str, current: &Module, format: Format) -> Option<String> {
+        let extension = format.extension();
+        // `[module]` and `[module.item]` name the module explicitly
+        if let Some(module) = self.pages.get(target) {
+            return Some(format!("{}.{extension}", module.page()));
+        }
+        if let Some((module, name)) = target.rsplit_once('.') {
+            let module = self.pages.get(module)?;
+            module.items.iter().find(|item| item.name == name)?;
+            return Some(format!("{}.{extension}#{name}", module.page()));
+        }
+        let module = std::iter::once(current)
+            .chain(self.modules)
+            .find(|module| module.items.iter().any(|item| item.name == target))?;
+        Some(format!("{}.{extension}#{target}", module.page()))
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Renders the inline markup of a line of doc: `code` spans and `[name]`
+// links, a `[name]` that names no item is left as written
+fn inline(text: &str, links: &Links, current: &Module, format: Format) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['`', '[']) {
+        let (before, from) = rest.split_at(start);
+        out.push_str(&match format {
+            Format::Html => escape(before),
+            Format::Markdown => before.to_string(),
+        });
+        let close = if from.starts_with('`') { '`' } else { ']' };
+        let Some(end) = from[1..].find(close).map(|end| end + 1) else {
+            rest = from;
+            break;
+        };
+        let inner = &from[1..end];
+        match (close, format) {
+            ('`', Format::Html) => write!(out, "<code>{}</code>", escape(inner)).unwrap(),
+            ('`', Format::Markdown) => out.push_str(&from[..=end]),
+            (_, _) => match links.href(inner, current, format) {
+                Some(href) if format == Format::Html => write!(
+                    out,
+                    "<a href=\"{}\"><code>{}</code></a>",
+                    escape(&href),
+                    escape(inner)
+                )
+                .unwrap(),
+                Some(href) => write!(out, "[`{inner}`]({href})").unwrap(),
+                None if format == Format::Html => out.push_str(&escape(&from[..=end])),
+                None => out.push_str(&from[..=end]),
+            },
+        }
+        rest = &from[end + 1..];
+    }
+    out.push_str(&match format {
+        Format::Html => escape(rest),
+        Format::Markdown => rest.to_string(),
+    });
+    out
+}
+
+// The doc of an item, blank lines separate the paragraphs
+fn doc(lines: &[String], links: &Links, current: &Module, format: Format) -> String {
+    let mut out = String::new();
+    let paragraphs = lines.split(|line| line.trim().is_empty());
+    for paragraph in paragraphs.filter(|paragraph| !paragraph.is_empty()) {
+        let text = paragraph
+            .iter()
+            .map(|line| inline(line.trim(), links, current, format))
+            .collect::<Vec<_>>()
+            .join("\n");
+        match format {
+            Format::Html => writeln!(out, "<p>{text}</p>").unwrap(),
+            Format::Markdown => writeln!(out, "{text}\n").unwrap(),
+        }
+    }
+    out
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>{}</title>
+</head>
+<body>
+<nav><a href=\"index.html\">Index</a></nav>
+{body}</body>
+</html>
+",
+        escape(title)
+    )
+}
+
+fn module_html(module: &Module, links: &Links) -> String {
+    let mut body = format!("<h1>Module <code>{}</code></h1>\n", escape(&module.name));
+    for (kind, heading) in [("function", "Functions"), ("constant", "Constants")] {
+        let items = module.items.iter().filter(|item| item.kind_name() == kind);
+        let mut items = items.peekable();
+        if items.peek().is_none() {
+            continue;
+        }
+        writeln!(body, "<h2>{heading}</h2>").unwrap();
+        for item in items {
+            writeln!(
+                body,
+                "<section id=\"{}\">\n<h3><code>{}</code></h3>\n<p>Line {}</p>",
+                escape(&item.name),
+                escape(&item.signature()),
+                item.line
+            )
+            .unwrap();
+            body.push_str(&doc(&item.doc, links, module, Format::Html));
+            body.push_str("</section>\n");
+        }
+    }
+    html_page(&module.name, &body)
+}
+
+fn module_markdown(module: &Module, links: &Links) -> String {
+    let mut out = format!("[Index](index.md)\n\n# Module `{}`\n", module.name);
+    for (kind, heading) in [("function", "Functions"), ("constant", "Constants")] {
+        let items = module.items.iter().filter(|item| item.kind_name() == kind);
+        let mut items = items.peekable();
+        if items.peek().is_none() {
+            continue;
+        }
+        write!(out, "\n## {heading}\n").unwrap();
+        for item in items {
+            write!(
+                out,
+                "\n<a id=\"{}\"></a>\n### `{}`\n\nLine {}\n\n",
+                item.name,
+                item.signature(),
+                item.line
+            )
+            .unwrap();
+            out.push_str(&doc(&item.doc, links, module, Format::Markdown));
+        }
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    out
+}
+
+fn index_html(modules: &[Module]) -> String {
+    let mut body = String::from("<h1>Modules</h1>\n<ul>\n");
+    for module in modules {
+        writeln!(
+            body,
+            "<li><a href=\"{}.html\">{}</a></li>",
+            escape(&module.page()),
+            escape(&module.name)
+        )
+        .unwrap();
+    }
+    body.push_str("</ul>\n");
+    html_page("Modules", &body)
+}
+
+fn index_markdown(modules: &[Module]) -> String {
+    let mut out = String::from("# Modules\n\n");
+    for module in modules {
+        writeln!(out, "- [{}]({}.md)", module.name, module.page()).unwrap();
+    }
+    out
+}
+
+/// One entry per item, for a search box to filter without loading the
+/// pages.
+pub fn search_index(modules: &[Module]) -> Json {
+    let entries = modules.iter().flat_map(|module| {
+        module.items.iter().map(move |item| {
+            json!({
+                "name": item.name,
+                "kind": item.kind_name(),
+                "module": module.name,
+                "signature": item.signature(),
+                "summary": item.summary(),
+                "href": format!("{}.html#{}", module.page(), item.name),
+            })
+        })
+    });
+    Json::Array(entries.collect())
+}
+
+/// Writes an HTML and a Markdown page per module, the index pages and the
+/// search index to `out`.
+pub fn generate(modules: &[Module], out: &Path) -> io::Result<()> {
+    fs::create_dir_all(out)?;
+    let links = Links::new(modules);
+    for module in modules {
+        let page = module.page();
+        fs::write(
+            out.join(format!("{page}.html")),
+            module_html(module, &links),
+        )?;
+        fs::write(
+            out.join(format!("{page}.md")),
+            module_markdown(module, &links),
+        )?;
+    }
+    fs::write(out.join("index.html"), index_html(modules))?;
+    fs::write(out.join("index.md"), index_markdown(modules))?;
+    let index = serde_json::to_string_pretty(&search_index(modules))
+        .expect("Expected a serializable index");
+    fs::write(out.join(SEARCH_INDEX), index + "\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MATH: &str = "/// Adds `a` and `b`.
+///
+/// See also [scale] and [shapes.area].
+fn add(a, b) { return a + b; }
+
+fn scale(x) { let factor = 2; return x * factor; }
+
+/// The ratio of a circle's circumference to its diameter.
+let PI = 3.14159;
+let name = \"math <core>\";
+let unset;
+print add(1, 2);";
+
+    const SHAPES: &str = "/// Area of a circle, uses [PI] and [missing].
+fn area(r) { return PI * r * r; }";
+
+    fn modules() -> Vec<Module> {
+        vec![
+            Module::extract("math", MATH).unwrap(),
+            Module::extract("geometry/shapes", SHAPES).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn extracts_top_level_declarations() {
+        let math = Module::extract("math", MATH).unwrap();
+        let items = math
+            .items
+            .iter()
+            .map(|item| (item.signature(), item.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            items,
+            vec![
+                (String::from("fn add(a, b)"), 4),
+                (String::from("fn scale(x)"), 6),
+                (String::from("let PI = 3.14159;"), 9),
+                (String::from("let name = \"math <core>\";"), 10),
+                (String::from("let unset;"), 11),
+            ]
+        );
+        assert_eq!(
+            math.items[0].doc,
+            vec![
+                "Adds `a` and `b`.",
+                "",
+                "See also [scale] and [shapes.area]."
+            ]
+        );
+        assert_eq!(math.items[0].summary(), "Adds `a` and `b`.");
+        assert!(math.items[1].doc.is_empty());
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        let errors = Module::extract("broken", "fn f( { }").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 1] Error at '{': Expect parameter name."
+        );
+    }
+
+    #[test]
+    fn cross_links() {
+        let modules = modules();
+        let links = Links::new(&modules);
+        let (math, shapes) = (&modules[0], &modules[1]);
+        let markdown = |text: &str, current| inline(text, &links, current, Format::Markdown);
+
+        assert_eq!(
+            markdown("[scale] and [PI]", shapes),
+            "[`scale`](math.md#scale) and [`PI`](math.md#PI)"
+        );
+        assert_eq!(
+            markdown("[geometry/shapes.area], [geometry/shapes]", math),
+            "[`geometry/shapes.area`](geometry.shapes.md#area), [`geometry/shapes`](geometry.shapes.md)"
+        );
+        assert_eq!(markdown("[missing] [a", math), "[missing] [a");
+        assert_eq!(
+            inline("`a < b` [add] <b>", &links, math, Format::Html),
+            "<code>a &lt; b</code> <a href=\"math.html#add\"><code>add</code></a> &lt;b&gt;"
+        );
+    }
+
+    #[test]
+    fn markdown_page() {
+        let modules = modules();
+        let links = Links::new(&modules);
+        assert_eq!(
+            module_markdown(&modules[1], &links),
+            "[Index](index.md)
+
+# Module `geometry/shapes`
+
+## Functions
+
+<a id=\"area\"></a>
+### `fn area(r)`
+
+Line 2
+
+Area of a circle, uses [`PI`](math.md#PI) and [missing].
+"
+        );
+    }
+
+    #[test]
+    fn html_page_escapes_the_source() {
+        let modules = modules();
+        let html = module_html(&modules[0], &Links::new(&modules));
+        assert!(html.contains("<h2>Constants</h2>"));
+        assert!(html.contains(
+            "<section id=\"name\">\n<h3><code>let name = &quot;math &lt;core&gt;&quot;;</code></h3>"
+        ));
+        assert!(html.contains("<p>Adds <code>a</code> and <code>b</code>.</p>\n<p>See also"));
+    }
+
+    #[test]
+    fn search_index_lists_every_item() {
+        let index = search_index(&modules());
+        assert_eq!(index.as_array().unwrap().len(), 6);
+        assert_eq!(
+            index[5],
+            json!({
+                "name": "area",
+                "kind": "function",
+                "module": "geometry/shapes",
+                "signature": "fn area(r)",
+                "summary": "Area of a circle, uses [PI] and [missing].",
+                "href": "geometry.shapes.html#area",
+            })
+        );
+    }
+}
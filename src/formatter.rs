@@ -0,0 +1,514 @@
+use thiserror::Error;
+
+use crate::scanner::token::{Token, TokenType};
+use crate::scanner::Scanner;
+
+const INDENT: &str = "  ";
+// Lines longer than this are broken after the commas of their arguments
+const MAX_WIDTH: usize = 100;
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    // Sources with lexical errors are left alone
+    #[error("[line {line}] Error: {message}")]
+    Scan { line: usize, message: &'static str },
+    #[error("Formatting would change the meaning of the code, please report it.")]
+    ChangedTokens,
+}
+
+// A token of the line being printed
+struct Piece<'a> {
+    ty: TokenType,
+    text: &'a str,
+    space_before: bool,
+    // Unary minus and not, no space follows them
+    unary: bool,
+    // Depth of the innermost parentheses or brackets around the piece, the
+    // brackets themselves have the depth outside of them
+    depth: usize,
+}
+
+struct Formatter<'a> {
+    out: String,
+    indent: usize,
+    // Indentation of the line being printed
+    line_indent: usize,
+    line: Vec<Piece<'a>>,
+    depth: usize,
+    // The line ends before the next token, unless it is a trailing comment
+    // or an `else` after a brace
+    pending_newline: bool,
+    // Set after a comment inside a statement, the rest of the statement is
+    // indented one level deeper
+    continuation: bool,
+    previous: Option<Token<'a>>,
+    // Line where the previous token, comments included, ends
+    previous_line: usize,
+}
+
+// Line where a token starts, the scanner gives the last line of strings
+fn start_line(token: &Token) -> usize {
+    token.line() - token.lexeme().matches('\n').count()
+}
+
+// Text printed for a token, strings get their quotes back
+fn text<'a>(token: &Token<'a>, source: &'a str) -> &'a str {
+    match token.ty() {
+        TokenType::CroxStr => {
+            let start = token.offset(source) - 1;
+            &source[start..start + token.lexeme().len() + 2]
+        }
+        _ => token.lexeme(),
+    }
+}
+
+fn is_operand_end(ty: TokenType) -> bool {
+    use TokenType::*;
+    matches!(
+        ty,
+        Identifier
+            | Number(_)
+            | Integer(_)
+            | CroxStr
+            | RightParen
+            | RightBracket
+            | True
+            | False
+            | Null
+            | This
+            | Super
+    )
+}
+
+fn space_between(previous: &Piece, next: TokenType) -> bool {
+    use TokenType::*;
+    match (previous.ty, next) {
+        (_, RightParen | RightBracket | Comma | SemiColon | Dot | Colon) => false,
+        (LeftBrace, RightBrace) => false,
+        (LeftParen | LeftBracket | Dot, _) => false,
+        // Calls and indexing
+        (previous, LeftParen | LeftBracket) if is_operand_end(previous) => false,
+        (Minus | Bang, _) => !previous.unary,
+        _ => true,
+    }
+}
+
+impl<'a> Formatter<'a> {
+    fn new() -> Self {
+        Self {
+            out: String::new(),
+            indent: 0,
+            line_indent: 0,
+            line: Vec::new(),
+            depth: 0,
+            pending_newline: false,
+            continuation: false,
+            previous: None,
+            previous_line: 0,
+        }
+    }
+
+    fn push(&mut self, ty: TokenType, text: &'a str) {
+        let unary = matches!(ty, TokenType::Minus | TokenType::Bang)
+            && !self.previous.is_some_and(|t| is_operand_end(t.ty()));
+        let space_before = match self.line.last() {
+            Some(previous) => space_between(previous, ty),
+            None => {
+                self.line_indent = self.indent + usize::from(self.continuation);
+                false
+            }
+        };
+        let depth = match ty {
+            TokenType::LeftParen | TokenType::LeftBracket => {
+                self.depth += 1;
+                self.depth - 1
+            }
+            TokenType::RightParen | TokenType::RightBracket => {
+                self.depth = self.depth.saturating_sub(1);
+                self.depth
+            }
+            _ => self.depth,
+        };
+        self.line.push(Piece {
+            ty,
+            text,
+            space_before,
+            unary,
+            depth,
+        });
+    }
+
+    fn flush(&mut self, comment: Option<&str>) {
+        if !self.line.is_empty() {
+            let line = render(&self.line, self.line_indent);
+            self.out.push_str(&line);
+            if let Some(comment) = comment {
+                self.out.push(' ');
+                self.out.push_str(comment);
+            }
+            self.out.push('\n');
+            self.line.clear();
+        }
+        self.pending_newline = false;
+    }
+
+    fn end_statement(&mut self) {
+        self.pending_newline = true;
+        self.continuation = false;
+    }
+
+    fn blank_line(&mut self, token: &Token) {
+        let after_open_brace = self.out.ends_with("{\n");
+        if start_line(token) > self.previous_line + 1
+            && self.line.is_empty()
+            && !self.out.is_empty()
+            && !after_open_brace
+            && token.ty() != TokenType::RightBrace
+        {
+            self.out.push('\n');
+        }
+    }
+
+    fn comment(&mut self, token: &Token<'a>, next: Option<&Token>) {
+        // A block comment with code after it on its line stays in the line
+        let inline = token.lexeme().starts_with("/*")
+            && !token.lexeme().contains('\n')
+            && next.is_some_and(|t| t.ty() != TokenType::Eof && start_line(t) == token.line());
+        if inline {
+            if self.pending_newline {
+                self.flush(None);
+            }
+            self.blank_line(token);
+            self.push(TokenType::Comment, token.lexeme());
+            self.previous_line = token.line();
+            return;
+        }
+
+        let trailing = !self.line.is_empty() && start_line(token) == self.previous_line;
+        if trailing {
+            if !self.pending_newline {
+                self.continuation = true;
+            }
+            self.flush(Some(token.lexeme()));
+        } else {
+            if !self.line.is_empty() && !self.pending_newline {
+                // The statement goes on after the comment
+                self.continuation = true;
+            }
+            self.flush(None);
+            self.blank_line(token);
+            let indent = self.indent + usize::from(self.continuation);
+            self.out.push_str(&INDENT.repeat(indent));
+            self.out.push_str(token.lexeme());
+            self.out.push('\n');
+        }
+        self.previous_line = token.line();
+    }
+
+    fn token(&mut self, token: &Token<'a>, next: Option<&Token>, source: &'a str) {
+        use TokenType::*;
+
+        let ty = token.ty();
+        if self.pending_newline {
+            let else_after_brace =
+                ty == Else && self.previous.is_some_and(|t| t.ty() == RightBrace);
+            if else_after_brace {
+                self.pending_newline = false;
+            } else {
+                self.flush(None);
+            }
+        }
+        self.blank_line(token);
+
+        match ty {
+            LeftBrace => {
+                self.push(ty, text(token, source));
+                // Empty blocks stay on one line
+                if !next.is_some_and(|t| t.ty() == RightBrace) {
+                    self.indent += 1;
+                    self.end_statement();
+                }
+            }
+            RightBrace => {
+                // The opening brace of an empty block is still on the line
+                let empty_block = self.line.last().is_some_and(|p| p.ty == LeftBrace);
+                if !empty_block {
+                    self.flush(None);
+                    self.indent = self.indent.saturating_sub(1);
+                }
+                self.push(ty, text(token, source));
+                self.end_statement();
+            }
+            SemiColon => {
+                self.push(ty, text(token, source));
+                if self.depth == 0 {
+                    self.end_statement();
+                }
+            }
+            _ => self.push(ty, text(token, source)),
+        }
+        self.previous = Some(*token);
+        self.previous_line = token.line();
+    }
+}
+
+fn width(pieces: &[Piece]) -> usize {
+    pieces
+        .iter()
+        .map(|piece| piece.text.len() + usize::from(piece.space_before))
+        .sum()
+}
+
+fn render(pieces: &[Piece], indent: usize) -> String {
+    let mut line = INDENT.repeat(indent);
+    if indent * INDENT.len() + width(pieces) <= MAX_WIDTH {
+        for piece in pieces {
+            if piece.space_before {
+                line.push(' ');
+            }
+            line.push_str(piece.text);
+        }
+        return line;
+    }
+
+    // Breaks the outermost argument lists: one argument per line, indented
+    // one level deeper than the line
+    let level = pieces
+        .iter()
+        .filter(|piece| piece.ty == TokenType::Comma)
+        .map(|piece| piece.depth)
+        .min();
+    let mut breaks = vec![false; pieces.len()];
+    if let Some(level) = level {
+        let mut open = None;
+        let mut has_comma = false;
+        for (index, piece) in pieces.iter().enumerate() {
+            match piece.ty {
+                TokenType::LeftParen | TokenType::LeftBracket if piece.depth + 1 == level => {
+                    open = Some(index);
+                    has_comma = false;
+                }
+                TokenType::Comma if piece.depth == level => has_comma = true,
+                TokenType::RightParen | TokenType::RightBracket if piece.depth + 1 == level => {
+                    if let (Some(start), true) = (open, has_comma) {
+                        // A break after the opening bracket, every comma and
+                        // before the closing one
+                        breaks[start] = true;
+                        for (i, piece) in pieces.iter().enumerate().take(index).skip(start) {
+                            if piece.ty == TokenType::Comma && piece.depth == level {
+                                breaks[i] = true;
+                            }
+                        }
+                        breaks[index - 1] = true;
+                    }
+                    open = None;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut broken = false;
+    for (index, piece) in pieces.iter().enumerate() {
+        if piece.space_before && !broken {
+            line.push(' ');
+        }
+        line.push_str(piece.text);
+        broken = breaks[index];
+        if broken {
+            let inner = index + 1 < pieces.len()
+                && pieces[index + 1].ty != TokenType::RightParen
+                && pieces[index + 1].ty != TokenType::RightBracket;
+            line.push('\n');
+            line.push_str(&INDENT.repeat(indent + usize::from(inner)));
+        }
+    }
+    line
+}
+
+/// Prints `source` in the canonical style, comments included.
+pub fn format(source: &str) -> Result<String, FormatError> {
+    let scanner = Scanner::new(source.to_string());
+    let source = scanner.source();
+    let tokens = scanner.tokenize_with_comments();
+    for token in &tokens {
+        if let TokenType::Error(message) = token.ty() {
+            return Err(FormatError::Scan {
+                line: token.line() + 1,
+                message,
+            });
+        }
+    }
+
+    let mut formatter = Formatter::new();
+    for (index, token) in tokens.iter().enumerate() {
+        match token.ty() {
+            TokenType::Eof => (),
+            TokenType::Comment | TokenType::DocComment => {
+                formatter.comment(token, tokens.get(index + 1))
+            }
+            _ => formatter.token(token, tokens.get(index + 1), source),
+        }
+    }
+    formatter.flush(None);
+    let formatted = formatter.out;
+
+    // Only whitespace may change
+    let formatted_scanner = Scanner::new(formatted.clone());
+    let same_tokens = tokens
+        .iter()
+        .map(|t| (t.ty(), t.lexeme()))
+        .eq(formatted_scanner
+            .tokenize_with_comments()
+            .iter()
+            .map(|t| (t.ty(), t.lexeme())));
+    if !same_tokens {
+        return Err(FormatError::ChangedTokens);
+    }
+    Ok(formatted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn crox_files(dir: &Path, files: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                crox_files(&path, files);
+            } else if path.extension().is_some_and(|e| e == "crox") {
+                files.push(fs::read_to_string(path).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn canonical_style() {
+        let source = "fn  add(a,b){return a+b ;}
+let x=-add( 1 ,2)*3;if(x>=0){print x;}else{print !true;}
+while(x<10)x=x+1;
+fn  empty( ) { }";
+
+        assert_eq!(
+            format(source).unwrap(),
+            "fn add(a, b) {
+  return a + b;
+}
+let x = -add(1, 2) * 3;
+if (x >= 0) {
+  print x;
+} else {
+  print !true;
+}
+while (x < 10) x = x + 1;
+fn empty() {}
+"
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let source = "// Header
+
+
+let a = 1; // one
+{
+    // inside
+
+    print a;   // trailing
+    print add(a, // first
+        2);
+}
+// end";
+
+        assert_eq!(
+            format(source).unwrap(),
+            "// Header
+
+let a = 1; // one
+{
+  // inside
+
+  print a; // trailing
+  print add(a, // first
+    2);
+}
+// end
+"
+        );
+    }
+
+    #[test]
+    fn long_lines_break_after_commas() {
+        let source = format!(
+            "print combine(first_argument_{0}, second_argument_{0}, f(1, 2), \"{0}\");",
+            "x".repeat(30)
+        );
+        let expected = format!(
+            "print combine(
+  first_argument_{0},
+  second_argument_{0},
+  f(1, 2),
+  \"{0}\"
+);
+",
+            "x".repeat(30)
+        );
+
+        assert_eq!(format(&source).unwrap(), expected);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let mut sources = Vec::new();
+        crox_files(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/crox"),
+            &mut sources,
+        );
+        assert!(!sources.is_empty());
+
+        for source in sources {
+            let formatted = match format(&source) {
+                Ok(formatted) => formatted,
+                // Scripts testing lexical errors can't be formatted
+                Err(FormatError::Scan { .. }) => continue,
+                Err(error) => panic!("{error}\n{source}"),
+            };
+            assert_eq!(format(&formatted).unwrap(), formatted, "{source}");
+        }
+    }
+
+    #[test]
+    fn lexical_errors() {
+        assert_eq!(
+            format("let s = \"unterminated;").unwrap_err().to_string(),
+            "[line 1] Error: Unterminated String"
+        );
+    }
+
+    #[test]
+    fn block_and_doc_comments() {
+        let source = "/// Adds.
+fn  add(a, /* second */ b) {
+    /* nested /* comment */
+       on two lines */
+  return a+b; /* trailing */
+}
+/* lead */ print add(1, 2);";
+
+        assert_eq!(
+            format(source).unwrap(),
+            "/// Adds.
+fn add(a, /* second */ b) {
+  /* nested /* comment */
+       on two lines */
+  return a + b; /* trailing */
+}
+/* lead */ print add(1, 2);
+"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::mem;
+use std::rc::{Rc, Weak};
+
+// Collections are also triggered when the allocated bytes reach this
+// threshold, which then grows with the live bytes like clox does.
+const INITIAL_COLLECTION_THRESHOLD: usize = 1024 * 1024;
+const HEAP_GROW_FACTOR: usize = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    // Bytes of the objects allocated and not collected yet
+    pub current: usize,
+    // Highest value `current` reached
+    pub peak: usize,
+    pub collections: usize,
+}
+
+/// Keeps track of the strings a script builds by concatenation while it
+/// runs, the other values aren't counted.
+///
+/// Values are reference counted so objects are freed as soon as the script
+/// drops them, a collection only walks the tracked objects to account for
+/// the ones that were freed since the last collection.
+pub struct Heap {
+    objects: Vec<(Weak<str>, usize)>,
+    stats: HeapStats,
+    limit: Option<usize>,
+    next_collection: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            stats: HeapStats::default(),
+            limit: None,
+            next_collection: INITIAL_COLLECTION_THRESHOLD,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Allocates the concatenation of `parts`, the limit is checked before
+    /// the string is built.
+    pub fn alloc_string(&mut self, parts: &[&str]) -> Result<Rc<str>, String> {
+        let len = parts.iter().map(|part| part.len()).sum::<usize>();
+        // The reference counts are allocated along with the string
+        let size = len + 2 * mem::size_of::<usize>();
+        self.reserve(size)?;
+
+        let string: Rc<str> = Rc::from(parts.concat());
+        self.objects.push((Rc::downgrade(&string), size));
+        self.stats.current += size;
+        self.stats.peak = self.stats.peak.max(self.stats.current);
+        Ok(string)
+    }
+
+    fn reserve(&mut self, size: usize) -> Result<(), String> {
+        let exceeds_limit = |heap: &Self| {
+            heap.limit
+                .is_some_and(|limit| heap.stats.current + size > limit)
+        };
+        if exceeds_limit(self) || self.stats.current + size > self.next_collection {
+            self.collect();
+        }
+        match self.limit {
+            Some(limit) if exceeds_limit(self) => Err(format!(
+                "Out of memory ({} bytes in use, limit is {} bytes).",
+                self.stats.current, limit
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn collect(&mut self) {
+        let mut freed = 0;
+        self.objects.retain(|(object, size)| {
+            let alive = object.strong_count() > 0;
+            if !alive {
+                freed += size;
+            }
+            alive
+        });
+        self.stats.current -= freed;
+        self.stats.collections += 1;
+        self.next_collection =
+            (self.stats.current * HEAP_GROW_FACTOR).max(INITIAL_COLLECTION_THRESHOLD);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn collect_accounts_for_dropped_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc_string(&["ke", "pt"]).unwrap();
+        let dropped = heap.alloc_string(&["dropped"]).unwrap();
+        let allocated = heap.stats().current;
+
+        drop(dropped);
+        heap.collect();
+
+        assert_eq!(
+            heap.stats().current,
+            allocated - 7 - 2 * mem::size_of::<usize>()
+        );
+        assert_eq!(heap.stats().peak, allocated);
+        assert_eq!(&*kept, "kept");
+    }
+
+    #[test]
+    fn limit_collects_before_failing() {
+        let mut heap = Heap::new();
+        let overhead = 2 * mem::size_of::<usize>();
+        heap.set_limit(Some(2 * (10 + overhead)));
+
+        let first = heap.alloc_string(&[&"a".repeat(10)]).unwrap();
+        drop(heap.alloc_string(&[&"b".repeat(10)]).unwrap());
+        // The second string is garbage, collecting it makes room
+        let _second = heap.alloc_string(&[&"c".repeat(10)]).unwrap();
+        assert_eq!(heap.stats().collections, 1);
+
+        assert!(heap.alloc_string(&[&"d".repeat(10)]).is_err());
+        drop(first);
+    }
+}
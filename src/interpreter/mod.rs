@@ -1 +1,5 @@
+pub mod heap;
+pub mod modules;
+pub mod output;
+pub mod tracing;
 pub mod virtual_machine;
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Finds the source of the modules imported by a script.
+pub trait ModuleResolver {
+    /// Key of the module imported as `path` by the module with the key
+    /// `importer`. Imports resolved to the same key share a single module.
+    fn resolve(&self, path: &str, importer: &str) -> Result<String, String>;
+
+    /// Source of the module with this key.
+    fn load(&self, key: &str) -> Result<String, String>;
+}
+
+/// Loads modules from files, the paths are relative to the file importing
+/// them and the keys are the canonical paths.
+pub struct FileResolver;
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, path: &str, importer: &str) -> Result<String, String> {
+        let dir = Path::new(importer).parent().unwrap_or(Path::new(""));
+        let file = dir.join(path);
+        match fs::canonicalize(file) {
+            Ok(file) => Ok(file.to_string_lossy().into_owned()),
+            Err(_) => Err(format!("Can't find module \"{path}\".")),
+        }
+    }
+
+    fn load(&self, key: &str) -> Result<String, String> {
+        fs::read_to_string(key).map_err(|error| format!("Can't read module \"{key}\": {error}."))
+    }
+}
+
+/// Modules kept in memory, for embedders and tests. The paths are the keys,
+/// they don't depend on the importer.
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct MemoryResolver {
+    modules: HashMap<String, String>,
+}
+
+#[allow(dead_code)]
+impl MemoryResolver {
+    pub fn add(&mut self, path: &str, source: &str) {
+        self.modules.insert(path.to_string(), source.to_string());
+    }
+}
+
+impl ModuleResolver for MemoryResolver {
+    fn resolve(&self, path: &str, _importer: &str) -> Result<String, String> {
+        match self.modules.contains_key(path) {
+            true => Ok(path.to_string()),
+            false => Err(format!("Can't find module \"{path}\".")),
+        }
+    }
+
+    fn load(&self, key: &str) -> Result<String, String> {
+        self.modules
+            .get(key)
+            .cloned()
+            .ok_or_else(|| format!("Can't find module \"{key}\"."))
+    }
+}
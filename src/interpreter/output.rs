@@ -0,0 +1,64 @@
+use std::cell::RefCell;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::rc::Rc;
+
+/// Destination of everything a script prints.
+pub trait OutputSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Buffered stdout, the sink used by default.
+pub struct StdoutSink {
+    out: BufWriter<Stdout>,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self {
+            out: BufWriter::new(io::stdout()),
+        }
+    }
+}
+
+impl OutputSink for StdoutSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.out, "{line}")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Keeps the output in memory. Clones share the same buffer so the embedder
+/// can keep one to read what the script printed.
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    buffer: Rc<RefCell<String>>,
+}
+
+#[allow(dead_code)]
+impl MemorySink {
+    pub fn contents(&self) -> String {
+        self.buffer.borrow().clone()
+    }
+
+    /// Returns the output printed since the last call and clears it.
+    pub fn take(&self) -> String {
+        std::mem::take(&mut *self.buffer.borrow_mut())
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.push_str(line);
+        buffer.push('\n');
+        Ok(())
+    }
+}
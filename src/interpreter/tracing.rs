@@ -0,0 +1,300 @@
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use serde_json::json;
+
+use crate::compiler::{Chunk, Value};
+use crate::disassembler;
+
+/// The call frame an instruction is executed in.
+pub struct FrameInfo<'a> {
+    pub function: &'a str,
+    // Number of frames on the call stack, the script frame included
+    pub depth: usize,
+    // Index of the first stack slot owned by the frame
+    pub base: usize,
+}
+
+/// Snapshot of the VM right before an instruction is executed.
+pub struct TraceEvent<'a> {
+    pub chunk: &'a Chunk,
+    pub offset: usize,
+    pub stack: &'a [Value],
+    pub frame: FrameInfo<'a>,
+}
+
+impl TraceEvent<'_> {
+    pub fn line(&self) -> usize {
+        self.chunk.get_line(self.offset) + 1
+    }
+
+    pub fn instruction(&self) -> String {
+        disassembler::instruction(self.chunk, self.offset)
+    }
+}
+
+/// Receives every instruction executed by a `VM` once installed with
+/// `VM::set_tracer`.
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+/// Writes one human readable line per instruction.
+pub struct TextTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let stack = event
+            .stack
+            .iter()
+            .map(|value| format!("[ {value} ]"))
+            .collect::<String>();
+        // A broken trace sink must not stop the script
+        let _ = writeln!(
+            self.out,
+            "{:<12} {:04} {:4} {:<32} {}",
+            event.frame.function,
+            event.offset,
+            event.line(),
+            event.instruction(),
+            stack
+        );
+    }
+}
+
+/// Writes one JSON object per instruction.
+pub struct JsonLinesTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> Tracer for JsonLinesTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let stack = event
+            .stack
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>();
+        let entry = json!({
+            "offset": event.offset,
+            "line": event.line(),
+            "instruction": event.instruction(),
+            "stack": stack,
+            "frame": {
+                "function": event.frame.function,
+                "depth": event.frame.depth,
+                "base": event.frame.base,
+            },
+        });
+        let _ = writeln!(self.out, "{entry}");
+    }
+}
+
+/// Forwards to the inner tracer only the instructions matching the filter.
+pub struct FilteredTracer<T: Tracer> {
+    inner: T,
+    function: Option<String>,
+    lines: Option<RangeInclusive<usize>>,
+}
+
+impl<T: Tracer> FilteredTracer<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            function: None,
+            lines: None,
+        }
+    }
+
+    pub fn function(mut self, name: &str) -> Self {
+        self.function = Some(name.to_string());
+        self
+    }
+
+    /// Lines are 1-based and inclusive on both ends
+    pub fn lines(mut self, lines: RangeInclusive<usize>) -> Self {
+        self.lines = Some(lines);
+        self
+    }
+}
+
+impl<T: Tracer> Tracer for FilteredTracer<T> {
+    fn trace(&mut self, event: &TraceEvent) {
+        if let Some(function) = &self.function {
+            if function != event.frame.function {
+                return;
+            }
+        }
+        if let Some(lines) = &self.lines {
+            if !lines.contains(&event.line()) {
+                return;
+            }
+        }
+        self.inner.trace(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::output::OutputSink;
+    use crate::interpreter::virtual_machine::VM;
+    use crate::scanner::Scanner;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Keeps the printed lines until flushed, like the stdout sink
+    struct BufferedSink {
+        out: SharedBuffer,
+        lines: Vec<String>,
+    }
+
+    impl OutputSink for BufferedSink {
+        fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+            self.lines.push(line.to_string());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            for line in self.lines.drain(..) {
+                writeln!(self.out, "{line}")?;
+            }
+            Ok(())
+        }
+    }
+
+    fn run_traced(source: &str, tracer: Box<dyn Tracer>) {
+        let scanner = Scanner::new(source.to_string());
+        let chunk = Compiler::new(&scanner).compile().unwrap();
+        let mut vm = VM::new();
+        vm.set_tracer(tracer);
+        vm.interpret(chunk);
+    }
+
+    #[test]
+    fn text_trace() {
+        let buffer = SharedBuffer::default();
+        run_traced("1 + 2;", Box::new(TextTracer::new(buffer.clone())));
+
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        let lines = trace.lines().map(str::trim_end).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "script       0000    1 CONSTANT            0 '1'        [ <script> ]",
+                "script       0001    1 CONSTANT            1 '2'        [ <script> ][ 1 ]",
+                "script       0002    1 ADD                              [ <script> ][ 1 ][ 2 ]",
+                "script       0003    1 POP                              [ <script> ][ 3 ]",
+                "script       0004    1 NULL                             [ <script> ]",
+                "script       0005    1 RETURN                           [ <script> ][ null ]",
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_and_output_are_interleaved() {
+        let buffer = SharedBuffer::default();
+        let scanner = Scanner::new(String::from("print 1;\nprint 2;"));
+        let chunk = Compiler::new(&scanner).compile().unwrap();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(BufferedSink {
+            out: buffer.clone(),
+            lines: Vec::new(),
+        }));
+        vm.set_tracer(Box::new(TextTracer::new(buffer.clone())));
+        vm.interpret(chunk);
+
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        let lines = trace
+            .lines()
+            .map(|line| {
+                line.split_whitespace()
+                    .take(4)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "script 0000 1 CONSTANT",
+                "script 0001 1 PRINT",
+                "1",
+                "script 0002 2 CONSTANT",
+                "script 0003 2 PRINT",
+                "2",
+                "script 0004 2 NULL",
+                "script 0005 2 RETURN",
+            ]
+        );
+    }
+
+    #[test]
+    fn json_lines_trace_filtered_by_line() {
+        let buffer = SharedBuffer::default();
+        let tracer = FilteredTracer::new(JsonLinesTracer::new(buffer.clone())).lines(2..=2);
+        run_traced("1 +\n2;", Box::new(tracer));
+
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        let events = trace
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[0],
+            json!({
+                "offset": 1,
+                "line": 2,
+                "instruction": "CONSTANT            1 '2'",
+                "stack": ["<script>", "1"],
+                "frame": { "function": "script", "depth": 1, "base": 0 },
+            })
+        );
+    }
+
+    #[test]
+    fn filtered_by_function() {
+        let buffer = SharedBuffer::default();
+        let tracer = FilteredTracer::new(TextTracer::new(buffer.clone())).function("f");
+        run_traced("fn f() { return 1; }\nf();", Box::new(tracer));
+
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        let lines = trace.lines().map(str::trim_end).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "f            0000    1 CONSTANT            0 '1'        [ <script> ][ <fn f> ]",
+                "f            0001    1 RETURN                           [ <script> ][ <fn f> ][ 1 ]",
+            ]
+        );
+    }
+}
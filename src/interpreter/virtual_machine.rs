@@ -1,56 +1,775 @@
-use crate::compiler::{Chunk, OpCode};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use crate::compiler::function::SCRIPT_NAME;
+use crate::compiler::optimizer::OptLevel;
+use crate::compiler::value::{Module, INTEGER_OVERFLOW};
 use crate::compiler::Value;
-use crate::logging;
+use crate::compiler::{Chunk, Compiler, Function, OpCode};
+use crate::natives;
+use crate::scanner::Scanner;
+
+use super::heap::{Heap, HeapStats};
+use super::modules::{FileResolver, ModuleResolver};
+use super::output::{MemorySink, OutputSink, StdoutSink};
+use super::tracing::{FrameInfo, TraceEvent, Tracer};
+
+/// A runtime error with the call stack it happened in, the innermost call
+/// first.
+#[derive(Debug, Error)]
+#[error("{message}{}", trace.iter().map(|frame| format!("\n{frame}")).collect::<String>())]
+pub struct RuntimeError {
+    pub message: String,
+    // `[line N] in f()` for each frame
+    pub trace: Vec<String>,
+}
 
 #[allow(dead_code)]
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError,
+    RuntimeError(RuntimeError),
+    // The script is paused before an instruction it had no fuel left for,
+    // see `VM::refuel` and `VM::resume`
+    OutOfFuel,
+}
+
+/// State of a script driven with `VM::step` or `VM::run_for`.
+#[allow(dead_code)]
+pub enum ExecutionState {
+    // The script has instructions left to execute
+    Running,
+    // The script returned, with the value it returned
+    Finished(Value),
+    // The script stopped on a runtime error
+    Errored(RuntimeError),
+}
+
+// Why `VM::run` stopped
+enum Progress {
+    Finished(Value),
+    Errored(RuntimeError),
+    OutOfFuel,
+    BudgetSpent,
+}
+
+/// Fuel consumed by each instruction when fuel metering is enabled.
+pub type FuelCosts = fn(&OpCode) -> u64;
+
+fn unit_cost(_instruction: &OpCode) -> u64 {
+    1
+}
+
+/// Bounds on the resources a script can use. Exceeding the stack or frame
+/// limit stops the script with a `Stack overflow` runtime error, exceeding the
+/// heap limit with an `Out of memory` one.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    // Maximum number of values on the stack, checked after every instruction
+    pub max_stack: usize,
+    // Maximum number of nested calls, the script itself included
+    pub max_frames: usize,
+    // Maximum number of bytes of the strings the script builds by
+    // concatenation and still uses, the other values are not counted
+    pub max_heap: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_stack: 64 * 1024,
+            max_frames: 256,
+            max_heap: None,
+        }
+    }
+}
+
+/// A call frame as seen by a debugger.
+pub struct FrameSnapshot<'a> {
+    pub function: &'a Function,
+    // Number of frames up to this one, the script's frame has depth 1
+    pub depth: usize,
+    // Line of the instruction being executed, the next one for the
+    // innermost frame and the call for its callers
+    pub line: usize,
+    // Locals in scope at that instruction, in slot order
+    pub locals: Vec<(&'a str, &'a Value)>,
+}
+
+struct CallFrame {
+    function: Rc<Function>,
+    // Index of the next instruction to execute
+    ip: usize,
+    // Index of the first stack slot of the frame, holding the called function
+    base: usize,
+    // Set for the script of a module being imported, its module object is
+    // the result of the import
+    import: Option<usize>,
+}
+
+#[derive(Clone)]
+struct LoadedModule {
+    // Key given by the resolver
+    key: String,
+    value: Rc<Module>,
+    globals: HashMap<Rc<str>, Value>,
+    // Set once the script of the module returned
+    loaded: bool,
+}
+
+impl LoadedModule {
+    fn new(id: usize, key: String, name: &str) -> Self {
+        Self {
+            key,
+            value: Rc::new(Module {
+                id,
+                name: Rc::from(name),
+            }),
+            globals: HashMap::new(),
+            loaded: false,
+        }
+    }
 }
 
 pub struct VM {
-    instruction_index: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    // The script run by the VM is the first module, it never counts as
+    // loaded so importing it is a circular import
+    modules: Vec<LoadedModule>,
+    module_keys: HashMap<String, usize>,
+    resolver: Box<dyn ModuleResolver>,
+    opt_level: OptLevel,
+    heap: Heap,
+    limits: Limits,
+    // None when fuel metering is disabled
+    fuel: Option<u64>,
+    fuel_costs: FuelCosts,
+    tracer: Option<Box<dyn Tracer>>,
+    output: Box<dyn OutputSink>,
 }
 
 impl VM {
     pub fn new() -> Self {
         VM {
-            instruction_index: 0,
+            frames: Vec::new(),
             stack: Vec::with_capacity(1024),
+            modules: vec![LoadedModule::new(0, String::from(SCRIPT_NAME), SCRIPT_NAME)],
+            module_keys: HashMap::from([(String::from(SCRIPT_NAME), 0)]),
+            resolver: Box::new(FileResolver),
+            opt_level: OptLevel::default(),
+            heap: Heap::new(),
+            limits: Limits::default(),
+            fuel: None,
+            fuel_costs: unit_cost,
+            tracer: None,
+            output: Box::new(StdoutSink::new()),
         }
     }
 
+    #[allow(dead_code)]
+    pub fn set_output(&mut self, output: Box<dyn OutputSink>) {
+        self.output = output;
+    }
+
+    /// Loads the imported modules, from files by default.
+    #[allow(dead_code)]
+    pub fn set_resolver(&mut self, resolver: Box<dyn ModuleResolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Path of the script the imports are resolved from, resolved itself by
+    /// the resolver set at this point.
+    pub fn set_script_path(&mut self, path: &str) {
+        let key = self
+            .resolver
+            .resolve(path, "")
+            .unwrap_or_else(|_| path.to_string());
+        self.module_keys.remove(&self.modules[0].key);
+        self.module_keys.insert(key.clone(), 0);
+        self.modules[0] = LoadedModule {
+            globals: std::mem::take(&mut self.modules[0].globals),
+            ..LoadedModule::new(0, key, path)
+        };
+    }
+
+    /// Level the imported modules are compiled at.
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.heap.set_limit(limits.max_heap);
+    }
+
+    #[allow(dead_code)]
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Enables fuel metering: every executed instruction consumes its cost
+    /// and the script is paused with `InterpretResult::OutOfFuel` when the
+    /// next instruction costs more than the fuel left.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    /// Adds fuel to a metered VM, doesn't enable metering on its own.
+    #[allow(dead_code)]
+    pub fn refuel(&mut self, fuel: u64) {
+        if let Some(left) = self.fuel.as_mut() {
+            *left = left.saturating_add(fuel);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Every instruction costs 1 unless costs are set.
+    #[allow(dead_code)]
+    pub fn set_fuel_costs(&mut self, costs: FuelCosts) {
+        self.fuel_costs = costs;
+    }
+
+    /// Runs a new script, a script paused by lack of fuel is discarded.
     pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
+        if let ExecutionState::Errored(error) = self.load(chunk) {
+            return InterpretResult::RuntimeError(error);
+        }
+        self.resume()
+    }
+
+    /// Continues a script paused by lack of fuel from where it stopped.
+    pub fn resume(&mut self) -> InterpretResult {
+        let result = match self.run(None) {
+            Progress::Finished(_) => InterpretResult::Ok,
+            Progress::Errored(error) => InterpretResult::RuntimeError(error),
+            Progress::OutOfFuel => InterpretResult::OutOfFuel,
+            Progress::BudgetSpent => unreachable!("Expected no instruction budget"),
+        };
+        self.flush_output();
+        result
+    }
+
+    /// Prepares a new script to be executed with `step` or `run_for`, a
+    /// script already loaded is discarded.
+    pub fn load(&mut self, chunk: Chunk) -> ExecutionState {
+        self.load_script(Function::script(chunk))
+    }
+
+    /// Same as `load` for a script compiled with `Compiler::compile_script`.
+    pub fn load_script(&mut self, script: Function) -> ExecutionState {
+        self.stack.clear();
+        self.frames.clear();
+        self.forget_partial_imports();
+        let script = Rc::new(script);
+        self.stack.push(Value::Function(Rc::clone(&script)));
+        match self.call(script, 0) {
+            Ok(()) => ExecutionState::Running,
+            Err(message) => ExecutionState::Errored(self.runtime_error(message)),
+        }
+    }
+
+    /// Executes the next instruction of the loaded script.
+    #[allow(dead_code)]
+    pub fn step(&mut self) -> ExecutionState {
+        self.run_for(1)
+    }
+
+    /// Executes at most `instructions` instructions of the loaded script.
+    /// Running out of fuel also leaves the script `Running`, it goes on once
+    /// the VM is refueled.
+    pub fn run_for(&mut self, instructions: usize) -> ExecutionState {
+        let state = match self.run(Some(instructions)) {
+            Progress::Finished(value) => ExecutionState::Finished(value),
+            Progress::Errored(error) => ExecutionState::Errored(error),
+            Progress::OutOfFuel | Progress::BudgetSpent => ExecutionState::Running,
+        };
+        self.flush_output();
+        state
+    }
+
+    /// Call frames of the loaded script, the script first and the function
+    /// being executed last.
+    pub fn frames(&self) -> Vec<FrameSnapshot<'_>> {
+        (0..self.frames.len())
+            .map(|depth| self.snapshot(depth))
+            .collect()
+    }
+
+    /// Frame of the function being executed, None once the script is over.
+    pub fn current_frame(&self) -> Option<FrameSnapshot<'_>> {
+        let depth = self.frames.len().checked_sub(1)?;
+        Some(self.snapshot(depth))
+    }
+
+    /// Number of call frames, the script's included, 0 once it is over.
+    pub fn frame_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Line of `current_frame`, without collecting its locals.
+    pub fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        let chunk = frame.function.chunk();
+        Some(chunk.get_line(frame.ip.min(chunk.code_nb().saturating_sub(1))))
+    }
+
+    /// Function of `current_frame` and the offset of its next instruction.
+    pub fn current_function(&self) -> Option<(&Function, usize)> {
+        let frame = self.frames.last()?;
+        Some((&frame.function, frame.ip))
+    }
+
+    fn snapshot(&self, depth: usize) -> FrameSnapshot<'_> {
+        let frame = &self.frames[depth];
+        // The callers are paused on their call instruction
+        let offset = if depth + 1 == self.frames.len() {
+            frame.ip
+        } else {
+            frame.ip.saturating_sub(1)
+        };
+        let chunk = frame.function.chunk();
+        let line = chunk.get_line(offset.min(chunk.code_nb().saturating_sub(1)));
+        let locals = frame
+            .function
+            .locals_at(offset)
+            .into_iter()
+            .filter_map(|local| {
+                let value = self.stack.get(frame.base + local.slot)?;
+                Some((local.name.as_str(), value))
+            })
+            .collect();
+        FrameSnapshot {
+            function: &frame.function,
+            depth: depth + 1,
+            line,
+            locals,
+        }
+    }
+
+    /// Global variables sorted by name.
+    pub fn globals(&self) -> Vec<(&str, &Value)> {
+        let mut globals = self.modules[0]
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_ref(), value))
+            .collect::<Vec<_>>();
+        globals.sort_by_key(|(name, _)| *name);
+        globals
+    }
+
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.modules[0].globals.get(name)
+    }
+
+    /// Calls `function` with `args` on a copy of the globals, the loaded
+    /// script isn't affected even when the call fails.
+    pub fn evaluate(&self, function: Function, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let mut vm = VM::new();
+        vm.modules = self.modules.clone();
+        vm.module_keys = self.module_keys.clone();
+        vm.set_limits(self.limits);
+        vm.set_output(Box::new(MemorySink::default()));
+
+        let function = Rc::new(function);
+        let arg_count = args.len();
+        vm.stack.push(Value::Function(Rc::clone(&function)));
+        vm.stack.extend(args);
+        if let Err(message) = vm.call(function, arg_count) {
+            return Err(vm.runtime_error(message));
+        }
+        match vm.run(None) {
+            Progress::Finished(value) => Ok(value),
+            Progress::Errored(error) => Err(error),
+            Progress::OutOfFuel | Progress::BudgetSpent => {
+                unreachable!("Expected no fuel metering nor budget")
+            }
+        }
+    }
+
+    fn run(&mut self, mut budget: Option<usize>) -> Progress {
         use OpCode::*;
 
-        while self.instruction_index < chunk.code_nb() {
-            let instruction = chunk.get_instruction(self.instruction_index);
-            logging::log_stack(&self.stack);
-            logging::log_instruction(&chunk, &instruction, self.instruction_index);
-            self.instruction_index += 1;
-            match instruction {
+        loop {
+            let Some(frame) = self.frames.last() else {
+                // Nothing is loaded or the script is over
+                return Progress::Finished(Value::Null);
+            };
+            // Chunks built by hand may not end with a Return
+            if frame.ip >= frame.function.chunk().code_nb() {
+                return Progress::Finished(Value::Null);
+            }
+            if let Some(left) = budget.as_mut() {
+                if *left == 0 {
+                    return Progress::BudgetSpent;
+                }
+                *left -= 1;
+            }
+            let instruction = frame.function.chunk().get_instruction(frame.ip);
+            if let Some(fuel) = self.fuel {
+                let cost = (self.fuel_costs)(&instruction);
+                if cost > fuel {
+                    return Progress::OutOfFuel;
+                }
+                self.fuel = Some(fuel - cost);
+            }
+            if let Some(tracer) = self.tracer.as_mut() {
+                let frame = self.frames.last().expect("Expected a call frame");
+                tracer.trace(&TraceEvent {
+                    chunk: frame.function.chunk(),
+                    offset: frame.ip,
+                    stack: &self.stack,
+                    frame: FrameInfo {
+                        function: frame.function.name(),
+                        depth: self.frames.len(),
+                        base: frame.base,
+                    },
+                });
+            }
+            self.frame_mut().ip += 1;
+
+            let mut result = match instruction {
                 Return => {
+                    let result = self.pop_value();
+                    let frame = self.frames.pop().expect("Expected a call frame");
+                    self.stack.truncate(frame.base);
+                    if self.frames.is_empty() {
+                        return Progress::Finished(result);
+                    }
+                    match frame.import {
+                        Some(id) => {
+                            let module = &mut self.modules[id];
+                            module.loaded = true;
+                            self.stack.push(Value::Module(Rc::clone(&module.value)));
+                        }
+                        None => self.stack.push(result),
+                    }
+                    Ok(())
+                }
+                Print => {
+                    let value = self.pop_value();
+                    let result = self
+                        .output
+                        .write_line(&value.to_string())
+                        .map_err(|error| format!("Can't print: {error}."));
+                    // The trace may go to the same place, the line comes
+                    // before the trace of the next instruction
+                    if self.tracer.is_some() {
+                        self.flush_output();
+                    }
+                    result
+                }
+                Pop => {
                     self.pop_value();
-                    break;
+                    Ok(())
+                }
+                Null => {
+                    self.stack.push(Value::Null);
+                    Ok(())
+                }
+                True => {
+                    self.stack.push(Value::Bool(true));
+                    Ok(())
                 }
-                Negate => {
-                    let Value::Number(num) = self.pop_value();
-                    self.stack.push(Value::Number(-num));
+                False => {
+                    self.stack.push(Value::Bool(false));
+                    Ok(())
                 }
-                Add | Sub | Mul | Mod | Div => {
-                    let res = self.binary_op(instruction);
-                    self.stack.push(res);
+                Negate => match self.pop_value() {
+                    Value::Number(num) => {
+                        self.stack.push(Value::Number(-num));
+                        Ok(())
+                    }
+                    Value::Int(num) => match num.checked_neg() {
+                        Some(negated) => {
+                            self.stack.push(Value::Int(negated));
+                            Ok(())
+                        }
+                        None => Err(String::from(INTEGER_OVERFLOW)),
+                    },
+                    _ => Err(String::from("Operand must be a number.")),
+                },
+                Not => {
+                    let value = self.pop_value();
+                    self.stack.push(Value::Bool(value.is_falsey()));
+                    Ok(())
+                }
+                Add | Sub | Mul | Mod | Div | IntDiv | Pow | Greater | Less => {
+                    self.binary_op(instruction)
+                }
+                Equal => {
+                    let rhs = self.pop_value();
+                    let lhs = self.pop_value();
+                    self.stack.push(Value::Bool(lhs == rhs));
+                    Ok(())
                 }
                 Constant(index) => {
-                    let constant = chunk.get_constant(index);
+                    let constant = self.chunk().get_constant(index);
+                    self.stack.push(constant);
+                    Ok(())
+                }
+                AddConstant(index) | SubConstant(index) => {
+                    let constant = self.chunk().get_constant(index);
                     self.stack.push(constant);
+                    match instruction {
+                        AddConstant(_) => self.binary_op(Add),
+                        _ => self.binary_op(Sub),
+                    }
+                }
+                DefineGlobal(index) => {
+                    let name = self.global_name(index);
+                    let value = self.pop_value();
+                    self.globals_mut().insert(name, value);
+                    Ok(())
+                }
+                GetGlobal(index) => {
+                    let name = self.global_name(index);
+                    let value = self.globals_mut().get(&name).cloned();
+                    match value.or_else(|| natives::lookup(&name).map(Value::Native)) {
+                        Some(value) => {
+                            self.stack.push(value);
+                            Ok(())
+                        }
+                        None => Err(format!("Undefined variable '{name}'.")),
+                    }
+                }
+                SetGlobal(index) => {
+                    let name = self.global_name(index);
+                    let value = self.peek(0).clone();
+                    match self.globals_mut().get_mut(&name) {
+                        Some(global) => {
+                            *global = value;
+                            Ok(())
+                        }
+                        None => Err(format!("Undefined variable '{name}'.")),
+                    }
+                }
+                GetLocal(slot) => {
+                    let value = self.stack[self.frame().base + slot].clone();
+                    self.stack.push(value);
+                    Ok(())
+                }
+                SetLocal(slot) => {
+                    let base = self.frame().base;
+                    self.stack[base + slot] = self.peek(0).clone();
+                    Ok(())
+                }
+                Jump(target) => {
+                    self.frame_mut().ip = target;
+                    Ok(())
+                }
+                JumpIfFalse(target) => {
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip = target;
+                    }
+                    Ok(())
+                }
+                Call(arg_count) => self.call_value(arg_count),
+                Import(index) => self.import(index),
+                GetProperty(index) => {
+                    let name = self.global_name(index);
+                    match self.pop_value() {
+                        Value::Module(module) => match self.modules[module.id].globals.get(&name) {
+                            Some(value) => {
+                                self.stack.push(value.clone());
+                                Ok(())
+                            }
+                            None => Err(format!(
+                                "Undefined property '{name}' of module \"{}\".",
+                                module.name
+                            )),
+                        },
+                        _ => Err(String::from("Only modules have properties.")),
+                    }
+                }
+            };
+
+            if result.is_ok() && self.stack.len() > self.limits.max_stack {
+                result = Err(self.stack_overflow());
+            }
+            if let Err(message) = result {
+                return Progress::Errored(self.runtime_error(message));
+            }
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("Expected a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Expected a call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        self.frame().function.chunk()
+    }
+
+    // Globals of the module of the function being executed
+    fn globals_mut(&mut self) -> &mut HashMap<Rc<str>, Value> {
+        let module = self.frame().function.module();
+        &mut self.modules[module].globals
+    }
+
+    // Pushes the module object of the path in the constant at `index`. The
+    // first import of a module calls its script, the object is pushed once
+    // the script returns.
+    fn import(&mut self, index: usize) -> Result<(), String> {
+        let path = self.global_name(index);
+        let importer = &self.modules[self.frame().function.module()].key;
+        let key = self.resolver.resolve(&path, importer)?;
+        if let Some(&id) = self.module_keys.get(&key) {
+            let module = &self.modules[id];
+            if !module.loaded {
+                return Err(self.circular_import(id));
+            }
+            self.stack.push(Value::Module(Rc::clone(&module.value)));
+            return Ok(());
+        }
+
+        let source = self.resolver.load(&key)?;
+        let id = self.modules.len();
+        let scanner = Scanner::new(source);
+        let mut compiler = Compiler::new(&scanner);
+        compiler.set_opt_level(self.opt_level);
+        compiler.set_module(id);
+        let script = match compiler.compile_script() {
+            Ok(script) => script,
+            Err(errors) => return Err(format!("Can't compile module \"{path}\":\n{errors}")),
+        };
+        self.module_keys.insert(key.clone(), id);
+        self.modules.push(LoadedModule::new(id, key, &path));
+        let script = Rc::new(script);
+        self.stack.push(Value::Function(Rc::clone(&script)));
+        self.call(script, 0)?;
+        self.frame_mut().import = Some(id);
+        Ok(())
+    }
+
+    // The import chain from the first import of `id` back to it
+    fn circular_import(&self, id: usize) -> String {
+        let importing = self.frames.iter().filter_map(|frame| frame.import);
+        let chain = std::iter::once(0)
+            .chain(importing)
+            .skip_while(|module| *module != id)
+            .chain([id])
+            .map(|module| format!("\"{}\"", self.modules[module].value.name))
+            .collect::<Vec<_>>();
+        format!("Circular import: {}.", chain.join(" -> "))
+    }
+
+    // The modules left half loaded by a script that stopped are dropped and
+    // loaded again by the next import. The modules after the first of them
+    // were imported while it loaded, only it can refer to them.
+    fn forget_partial_imports(&mut self) {
+        let kept = self
+            .modules
+            .iter()
+            .skip(1)
+            .position(|module| !module.loaded)
+            .map_or(self.modules.len(), |partial| partial + 1);
+        self.modules.truncate(kept);
+        self.module_keys.retain(|_, id| *id < kept);
+    }
+
+    fn global_name(&self, index: usize) -> Rc<str> {
+        match self.chunk().get_constant(index) {
+            Value::Str(name) => name,
+            _ => unreachable!("Expected the name of a global"),
+        }
+    }
+
+    fn call_value(&mut self, arg_count: usize) -> Result<(), String> {
+        match self.peek(arg_count).clone() {
+            Value::Function(function) => self.call(function, arg_count),
+            Value::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(format!(
+                        "Expected {} arguments but got {}.",
+                        native.arity, arg_count
+                    ));
                 }
+                let args = self.stack.split_off(self.stack.len() - arg_count);
+                let result = (native.function)(&args)?;
+                // The callee
+                self.pop_value();
+                self.stack.push(result);
+                Ok(())
             }
+            _ => Err(String::from("Can only call functions.")),
         }
-        logging::log_stack(&self.stack);
-        InterpretResult::Ok
+    }
+
+    fn call(&mut self, function: Rc<Function>, arg_count: usize) -> Result<(), String> {
+        if arg_count != function.arity() {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                function.arity(),
+                arg_count
+            ));
+        }
+        if self.frames.len() >= self.limits.max_frames || self.stack.len() > self.limits.max_stack {
+            return Err(self.stack_overflow());
+        }
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            base: self.stack.len() - arg_count - 1,
+            import: None,
+        });
+        Ok(())
+    }
+
+    fn stack_overflow(&self) -> String {
+        format!("Stack overflow ({} frames).", self.frames.len())
+    }
+
+    fn flush_output(&mut self) {
+        if let Err(error) = self.output.flush() {
+            eprintln!("Can't flush the output: {error}.");
+        }
+    }
+
+    // Stops the script, the error is reported by the caller
+    fn runtime_error(&mut self, message: String) -> RuntimeError {
+        // What the script printed so far comes before the error
+        self.flush_output();
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let line = frame.function.chunk().get_line(frame.ip.saturating_sub(1)) + 1;
+                if let Some(id) = frame.import {
+                    format!(
+                        "[line {line}] in module \"{}\"",
+                        self.modules[id].value.name
+                    )
+                } else if frame.function.name() == SCRIPT_NAME {
+                    format!("[line {line}] in script")
+                } else {
+                    format!("[line {line}] in {}()", frame.function.name())
+                }
+            })
+            .collect();
+        self.stack.clear();
+        self.frames.clear();
+        self.forget_partial_imports();
+        RuntimeError { message, trace }
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
     }
 
     fn pop_value(&mut self) -> Value {
@@ -59,28 +778,42 @@ impl VM {
             .expect("Expected a constant in the value stack")
     }
 
-    fn binary_op(&mut self, instruction: OpCode) -> Value {
+    fn binary_op(&mut self, instruction: OpCode) -> Result<(), String> {
         // This function is only called with binary operators:
-        // [Add, Sub, Mul, Div, Mod]
+        // [Add, Sub, Mul, Div, IntDiv, Mod, Pow, Greater, Less]
         use OpCode::*;
 
-        let Value::Number(rhs) = self.pop_value();
-        let Value::Number(lhs) = self.pop_value();
-        let result = match instruction {
-            Add => lhs + rhs,
-            Sub => lhs - rhs,
-            Mul => lhs + rhs,
-            Div => lhs / rhs,
-            Mod => lhs % rhs,
-            _ => unreachable!(),
+        let rhs = self.pop_value();
+        let lhs = self.pop_value();
+        let result = match (instruction, lhs, rhs) {
+            (Add, Value::Str(lhs), Value::Str(rhs)) => {
+                Value::Str(self.heap.alloc_string(&[&lhs, &rhs])?)
+            }
+            (_, lhs, rhs) => match Value::arithmetic(instruction, &lhs, &rhs) {
+                Some(result) => result?,
+                None if matches!(instruction, Add) => {
+                    return Err(String::from("Operands must be two numbers or two strings."))
+                }
+                None => return Err(String::from("Operands must be numbers.")),
+            },
         };
-        Value::Number(result)
+        self.stack.push(result);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::modules::MemoryResolver;
+    use crate::interpreter::output::MemorySink;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> Chunk {
+        let scanner = Scanner::new(source.to_string());
+        Compiler::new(&scanner).compile().unwrap()
+    }
 
     #[test]
     fn unary_negate() {
@@ -89,7 +822,7 @@ mod test {
 
         let num = 20.0;
 
-        let constant = chunk.add_constants(Value::Number(num));
+        let constant = chunk.add_constants(Value::Number(num)).unwrap();
         chunk.write_opcode(OpCode::Constant(constant), 0);
         chunk.write_opcode(OpCode::Negate, 0);
         vm.interpret(chunk);
@@ -102,16 +835,340 @@ mod test {
         let mut vm = VM::new();
         let mut chunk = Chunk::new();
 
+        let lhs = 0.1;
+        let rhs = 0.2;
+
+        let constant = chunk.add_constants(Value::Number(lhs)).unwrap();
+        chunk.write_opcode(OpCode::Constant(constant), 0);
+        let constant = chunk.add_constants(Value::Number(rhs)).unwrap();
+        chunk.write_opcode(OpCode::Constant(constant), 0);
+        chunk.write_opcode(OpCode::Add, 0);
+
+        vm.interpret(chunk);
+        let sum = vm.pop_value();
+        assert_eq!(sum, Value::Number(lhs + rhs));
+        // Equality is exact
+        assert_ne!(sum, Value::Number(0.3));
+    }
+
+    #[test]
+    fn binary_mul() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+
         let lhs = 10.0;
         let rhs = 20.0;
 
-        let constant = chunk.add_constants(Value::Number(lhs));
+        let constant = chunk.add_constants(Value::Number(lhs)).unwrap();
         chunk.write_opcode(OpCode::Constant(constant), 0);
-        let constant = chunk.add_constants(Value::Number(rhs));
+        let constant = chunk.add_constants(Value::Number(rhs)).unwrap();
         chunk.write_opcode(OpCode::Constant(constant), 0);
-        chunk.write_opcode(OpCode::Add, 0);
+        chunk.write_opcode(OpCode::Mul, 0);
 
         vm.interpret(chunk);
-        assert_eq!(vm.pop_value(), Value::Number(lhs + rhs));
+        assert_eq!(vm.pop_value(), Value::Number(lhs * rhs));
+    }
+
+    #[test]
+    fn numeric_equality() {
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+
+        let chunk = compile(
+            "let nan = 0 / 0.0;
+            print nan == nan; print nan != nan; print nan < 1; print nan > 1;
+            print 0.000000001 == 0; print 0.0 == -0.0; print 1 == 1.0;
+            print 9007199254740993 == 9007199254740992.0;
+            print 9007199254740993 > 9007199254740992.0;
+            print approx_eq(0.000000001, 0, 0.00000001);",
+        );
+        assert!(matches!(vm.interpret(chunk), InterpretResult::Ok));
+        assert_eq!(
+            output.contents(),
+            "false\ntrue\nfalse\nfalse\nfalse\ntrue\ntrue\nfalse\ntrue\ntrue\n"
+        );
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+
+        // Past 2^53 a float can't hold every integer
+        let chunk = compile("let i = 9007199254740992; i = i + 1; print i; print i * 1.0;");
+        assert!(matches!(vm.interpret(chunk), InterpretResult::Ok));
+        assert_eq!(output.contents(), "9007199254740993\n9007199254740992\n");
+
+        vm.set_output(Box::new(MemorySink::default()));
+        let chunk = compile("let i = -9223372036854775807 - 1; print -i;");
+        assert!(matches!(
+            vm.interpret(chunk),
+            InterpretResult::RuntimeError(_)
+        ));
+    }
+
+    #[test]
+    fn print_to_memory() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+
+        let constant = chunk.add_constants(Value::Number(1.5)).unwrap();
+        chunk.write_opcode(OpCode::Constant(constant), 0);
+        chunk.write_opcode(OpCode::Print, 0);
+        chunk.write_opcode(OpCode::Null, 0);
+        chunk.write_opcode(OpCode::Return, 0);
+
+        vm.interpret(chunk);
+        assert_eq!(output.contents(), "1.5\n");
+    }
+
+    #[test]
+    fn call_depth_limit() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(MemorySink::default()));
+        vm.set_limits(Limits {
+            max_frames: 10,
+            ..Limits::default()
+        });
+
+        let chunk = compile("fn f(n) { if (n > 0) f(n - 1); } f(8);");
+        assert!(matches!(vm.interpret(chunk), InterpretResult::Ok));
+
+        let chunk = compile("fn f(n) { if (n > 0) f(n - 1); } f(9);");
+        assert!(matches!(
+            vm.interpret(chunk),
+            InterpretResult::RuntimeError(_)
+        ));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        assert_eq!((vm.frame_depth(), vm.current_line()), (0, None));
+    }
+
+    #[test]
+    fn stack_size_limit() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(MemorySink::default()));
+        vm.set_limits(Limits {
+            max_stack: 100,
+            ..Limits::default()
+        });
+
+        let chunk = compile("fn f(a, b, c, d, e, f, g, h, i) { f(a, b, c, d, e, f, g, h, i); }");
+        assert!(matches!(vm.interpret(chunk), InterpretResult::Ok));
+        let chunk = compile("f(1, 2, 3, 4, 5, 6, 7, 8, 9);");
+        assert!(matches!(
+            vm.interpret(chunk),
+            InterpretResult::RuntimeError(_)
+        ));
+
+        // Values pushed without any call
+        let nested = (0..100).fold(String::from("0"), |expr, n| format!("{n} + ({expr})"));
+        let InterpretResult::RuntimeError(error) =
+            vm.interpret(compile(&format!("print {nested};")))
+        else {
+            panic!("Expected a stack overflow");
+        };
+        assert_eq!(error.message, "Stack overflow (1 frames).");
+    }
+
+    #[test]
+    fn out_of_fuel_and_resume() {
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_fuel(25);
+
+        let chunk = compile("let i = 0; while (i < 5) { print i; i = i + 1; }");
+        assert!(matches!(vm.interpret(chunk), InterpretResult::OutOfFuel));
+        assert_eq!(output.contents(), "0\n1\n");
+        assert_eq!(vm.fuel(), Some(0));
+
+        vm.refuel(1000);
+        assert!(matches!(vm.resume(), InterpretResult::Ok));
+        assert_eq!(output.contents(), "0\n1\n2\n3\n4\n");
+    }
+
+    #[test]
+    fn fuel_costs_per_instruction() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(MemorySink::default()));
+        vm.set_fuel(1000);
+        vm.set_fuel_costs(|instruction| match instruction {
+            OpCode::Call(_) => 1000,
+            _ => 1,
+        });
+
+        let chunk = compile("fn f() {} print 1; f();");
+        assert!(matches!(vm.interpret(chunk), InterpretResult::OutOfFuel));
+        assert!(matches!(
+            vm.frame().function.chunk().get_instruction(vm.frame().ip),
+            OpCode::Call(0)
+        ));
+    }
+
+    #[test]
+    fn heap_limit() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(MemorySink::default()));
+        vm.set_limits(Limits {
+            max_heap: Some(4096),
+            ..Limits::default()
+        });
+
+        // Only the last string is alive, collections keep the heap small
+        let chunk = compile(
+            "let i = 0; let s = \"\"; while (i < 1000) { s = \"abc\" + \"def\"; i = i + 1; }",
+        );
+        assert!(matches!(vm.interpret(chunk), InterpretResult::Ok));
+        assert!(vm.heap_stats().peak <= 4096);
+        assert!(vm.heap_stats().collections > 0);
+
+        let chunk = compile("let s = \"ab\"; while (true) { s = s + s; }");
+        assert!(matches!(
+            vm.interpret(chunk),
+            InterpretResult::RuntimeError(_)
+        ));
+        assert!(vm.heap_stats().peak <= 4096);
+    }
+
+    #[test]
+    fn step_through_a_script() {
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+
+        let chunk = compile("print 1;\nprint 2;");
+        assert!(matches!(vm.load(chunk), ExecutionState::Running));
+        assert!(matches!(vm.step(), ExecutionState::Running));
+        assert!(matches!(vm.step(), ExecutionState::Running));
+        assert_eq!(output.contents(), "1\n");
+        assert_eq!(vm.frame_depth(), 1);
+        assert_eq!(vm.current_line(), Some(1));
+        assert_eq!(
+            vm.current_line(),
+            vm.current_frame().map(|frame| frame.line)
+        );
+
+        assert!(matches!(vm.run_for(2), ExecutionState::Running));
+        assert_eq!(output.contents(), "1\n2\n");
+        assert!(matches!(
+            vm.run_for(100),
+            ExecutionState::Finished(Value::Null)
+        ));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn run_for_keeps_frames_between_calls() {
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+
+        let chunk = compile("fn f(n) { if (n > 0) { print n; f(n - 1); } } f(3);");
+        vm.load(chunk);
+        let mut slices = 0;
+        while let ExecutionState::Running = vm.run_for(3) {
+            slices += 1;
+        }
+        assert!(slices > 3);
+        assert_eq!(output.contents(), "3\n2\n1\n");
+    }
+
+    #[test]
+    fn step_reports_errors() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(MemorySink::default()));
+
+        vm.load(compile("-null;"));
+        assert!(matches!(vm.step(), ExecutionState::Running));
+        assert!(matches!(vm.step(), ExecutionState::Errored(_)));
+    }
+
+    fn vm_with_modules(modules: &[(&str, &str)]) -> (VM, MemorySink) {
+        let mut vm = VM::new();
+        let output = MemorySink::default();
+        vm.set_output(Box::new(output.clone()));
+        let mut resolver = MemoryResolver::default();
+        for (path, source) in modules {
+            resolver.add(path, source);
+        }
+        vm.set_resolver(Box::new(resolver));
+        (vm, output)
+    }
+
+    #[test]
+    fn import_modules() {
+        let (mut vm, output) = vm_with_modules(&[(
+            "math",
+            "print \"loading\"; let two = 2; fn double(n) { return n * two; }",
+        )]);
+        let chunk = compile(
+            "let two = 3;
+import \"math\" as math;
+from \"math\" import double;
+print math.double(4);
+print double(5);
+print math.two + two;
+print math;",
+        );
+        assert!(matches!(vm.interpret(chunk), InterpretResult::Ok));
+        assert_eq!(output.contents(), "loading\n8\n10\n5\n<module \"math\">\n");
+    }
+
+    #[test]
+    fn module_errors() {
+        let (mut vm, _) = vm_with_modules(&[
+            ("a", "import \"b\" as b;"),
+            ("b", "import \"a\" as a;"),
+            ("broken", "let = 1;"),
+            ("empty", ""),
+        ]);
+        for (source, message) in [
+            (
+                "import \"a\" as a;",
+                "Circular import: \"a\" -> \"b\" -> \"a\".",
+            ),
+            ("import \"missing\" as m;", "Can't find module \"missing\"."),
+            (
+                "import \"broken\" as m;",
+                "Can't compile module \"broken\":\n[line 1] Error at '=': Expect variable name.",
+            ),
+            (
+                "import \"empty\" as m; print m.x;",
+                "Undefined property 'x' of module \"empty\".",
+            ),
+            ("let x = 1; print x.y;", "Only modules have properties."),
+        ] {
+            let InterpretResult::RuntimeError(error) = vm.interpret(compile(source)) else {
+                panic!("Expected a runtime error for {source}");
+            };
+            assert_eq!(error.message, message);
+        }
+    }
+
+    #[test]
+    fn failed_imports_are_forgotten() {
+        let (mut vm, output) = vm_with_modules(&[
+            ("half", "import \"whole\" as w; print \"half\"; -null;"),
+            ("whole", "let x = 1;"),
+        ]);
+        for _ in 0..2 {
+            let chunk = compile("import \"half\" as h;");
+            let InterpretResult::RuntimeError(error) = vm.interpret(chunk) else {
+                panic!("Expected a runtime error");
+            };
+            assert_eq!(
+                error.trace,
+                ["[line 1] in module \"half\"", "[line 1] in script"]
+            );
+            // Neither the failed module nor the ones it imported are kept
+            assert_eq!(vm.modules.len(), 1);
+            assert_eq!(vm.module_keys.len(), 1);
+        }
+        assert_eq!(output.contents(), "half\nhalf\n");
     }
 }
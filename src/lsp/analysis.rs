@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value as Json};
+
+use crate::compiler::ast::{
+    walk_expr, walk_program, walk_stmt, Expr, ExprKind, Identifier, ImportKind, Program, Stmt,
+    StmtKind, Visitor,
+};
+use crate::compiler::lint::{self, LintConfig};
+use crate::compiler::parser::Parser;
+use crate::scanner::token::{Token, TokenType};
+
+/// Keywords offered as completions, the reserved words the language doesn't
+/// use yet are left out.
+pub const KEYWORDS: &[&str] = &[
+    "and", "as", "else", "false", "fn", "from", "if", "import", "let", "null", "or", "print",
+    "return", "true", "while",
+];
+
+/// Types of the semantic tokens, in the order of the legend sent to the
+/// client.
+pub const TOKEN_TYPES: &[&str] = &[
+    "keyword",
+    "variable",
+    "function",
+    "parameter",
+    "string",
+    "number",
+    "operator",
+    "comment",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SymbolKind {
+    Variable,
+    Function,
+    Parameter,
+}
+
+impl SymbolKind {
+    // Index in TOKEN_TYPES
+    fn token_type(self) -> u32 {
+        match self {
+            SymbolKind::Variable => 1,
+            SymbolKind::Function => 2,
+            SymbolKind::Parameter => 3,
+        }
+    }
+
+    // LSP CompletionItemKind
+    pub fn completion_kind(self) -> u32 {
+        match self {
+            SymbolKind::Variable | SymbolKind::Parameter => 6,
+            SymbolKind::Function => 3,
+        }
+    }
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    // Declaration shown on hover, like `fn add(a, b)`
+    pub detail: String,
+    // Text of the `///` comments before the declaration
+    pub doc: Option<String>,
+    // Index of the token declaring the name
+    pub declaration: usize,
+    // Indices of the tokens naming the symbol, declarations included
+    pub occurrences: Vec<usize>,
+}
+
+/// Converts byte offsets to LSP positions, 0-based lines and UTF-16 columns,
+/// and back.
+pub struct LineIndex<'a> {
+    source: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { source, starts }
+    }
+
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let character = self.source[self.starts[line]..offset]
+            .encode_utf16()
+            .count();
+        (line, character)
+    }
+
+    pub fn offset(&self, line: usize, character: usize) -> usize {
+        let Some(&start) = self.starts.get(line) else {
+            return self.source.len();
+        };
+        let end = self
+            .starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.source.len());
+        let mut units = 0;
+        for (index, c) in self.source[start..end].char_indices() {
+            if units >= character {
+                return start + index;
+            }
+            units += c.len_utf16();
+        }
+        end
+    }
+}
+
+// Symbols declared by a statement, with their hover text and doc comments
+fn declarations<'s, 'a>(
+    stmt: &'s Stmt<'a>,
+) -> Vec<(SymbolKind, Identifier<'a>, String, &'s [&'a str])> {
+    match &stmt.kind {
+        StmtKind::Let { name, doc, .. } => {
+            vec![(
+                SymbolKind::Variable,
+                *name,
+                format!("let {}", name.name),
+                doc,
+            )]
+        }
+        StmtKind::Fn(function) => {
+            let params = function.params.iter().map(|p| p.name).collect::<Vec<_>>();
+            let detail = format!("fn {}({})", function.name.name, params.join(", "));
+            vec![(SymbolKind::Function, function.name, detail, &function.doc)]
+        }
+        StmtKind::Import {
+            path,
+            kind: ImportKind::Module(name),
+            ..
+        } => {
+            let detail = format!("import \"{path}\" as {}", name.name);
+            vec![(SymbolKind::Variable, *name, detail, &[])]
+        }
+        StmtKind::Import {
+            path,
+            kind: ImportKind::Names(names),
+            ..
+        } => names
+            .iter()
+            .map(|name| {
+                let detail = format!("from \"{path}\" import {}", name.name);
+                (SymbolKind::Variable, *name, detail, &[][..])
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Resolves the identifiers of the tree like the compiler does: locals are
+// visible from the end of their declaration to the end of their block,
+// globals from anywhere since they are looked up by name when the script
+// runs.
+struct Resolver<'a> {
+    symbols: Vec<Symbol>,
+    resolved: HashMap<usize, usize>,
+    globals: HashMap<&'a str, usize>,
+    // Names declared in each block, innermost last
+    scopes: Vec<Vec<(&'a str, usize)>>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new() -> Self {
+        Self {
+            symbols: Vec::new(),
+            resolved: HashMap::new(),
+            globals: HashMap::new(),
+            scopes: Vec::new(),
+        }
+    }
+
+    fn add_symbol(
+        &mut self,
+        kind: SymbolKind,
+        name: Identifier,
+        detail: String,
+        doc: &[&str],
+    ) -> usize {
+        self.symbols.push(Symbol {
+            name: name.name.to_string(),
+            kind,
+            detail,
+            doc: (!doc.is_empty()).then(|| doc.join("\n")),
+            declaration: name.token,
+            occurrences: Vec::new(),
+        });
+        self.symbols.len() - 1
+    }
+
+    fn occurrence(&mut self, name: Identifier, symbol: usize) {
+        self.symbols[symbol].occurrences.push(name.token);
+        self.resolved.insert(name.token, symbol);
+    }
+
+    // A global declared twice is the same symbol, the first declaration
+    // being its definition
+    fn declare(&mut self, stmt: &Stmt<'a>) {
+        for (kind, name, detail, doc) in declarations(stmt) {
+            let global = self
+                .globals
+                .get(name.name)
+                .filter(|_| self.scopes.is_empty());
+            let symbol = match global {
+                Some(&symbol) => symbol,
+                None => self.add_symbol(kind, name, detail, doc),
+            };
+            self.occurrence(name, symbol);
+            if let Some(scope) = self.scopes.last_mut() {
+                scope.push((name.name, symbol));
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(local, _)| *local == name)
+            .map(|(_, symbol)| *symbol)
+            .or_else(|| self.globals.get(name).copied())
+    }
+
+    fn reference(&mut self, name: Identifier) {
+        if let Some(symbol) = self.lookup(name.name) {
+            self.occurrence(name, symbol);
+        }
+    }
+
+    fn resolve(mut self, program: &Program<'a>) -> (Vec<Symbol>, HashMap<usize, usize>) {
+        for stmt in &program.statements {
+            for (kind, name, detail, doc) in declarations(stmt) {
+                if !self.globals.contains_key(name.name) {
+                    let symbol = self.add_symbol(kind, name, detail, doc);
+                    self.globals.insert(name.name, symbol);
+                }
+            }
+        }
+        walk_program(&mut self, program);
+        for symbol in &mut self.symbols {
+            symbol.occurrences.sort_unstable();
+        }
+        (self.symbols, self.resolved)
+    }
+}
+
+impl<'a> Visitor<'a> for Resolver<'a> {
+    fn visit_stmt(&mut self, stmt: &Stmt<'a>) {
+        match &stmt.kind {
+            // The name is visible in the body, for recursive calls, and the
+            // parameters share the scope of the body
+            StmtKind::Fn(function) => {
+                self.declare(stmt);
+                self.scopes.push(Vec::new());
+                for param in &function.params {
+                    let detail = format!("(parameter) {}", param.name);
+                    let symbol = self.add_symbol(SymbolKind::Parameter, *param, detail, &[]);
+                    self.occurrence(*param, symbol);
+                    self.scopes.last_mut().unwrap().push((param.name, symbol));
+                }
+                walk_stmt(self, stmt);
+                self.scopes.pop();
+            }
+            StmtKind::Block(_) => {
+                self.scopes.push(Vec::new());
+                walk_stmt(self, stmt);
+                self.scopes.pop();
+            }
+            // Locals become visible after their initializer
+            _ => {
+                walk_stmt(self, stmt);
+                self.declare(stmt);
+            }
+        }
+    }
+
+    // Properties are looked up on modules, not in scopes
+    fn visit_expr(&mut self, expr: &Expr<'a>) {
+        match &expr.kind {
+            ExprKind::Variable(name) => self.reference(*name),
+            ExprKind::Assign { name, .. } => {
+                self.reference(*name);
+                walk_expr(self, expr);
+            }
+            _ => walk_expr(self, expr),
+        }
+    }
+}
+
+/// What the server knows about a document, computed again on every request.
+pub struct Analysis<'a> {
+    source: &'a str,
+    // Tokens of `Scanner::tokenize`, the indices of the symbols refer to them
+    tokens: Vec<Token<'a>>,
+    // Same with the comments, for the lint
+    scanned: Vec<Token<'a>>,
+    lines: LineIndex<'a>,
+    symbols: Vec<Symbol>,
+    // Symbol of the identifiers that could be resolved, by token index
+    resolved: HashMap<usize, usize>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Analysis of `source` from its tokens with the comments, see
+    /// `Scanner::tokenize_with_comments`.
+    pub fn new(source: &'a str, scanned: Vec<Token<'a>>) -> Self {
+        let tokens = scanned
+            .iter()
+            .copied()
+            .filter(|token| token.ty() != TokenType::Comment)
+            .collect::<Vec<_>>();
+        // The statements with syntax errors are left out, the names they
+        // declare or use aren't resolved
+        let (program, _) = Parser::new(&tokens).parse();
+        let (symbols, resolved) = Resolver::new().resolve(&program);
+        Self {
+            source,
+            lines: LineIndex::new(source),
+            tokens,
+            scanned,
+            symbols,
+            resolved,
+        }
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // Byte range of a token, with the quotes of strings
+    fn span(&self, index: usize) -> (usize, usize) {
+        let token = self.tokens[index];
+        let start = token.offset(self.source);
+        let end = start + token.lexeme().len();
+        match token.ty() {
+            TokenType::CroxStr => (start - 1, end + 1),
+            TokenType::Error("Unterminated String") => (start - 1, end),
+            _ => (start, end),
+        }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let (line, character) = self.lines.position(offset);
+        json!({ "line": line, "character": character })
+    }
+
+    pub fn range(&self, index: usize) -> Json {
+        let (start, end) = self.span(index);
+        json!({ "start": self.position(start), "end": self.position(end) })
+    }
+
+    /// Index of the token under the cursor, identifiers win over the token
+    /// they touch.
+    pub fn token_at(&self, line: usize, character: usize) -> Option<usize> {
+        let offset = self.lines.offset(line, character);
+        let touched = (0..self.tokens.len())
+            .filter(|&index| self.tokens[index].ty() != TokenType::Eof)
+            .filter(|&index| {
+                let (start, end) = self.span(index);
+                start <= offset && offset <= end
+            })
+            .collect::<Vec<_>>();
+        touched
+            .iter()
+            .find(|&&index| self.tokens[index].ty() == TokenType::Identifier)
+            .or(touched.first())
+            .copied()
+    }
+
+    pub fn symbol_at(&self, line: usize, character: usize) -> Option<&Symbol> {
+        let token = self.token_at(line, character)?;
+        let symbol = self.resolved.get(&token)?;
+        Some(&self.symbols[*symbol])
+    }
+
+    /// Compile errors and lint warnings as LSP diagnostics.
+    pub fn diagnostics(&self, config: &LintConfig) -> Vec<Json> {
+        lint::lint_tokens(self.scanned.iter().copied(), config)
+            .iter()
+            .map(|diagnostic| {
+                let mut json = json!({
+                    "range": self.range(diagnostic.token()),
+                    "severity": 1,
+                    "source": "crox",
+                    "message": diagnostic.message(),
+                });
+                if let Some(rule) = diagnostic.rule() {
+                    json["severity"] = json!(2);
+                    json["code"] = json!(rule.name());
+                }
+                json
+            })
+            .collect()
+    }
+
+    fn token_type(&self, index: usize) -> Option<u32> {
+        use TokenType::*;
+        match self.tokens[index].ty() {
+            And | As | Class | Else | False | For | Fn | From | If | Import | Let | Null | Or
+            | Print | Return | Super | This | True | While => Some(0),
+            Identifier => Some(
+                self.resolved
+                    .get(&index)
+                    .map_or(1, |symbol| self.symbols[*symbol].kind.token_type()),
+            ),
+            CroxStr => Some(4),
+            Number(_) | Integer(_) => Some(5),
+            Bang | Carrot | Equal | Greater | Less | Minus | Percent | Plus | Star | Slash
+            | LessEq | GreaterEq | DoubleEq | BangEq | TildeSlash => Some(6),
+            DocComment => Some(7),
+            _ => None,
+        }
+    }
+
+    /// Semantic tokens in the relative encoding of LSP, tokens spanning
+    /// several lines are split at line ends.
+    pub fn semantic_tokens(&self) -> Vec<u32> {
+        let mut data = Vec::new();
+        let (mut last_line, mut last_start) = (0, 0);
+        for index in 0..self.tokens.len() {
+            let Some(token_type) = self.token_type(index) else {
+                continue;
+            };
+            let (start, end) = self.span(index);
+            let mut segment_start = start;
+            for segment in self.source[start..end].split_inclusive('\n') {
+                let text = segment.trim_end_matches(['\n', '\r']);
+                let (line, character) = self.lines.position(segment_start);
+                segment_start += segment.len();
+                if text.is_empty() {
+                    continue;
+                }
+                let delta_start = if line == last_line {
+                    character - last_start
+                } else {
+                    character
+                };
+                data.extend([
+                    (line - last_line) as u32,
+                    delta_start as u32,
+                    text.encode_utf16().count() as u32,
+                    token_type,
+                    0,
+                ]);
+                (last_line, last_start) = (line, character);
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    #[test]
+    fn line_index_counts_utf16() {
+        let source = "let é = \"😀\";\nprint é;";
+        let lines = LineIndex::new(source);
+
+        assert_eq!(lines.position(0), (0, 0));
+        assert_eq!(lines.position(source.find(';').unwrap()), (0, 12));
+        assert_eq!(lines.position(source.find("print").unwrap()), (1, 0));
+        assert_eq!(lines.offset(0, 12), source.find(';').unwrap());
+        assert_eq!(lines.offset(1, 6), source.rfind('é').unwrap());
+        assert_eq!(lines.offset(5, 0), source.len());
+    }
+
+    #[test]
+    fn resolves_scopes_like_the_compiler() {
+        let scanner = Scanner::new(String::from(
+            "let a = 1;\nfn f(a) { let b = a; { let a = b; print a; } return a + c; }\nlet c = a;",
+        ));
+        let analysis = Analysis::new(scanner.source(), scanner.tokenize_with_comments());
+        let occurrences = |detail: &str| {
+            let symbol = analysis
+                .symbols()
+                .iter()
+                .find(|symbol| symbol.detail == detail)
+                .unwrap();
+            symbol
+                .occurrences
+                .iter()
+                .map(|&index| analysis.lines.position(analysis.span(index).0))
+                .collect::<Vec<_>>()
+        };
+
+        // The global a and the one of the last line
+        assert_eq!(occurrences("let a"), vec![(0, 4), (2, 8)]);
+        assert_eq!(occurrences("(parameter) a"), vec![(1, 5), (1, 18), (1, 52)]);
+        assert_eq!(occurrences("fn f(a)"), vec![(1, 3)]);
+        // Globals can be used before their declaration
+        assert_eq!(occurrences("let c"), vec![(1, 56), (2, 4)]);
+        let inner = analysis
+            .symbols()
+            .iter()
+            .filter(|symbol| symbol.detail == "let a")
+            .nth(1)
+            .unwrap();
+        assert_eq!(inner.occurrences.len(), 2);
+    }
+
+    #[test]
+    fn resolves_blocks_and_imports() {
+        let scanner = Scanner::new(String::from(
+            "{ let i = 0; while (i < 2) i = i + 1; }\nprint i;\n\
+             fn f() { from \"m\" import x; import \"m\" as m; return m.x + x; }\nprint x;",
+        ));
+        let analysis = Analysis::new(scanner.source(), scanner.tokenize_with_comments());
+        let symbol = |detail: &str| {
+            analysis
+                .symbols()
+                .iter()
+                .find(|symbol| symbol.detail == detail)
+                .unwrap()
+        };
+        let lines = |symbol: &Symbol| {
+            symbol
+                .occurrences
+                .iter()
+                .map(|&index| analysis.lines.position(analysis.span(index).0))
+                .collect::<Vec<_>>()
+        };
+
+        // The local of the block
+        assert_eq!(lines(symbol("let i")).len(), 4);
+        assert!(analysis.symbol_at(1, 6).is_none());
+        // The names imported in a function are its locals, and the property
+        // of the module isn't the local x
+        assert_eq!(lines(symbol("from \"m\" import x")), vec![(2, 25), (2, 58)]);
+        assert_eq!(lines(symbol("import \"m\" as m")), vec![(2, 42), (2, 52)]);
+        assert!(analysis.symbol_at(3, 6).is_none());
+    }
+
+    #[test]
+    fn doc_comments_of_symbols() {
+        let scanner = Scanner::new(String::from(
+            "/// Adds a and b.\n///\n/// Ints or floats.\nfn add(a, b) { return a + b; }\nlet x = 1;",
+        ));
+        let analysis = Analysis::new(scanner.source(), scanner.tokenize_with_comments());
+        let doc = |name: &str| {
+            let symbol = analysis.symbols().iter().find(|s| s.name == name).unwrap();
+            symbol.doc.clone()
+        };
+
+        assert_eq!(
+            doc("add").as_deref(),
+            Some("Adds a and b.\n\nInts or floats.")
+        );
+        assert_eq!(doc("a"), None);
+        assert_eq!(doc("x"), None);
+    }
+
+    #[test]
+    fn semantic_tokens_are_relative() {
+        let scanner = Scanner::new(String::from("fn f(x) {\n  print \"a\nb\" + x;\n}"));
+        let analysis = Analysis::new(scanner.source(), scanner.tokenize_with_comments());
+
+        assert_eq!(
+            analysis.semantic_tokens(),
+            vec![
+                0, 0, 2, 0, 0, // fn
+                0, 3, 1, 2, 0, // f
+                0, 2, 1, 3, 0, // x
+                1, 2, 5, 0, 0, // print
+                0, 6, 2, 4, 0, // "a
+                1, 0, 2, 4, 0, // b"
+                0, 3, 1, 6, 0, // +
+                0, 2, 1, 3, 0, // x
+            ]
+        );
+    }
+
+    #[test]
+    fn lint_warnings_are_diagnostics() {
+        let scanner = Scanner::new(String::from("fn f() {\n  let unused = 1;\n}\nprint f(;"));
+        let analysis = Analysis::new(scanner.source(), scanner.tokenize_with_comments());
+
+        assert_eq!(
+            analysis.diagnostics(&LintConfig::default()),
+            vec![
+                json!({ "range": analysis.range(6), "severity": 2, "source": "crox",
+                        "message": "Local variable 'unused' is never used.",
+                        "code": "unused-local" }),
+                json!({ "range": analysis.range(14), "severity": 1, "source": "crox",
+                        "message": "Expect expression." }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,4 @@
+pub mod analysis;
+pub mod server;
+
+pub use server::LanguageServer;
@@ -0,0 +1,521 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use serde_json::{json, Value as Json};
+
+use super::analysis::{Analysis, LineIndex, KEYWORDS, TOKEN_TYPES};
+use crate::compiler::lint::LintConfig;
+use crate::framing::{read_message, write_message};
+use crate::scanner::scanning::Edit;
+use crate::scanner::token::{Token, TokenType};
+use crate::scanner::Scanner;
+
+// JSON-RPC error code of the requests the server doesn't implement
+const METHOD_NOT_FOUND: i64 = -32601;
+// LSP CompletionItemKind of the keywords
+const KEYWORD_COMPLETION: u32 = 14;
+// LSP MessageType of the errors shown to the user
+const ERROR_MESSAGE: u32 = 1;
+
+/// Language Server Protocol server over stdio. Documents are synchronized
+/// incrementally, their tokens are kept between edits and the rest is
+/// analyzed again for each request.
+pub struct LanguageServer<W: Write> {
+    out: W,
+    // Open documents by URI
+    documents: HashMap<String, Document>,
+    // Last crox.toml error shown, not shown again on every edit
+    config_error: Option<String>,
+}
+
+// An open document, only the text around its edits is scanned again
+struct Document {
+    scanner: Scanner,
+    // Type, byte range and line of the tokens with the comments, tokens
+    // can't borrow the scanner next to them
+    tokens: Vec<(TokenType, Range<usize>, usize)>,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let scanner = Scanner::new(text);
+        let tokens = spans(&scanner, &scanner.tokenize_with_comments());
+        Self { scanner, tokens }
+    }
+
+    fn tokens(&self) -> Vec<Token<'_>> {
+        let source = self.scanner.source();
+        self.tokens
+            .iter()
+            .map(|(ty, range, line)| Token::new(*ty, &source[range.clone()], *line))
+            .collect()
+    }
+
+    // Applies a `TextDocumentContentChangeEvent`, which replaces the whole
+    // text when it has no range
+    fn change(&mut self, change: &Json) {
+        let text = change["text"].as_str().unwrap_or_default();
+        if change["range"].is_null() {
+            *self = Self::new(text.to_string());
+            return;
+        }
+        let lines = LineIndex::new(self.scanner.source());
+        let offset = |position: &Json| {
+            let line = position["line"].as_u64().unwrap_or_default() as usize;
+            let character = position["character"].as_u64().unwrap_or_default() as usize;
+            lines.offset(line, character)
+        };
+        let end = offset(&change["range"]["end"]);
+        let start = offset(&change["range"]["start"]).min(end);
+
+        let mut source = self.scanner.source().to_string();
+        source.replace_range(start..end, text);
+        let scanner = Scanner::new(source);
+        let edit = Edit {
+            range: start..end,
+            len: text.len(),
+        };
+        let tokens = scanner.relex(&self.scanner, &self.tokens(), &edit, true);
+        self.tokens = spans(&scanner, &tokens);
+        self.scanner = scanner;
+    }
+}
+
+fn spans(scanner: &Scanner, tokens: &[Token]) -> Vec<(TokenType, Range<usize>, usize)> {
+    tokens
+        .iter()
+        .map(|token| {
+            let start = token.offset(scanner.source());
+            (
+                token.ty(),
+                start..start + token.lexeme().len(),
+                token.line(),
+            )
+        })
+        .collect()
+}
+
+impl<W: Write> LanguageServer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            documents: HashMap::new(),
+            config_error: None,
+        }
+    }
+
+    /// Handles the messages read from `input` until `exit` or the end of the
+    /// input.
+    pub fn run<R: BufRead>(&mut self, mut input: R) -> io::Result<()> {
+        while let Some(message) = read_message(&mut input)? {
+            let method = message["method"].as_str().unwrap_or_default();
+            let params = &message["params"];
+            if method == "exit" {
+                break;
+            }
+            match message.get("id") {
+                Some(id) => {
+                    let result = self.request(method, params);
+                    self.respond(id, result)?;
+                }
+                None => self.notification(method, params)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, id: &Json, result: Result<Json, (i64, String)>) -> io::Result<()> {
+        let mut response = json!({ "jsonrpc": "2.0", "id": id });
+        match result {
+            Ok(result) => response["result"] = result,
+            Err((code, message)) => {
+                response["error"] = json!({ "code": code, "message": message });
+            }
+        }
+        write_message(&mut self.out, &response)
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        let notification = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        write_message(&mut self.out, &notification)
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents
+                    .insert(uri.clone(), Document::new(text.to_string()));
+                self.publish_diagnostics(&uri)
+            }
+            "textDocument/didChange" => {
+                if let Some(document) = self.documents.get_mut(&uri) {
+                    let changes = params["contentChanges"].as_array();
+                    for change in changes.into_iter().flatten() {
+                        document.change(change);
+                    }
+                }
+                self.publish_diagnostics(&uri)
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        // The rules come from the crox.toml of the file, the defaults apply
+        // while it is broken
+        let dir = uri
+            .strip_prefix("file://")
+            .and_then(|path| Path::new(path).parent());
+        let config = match dir.map(LintConfig::load).transpose() {
+            Ok(config) => {
+                self.config_error = None;
+                config.unwrap_or_default()
+            }
+            Err(error) => {
+                let message = error.to_string();
+                if self.config_error.as_ref() != Some(&message) {
+                    self.notify(
+                        "window/showMessage",
+                        json!({ "type": ERROR_MESSAGE, "message": message }),
+                    )?;
+                    self.config_error = Some(message);
+                }
+                LintConfig::default()
+            }
+        };
+        let diagnostics = self
+            .analyze(uri, |analysis| analysis.diagnostics(&config))
+            .unwrap_or_default();
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+
+    fn analyze<T>(&self, uri: &str, f: impl FnOnce(&Analysis) -> T) -> Option<T> {
+        let document = self.documents.get(uri)?;
+        let source = document.scanner.source();
+        Some(f(&Analysis::new(source, document.tokens())))
+    }
+
+    // Runs `f` with the analysis of the document and the position of a
+    // `TextDocumentPositionParams`
+    fn at_position<T>(
+        &self,
+        params: &Json,
+        f: impl FnOnce(&Analysis, usize, usize) -> Option<T>,
+    ) -> Option<T> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        self.analyze(uri, |analysis| f(analysis, line, character))?
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        let uri = params["textDocument"]["uri"].clone();
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 2,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "crox" },
+            }),
+            "shutdown" => Json::Null,
+            "textDocument/semanticTokens/full" => {
+                let data = self
+                    .analyze(uri.as_str().unwrap_or_default(), |analysis| {
+                        analysis.semantic_tokens()
+                    })
+                    .unwrap_or_default();
+                json!({ "data": data })
+            }
+            "textDocument/definition" => self
+                .at_position(params, |analysis, line, character| {
+                    let symbol = analysis.symbol_at(line, character)?;
+                    Some(json!({ "uri": uri, "range": analysis.range(symbol.declaration) }))
+                })
+                .unwrap_or(Json::Null),
+            "textDocument/references" => {
+                let declaration = params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(true);
+                let locations = self.at_position(params, |analysis, line, character| {
+                    let symbol = analysis.symbol_at(line, character)?;
+                    let locations = symbol
+                        .occurrences
+                        .iter()
+                        .filter(|&&token| declaration || token != symbol.declaration)
+                        .map(|&token| json!({ "uri": uri, "range": analysis.range(token) }))
+                        .collect::<Vec<_>>();
+                    Some(locations)
+                });
+                json!(locations.unwrap_or_default())
+            }
+            "textDocument/hover" => self
+                .at_position(params, |analysis, line, character| {
+                    let token = analysis.token_at(line, character)?;
+                    let symbol = analysis.symbol_at(line, character)?;
+                    let mut value = format!("```crox\n{}\n```", symbol.detail);
+                    if let Some(doc) = &symbol.doc {
+                        value.push_str("\n\n");
+                        value.push_str(doc);
+                    }
+                    Some(json!({
+                        "contents": {
+                            "kind": "markdown",
+                            "value": value,
+                        },
+                        "range": analysis.range(token),
+                    }))
+                })
+                .unwrap_or(Json::Null),
+            "textDocument/completion" => {
+                let mut items = KEYWORDS
+                    .iter()
+                    .map(|keyword| json!({ "label": keyword, "kind": KEYWORD_COMPLETION }))
+                    .collect::<Vec<_>>();
+                let symbols = self
+                    .analyze(uri.as_str().unwrap_or_default(), |analysis| {
+                        let mut seen = HashSet::new();
+                        analysis
+                            .symbols()
+                            .iter()
+                            .filter(|symbol| seen.insert(symbol.name.clone()))
+                            .map(|symbol| {
+                                json!({
+                                    "label": symbol.name,
+                                    "kind": symbol.kind.completion_kind(),
+                                    "detail": symbol.detail,
+                                })
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                items.extend(symbols);
+                json!(items)
+            }
+            method => return Err((METHOD_NOT_FOUND, format!("Unhandled method {method}."))),
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const URI: &str = "file:///tmp/main.crox";
+
+    // A session recorded from an editor: the client opens a document, asks
+    // about its symbols, then breaks it with an edit
+    fn recorded_session() -> Vec<Json> {
+        vec![
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                    "params": { "processId": null, "rootUri": null, "capabilities": {} } }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen",
+                    "params": { "textDocument": { "uri": URI, "languageId": "crox", "version": 1,
+                                "text": "fn add(a, b) {\n  return a + b;\n}\nlet total = add(1, 2);\nprint total;\n" } } }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/semanticTokens/full",
+                    "params": { "textDocument": { "uri": URI } } }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "textDocument/definition",
+                    "params": { "textDocument": { "uri": URI }, "position": { "line": 3, "character": 14 } } }),
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "textDocument/references",
+                    "params": { "textDocument": { "uri": URI }, "position": { "line": 1, "character": 9 },
+                                "context": { "includeDeclaration": true } } }),
+            json!({ "jsonrpc": "2.0", "id": 5, "method": "textDocument/hover",
+                    "params": { "textDocument": { "uri": URI }, "position": { "line": 4, "character": 8 } } }),
+            json!({ "jsonrpc": "2.0", "id": 6, "method": "textDocument/completion",
+                    "params": { "textDocument": { "uri": URI }, "position": { "line": 4, "character": 0 } } }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange",
+                    "params": { "textDocument": { "uri": URI, "version": 2 },
+                                "contentChanges": [{ "text": "let x = ;\nprint x" }] } }),
+            json!({ "jsonrpc": "2.0", "id": 7, "method": "textDocument/formatting",
+                    "params": { "textDocument": { "uri": URI } } }),
+            json!({ "jsonrpc": "2.0", "id": 8, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]
+    }
+
+    fn replay(messages: &[Json]) -> Vec<Json> {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut out = Vec::new();
+        LanguageServer::new(&mut out).run(&input[..]).unwrap();
+
+        let mut out = &out[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut out).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn range(line: u64, start: u64, end: u64) -> Json {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    #[test]
+    fn recorded_session_replay() {
+        let messages = replay(&recorded_session());
+        assert_eq!(messages.len(), 10);
+
+        let capabilities = &messages[0]["result"]["capabilities"];
+        assert_eq!(capabilities["textDocumentSync"], 2);
+        assert_eq!(
+            capabilities["semanticTokensProvider"]["legend"]["tokenTypes"][2],
+            "function"
+        );
+        assert_eq!(
+            capabilities["semanticTokensProvider"]["legend"]["tokenTypes"][4],
+            "string"
+        );
+
+        assert_eq!(
+            messages[1],
+            json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": URI, "diagnostics": [] } })
+        );
+
+        let tokens = messages[2]["result"]["data"].as_array().unwrap();
+        // fn, add, then a and b as parameters
+        assert_eq!(
+            tokens[..20],
+            [0, 0, 2, 0, 0, 0, 3, 3, 2, 0, 0, 4, 1, 3, 0, 0, 3, 1, 3, 0]
+        );
+
+        assert_eq!(
+            messages[3]["result"],
+            json!({ "uri": URI, "range": range(0, 3, 6) })
+        );
+        assert_eq!(
+            messages[4]["result"],
+            json!([
+                { "uri": URI, "range": range(0, 7, 8) },
+                { "uri": URI, "range": range(1, 9, 10) },
+            ])
+        );
+        assert_eq!(
+            messages[5]["result"]["contents"]["value"],
+            "```crox\nlet total\n```"
+        );
+        assert_eq!(messages[5]["result"]["range"], range(4, 6, 11));
+
+        let labels = messages[6]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(labels.contains(&"while"));
+        assert!(!labels.contains(&"class"));
+        assert!(labels.ends_with(&["add", "total", "a", "b"]));
+
+        assert_eq!(
+            messages[7]["params"]["diagnostics"],
+            json!([
+                { "range": range(0, 8, 9), "severity": 1, "source": "crox",
+                  "message": "Expect expression." },
+                { "range": range(1, 7, 7), "severity": 1, "source": "crox",
+                  "message": "Expect ';' after value." },
+            ])
+        );
+        assert_eq!(messages[8]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(messages[9]["result"], Json::Null);
+    }
+
+    #[test]
+    fn incremental_changes_scan_like_the_whole_text() {
+        let mut document = Document::new(String::from(
+            "fn add(a, b) {\n  return a + b;\n}\nlet é = \"x\";\n",
+        ));
+        let changes = [
+            (
+                json!({ "range": range(1, 14, 14), "text": " * 2" }),
+                "fn add(a, b) {\n  return a + b * 2;\n}\nlet é = \"x\";\n",
+            ),
+            (
+                json!({ "range": range(3, 4, 5), "text": "ü" }),
+                "fn add(a, b) {\n  return a + b * 2;\n}\nlet ü = \"x\";\n",
+            ),
+            (
+                json!({ "range": range(3, 9, 9), "text": "\n" }),
+                "fn add(a, b) {\n  return a + b * 2;\n}\nlet ü = \"\nx\";\n",
+            ),
+            (
+                json!({ "range": { "start": { "line": 0, "character": 0 },
+                                   "end": { "line": 1, "character": 2 } },
+                        "text": "/* " }),
+                "/* return a + b * 2;\n}\nlet ü = \"\nx\";\n",
+            ),
+            (json!({ "text": "print 1;" }), "print 1;"),
+        ];
+        for (change, source) in changes {
+            document.change(&change);
+            assert_eq!(document.scanner.source(), source);
+            assert_eq!(document.tokens, Document::new(source.to_string()).tokens);
+        }
+    }
+
+    #[test]
+    fn broken_config_is_shown_once() {
+        let dir = std::env::temp_dir().join(format!("crox-lsp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("crox.toml"), "[lint]\nunused = \"allow\"\n").unwrap();
+        let uri = format!("file://{}", dir.join("main.crox").display());
+        let messages = replay(&[
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen",
+                    "params": { "textDocument": { "uri": uri, "languageId": "crox", "version": 1,
+                                "text": "print 1;" } } }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange",
+                    "params": { "textDocument": { "uri": uri, "version": 2 },
+                                "contentChanges": [{ "text": "print 2;" }] } }),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let methods = messages
+            .iter()
+            .map(|message| message["method"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            [
+                "window/showMessage",
+                "textDocument/publishDiagnostics",
+                "textDocument/publishDiagnostics",
+            ]
+        );
+        assert_eq!(
+            messages[0]["params"],
+            json!({ "type": ERROR_MESSAGE,
+                    "message": "[line 2] Error in crox.toml: Unknown rule 'unused'." })
+        );
+    }
+}
@@ -1,39 +1,451 @@
 mod compiler;
+mod debugger;
+mod disassembler;
+mod doc;
+mod formatter;
+mod framing;
 mod interpreter;
-mod logging;
+mod lsp;
+mod natives;
 mod scanner;
 
 use std::env;
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
+use compiler::lint::{self, LintConfig};
+use compiler::optimizer::OptLevel;
+use compiler::parser::Parser;
+use compiler::{Compiler, Function};
+use debugger::{DapServer, Debugger};
+use disassembler::DumpFormat;
+use interpreter::tracing::{FilteredTracer, JsonLinesTracer, TextTracer, Tracer};
+use interpreter::virtual_machine::{InterpretResult, Limits, VM};
+use lsp::LanguageServer;
 use scanner::Scanner;
 
+const USAGE: &str = "Usage:
+           - script mode: crox [options] [file path]
+           - disassembler: crox disasm [--json] [file path]
+           - debugger: crox debug [options] [file path]
+           - debug adapter (DAP over stdio): crox dap
+           - language server (LSP over stdio): crox lsp
+           - formatter: crox fmt [--check] [file or directory paths]
+           - linter: crox lint [file or directory paths]
+           - documentation: crox doc [file or directory paths] -o [output directory]
+           - repl mode: crox
+
+Options:
+           --dump-tokens    print the tokens of the script
+           --dump-ast       print the syntax tree of the script
+           --dump-bytecode  print the bytecode of the script
+           --json           print the dumps as JSON instead of plain text
+           -O0              compile the code as written (default)
+           -O1              fold constants and optimize the bytecode
+           --trace          print every executed instruction with the stack
+           --trace-json     same as --trace, one JSON object per line
+           --trace-file     [path] write the trace to a file instead of stdout
+           --trace-function [name] only trace the instructions of a function
+           --trace-lines    [from-to] only trace the instructions of these lines
+           --max-stack      [n] maximum number of values on the stack
+           --max-frames     [n] maximum depth of nested function calls
+           --max-heap       [n] maximum number of bytes of the strings the script
+                            concatenates, other values are not counted
+           --fuel           [n] stop the script after n instructions";
+
+#[derive(Default)]
+struct Options {
+    dump_tokens: bool,
+    dump_ast: bool,
+    dump_bytecode: bool,
+    run: bool,
+    debug: bool,
+    json: bool,
+    trace: Option<TraceOptions>,
+    limits: Limits,
+    fuel: Option<u64>,
+    opt_level: OptLevel,
+}
+
+#[derive(Default)]
+struct TraceOptions {
+    json: bool,
+    file: Option<String>,
+    function: Option<String>,
+    lines: Option<(usize, usize)>,
+}
+
+impl TraceOptions {
+    fn tracer(&self) -> Result<Box<dyn Tracer>, Box<dyn std::error::Error>> {
+        let out: Box<dyn Write> = match &self.file {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        };
+        Ok(if self.json {
+            self.filter(JsonLinesTracer::new(out))
+        } else {
+            self.filter(TextTracer::new(out))
+        })
+    }
+
+    // Without filters the tracer sees every instruction, no need to wrap it
+    fn filter<T: Tracer + 'static>(&self, tracer: T) -> Box<dyn Tracer> {
+        if self.function.is_none() && self.lines.is_none() {
+            return Box::new(tracer);
+        }
+        let mut tracer = FilteredTracer::new(tracer);
+        if let Some(function) = &self.function {
+            tracer = tracer.function(function);
+        }
+        if let Some((from, to)) = self.lines {
+            tracer = tracer.lines(from..=to);
+        }
+        Box::new(tracer)
+    }
+}
+
+impl Options {
+    fn format(&self) -> DumpFormat {
+        if self.json {
+            DumpFormat::Json
+        } else {
+            DumpFormat::Text
+        }
+    }
+}
+
 fn run_repl() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
-fn run_file(mut file: File) -> Result<(), Box<dyn std::error::Error>> {
+
+fn read_source(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = File::open(Path::new(path))?;
     let mut source = String::new();
     file.read_to_string(&mut source)?;
-    let scanner = Scanner::new(source);
-    scanner.tokenize();
+    Ok(source)
+}
+
+fn compile(source: &str, options: &Options) -> Function {
+    let scanner = Scanner::new(source.to_string());
+    let tokens = scanner.tokenize();
+    if options.dump_tokens {
+        print!("{}", disassembler::dump_tokens(&tokens, options.format()));
+    }
+    if options.dump_ast {
+        // The errors are reported by the compilation below
+        let (program, _) = Parser::new(&tokens).parse();
+        print!(
+            "{}",
+            disassembler::dump_ast(&program, &tokens, options.format())
+        );
+    }
+    let mut compiler = Compiler::new(tokens);
+    compiler.set_opt_level(options.opt_level);
+    match compiler.compile_script() {
+        Ok(script) => script,
+        Err(errors) => {
+            eprintln!("{errors}");
+            process::exit(65);
+        }
+    }
+}
+
+fn run_file(path: &str, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let source = read_source(path)?;
+    let script = compile(&source, options);
+    if options.dump_bytecode {
+        print!(
+            "{}",
+            disassembler::disassemble(script.chunk(), "script", Some(&source), options.format())
+        );
+    }
+    let mut vm = VM::new();
+    vm.set_script_path(path);
+    vm.set_opt_level(options.opt_level);
+    vm.set_limits(options.limits);
+    if let Some(fuel) = options.fuel {
+        vm.set_fuel(fuel);
+    }
+    if options.debug {
+        let stdin = io::stdin();
+        return Ok(Debugger::new(vm, &source).run(script, stdin.lock(), io::stdout())?);
+    }
+    if options.run {
+        if let Some(trace) = &options.trace {
+            vm.set_tracer(trace.tracer()?);
+        }
+        let result = vm.interpret(script.into_chunk());
+        // The tracer may buffer its output, flush it before exiting
+        drop(vm);
+        match result {
+            InterpretResult::RuntimeError(error) => {
+                eprintln!("{error}");
+                process::exit(70);
+            }
+            InterpretResult::OutOfFuel => {
+                eprintln!("Out of fuel.");
+                process::exit(70);
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// The .crox files of a directory and its subdirectories, in a stable order
+fn crox_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            crox_files(&entry, files)?;
+        } else if entry
+            .extension()
+            .is_some_and(|extension| extension == "crox")
+        {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+// Formats the files in place, or only lists the ones that would change with
+// --check so CI can fail on unformatted code
+fn run_fmt(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let mut files = Vec::new();
+    for arg in args.iter().filter(|arg| *arg != "--check") {
+        if arg.starts_with('-') {
+            usage();
+        }
+        crox_files(Path::new(arg), &mut files)?;
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let mut unformatted = false;
+    for file in files {
+        let source = std::fs::read_to_string(&file)?;
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("{}: {error}", file.display());
+                process::exit(65);
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("Would reformat: {}", file.display());
+            unformatted = true;
+        } else {
+            std::fs::write(&file, formatted)?;
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
     Ok(())
 }
 
+// Prints the compile errors and the warnings of the lint rules, exits with 65
+// on errors and 1 on warnings
+fn run_lint(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for arg in args {
+        if arg.starts_with('-') {
+            usage();
+        }
+        crox_files(Path::new(arg), &mut files)?;
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let (mut errors, mut warnings) = (false, false);
+    for file in files {
+        let source = std::fs::read_to_string(&file)?;
+        let dir = file.parent().unwrap_or(Path::new("."));
+        let config = LintConfig::load(dir).unwrap_or_else(|error| {
+            eprintln!("{error}");
+            process::exit(78);
+        });
+        for diagnostic in lint::lint(&source, &config) {
+            match diagnostic.rule() {
+                Some(_) => warnings = true,
+                None => errors = true,
+            }
+            eprintln!("{}: {diagnostic}", file.display());
+        }
+    }
+    if errors {
+        process::exit(65);
+    }
+    if warnings {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Writes the documentation pages of the files, exits with 65 if one of them
+// does not parse
+fn run_doc(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage())),
+            flag if flag.starts_with('-') => usage(),
+            root => {
+                let root = Path::new(root);
+                let mut paths = Vec::new();
+                crox_files(root, &mut paths)?;
+                files.extend(paths.into_iter().map(|path| (root.to_path_buf(), path)));
+            }
+        }
+    }
+    let (Some(out), false) = (out, files.is_empty()) else {
+        usage();
+    };
+
+    let mut modules = Vec::new();
+    let mut errors = false;
+    for (root, file) in files {
+        // Modules are named by their path in the documented directory
+        let relative = file.strip_prefix(&root).unwrap_or(&file);
+        let relative = match relative.as_os_str().is_empty() {
+            true => Path::new(file.file_name().unwrap_or_default()),
+            false => relative,
+        };
+        let name = relative
+            .with_extension("")
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let source = std::fs::read_to_string(&file)?;
+        match doc::Module::extract(&name, &source) {
+            Ok(module) => modules.push(module),
+            Err(diagnostics) => {
+                errors = true;
+                for diagnostic in diagnostics {
+                    eprintln!("{}: {diagnostic}", file.display());
+                }
+            }
+        }
+    }
+    if errors {
+        process::exit(65);
+    }
+    doc::generate(&modules, Path::new(out))?;
+    Ok(())
+}
+
+// Accepts either a single line `12` or an inclusive range `12-20`
+fn parse_line_range(lines: &str) -> Option<(usize, usize)> {
+    match lines.split_once('-') {
+        Some((from, to)) => Some((from.parse().ok()?, to.parse().ok()?)),
+        None => {
+            let line = lines.parse().ok()?;
+            Some((line, line))
+        }
+    }
+}
+
+fn parse_count(arg: Option<&String>) -> usize {
+    arg.and_then(|count| count.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(64);
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => run_repl(),
-        2 => {
-            let path = Path::new(args[1].as_str());
-            let file = File::open(path)?;
-            run_file(file)
-        }
-        _ => Ok(println!(
-            "Usage:
-           - script mode: clox [file path]
-           - repl mode: clox"
-        )),
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (mut options, args) = match args.first().map(String::as_str) {
+        None => return run_repl(),
+        Some("disasm") => (
+            Options {
+                dump_bytecode: true,
+                ..Default::default()
+            },
+            &args[1..],
+        ),
+        Some("dap") => return Ok(DapServer::new(io::stdout().lock()).run(io::stdin().lock())?),
+        Some("lsp") => return Ok(LanguageServer::new(io::stdout().lock()).run(io::stdin().lock())?),
+        Some("fmt") => return run_fmt(&args[1..]),
+        Some("lint") => return run_lint(&args[1..]),
+        Some("doc") => return run_doc(&args[1..]),
+        Some("debug") => (
+            Options {
+                debug: true,
+                ..Default::default()
+            },
+            &args[1..],
+        ),
+        Some(_) => (
+            Options {
+                run: true,
+                ..Default::default()
+            },
+            &args[..],
+        ),
+    };
+
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dump-tokens" => options.dump_tokens = true,
+            "--dump-ast" => options.dump_ast = true,
+            "--dump-bytecode" => options.dump_bytecode = true,
+            "--json" => options.json = true,
+            "-O0" => options.opt_level = OptLevel::O0,
+            "-O1" => options.opt_level = OptLevel::O1,
+            "--trace" => {
+                options.trace.get_or_insert_with(Default::default);
+            }
+            "--trace-json" => options.trace.get_or_insert_with(Default::default).json = true,
+            "--trace-file" => {
+                let file = args.next().unwrap_or_else(|| usage());
+                options.trace.get_or_insert_with(Default::default).file = Some(file.clone());
+            }
+            "--trace-function" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                options.trace.get_or_insert_with(Default::default).function = Some(name.clone());
+            }
+            "--trace-lines" => {
+                let lines = args
+                    .next()
+                    .and_then(|lines| parse_line_range(lines))
+                    .unwrap_or_else(|| usage());
+                options.trace.get_or_insert_with(Default::default).lines = Some(lines);
+            }
+            "--max-stack" => options.limits.max_stack = parse_count(args.next()),
+            "--max-frames" => options.limits.max_frames = parse_count(args.next()),
+            "--max-heap" => options.limits.max_heap = Some(parse_count(args.next())),
+            "--fuel" => options.fuel = Some(parse_count(args.next()) as u64),
+            flag if flag.starts_with('-') => usage(),
+            file if path.is_none() => path = Some(file),
+            _ => usage(),
+        }
+    }
+
+    match path {
+        Some(path) => run_file(path, &options),
+        None => usage(),
     }
 }
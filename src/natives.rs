@@ -0,0 +1,63 @@
+use crate::compiler::value::Native;
+use crate::compiler::Value;
+
+/// Functions every script can call, looked up after the globals it defines.
+///
+/// `approx_eq(a, b, eps)` was meant to be `math.approx_eq`, but imports
+/// only load script files and there are no built-in modules yet, so it is
+/// a bare global until there are.
+pub const NATIVES: &[Native] = &[Native {
+    name: "approx_eq",
+    arity: 3,
+    function: approx_eq,
+}];
+
+pub fn lookup(name: &str) -> Option<Native> {
+    NATIVES.iter().find(|native| native.name == name).copied()
+}
+
+// approx_eq(a, b, eps): whether a and b are at most eps apart, `==` is exact
+fn approx_eq(args: &[Value]) -> Result<Value, String> {
+    let numbers = args.iter().map(Value::as_float).collect::<Option<Vec<_>>>();
+    let Some(&[a, b, eps]) = numbers.as_deref() else {
+        return Err(String::from("Arguments of approx_eq() must be numbers."));
+    };
+    // Infinities of the same sign are equal, whatever the tolerance
+    Ok(Value::Bool(a == b || (a - b).abs() <= eps))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+        (lookup(name).unwrap().function)(args)
+    }
+
+    #[test]
+    fn approx_eq_tolerance() {
+        let approx_eq = |a, b, eps| call("approx_eq", &[a, b, eps]);
+
+        assert_eq!(
+            approx_eq(
+                Value::Number(0.1 + 0.2),
+                Value::Number(0.3),
+                Value::Number(1e-9)
+            ),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            approx_eq(Value::Number(1e-9), Value::Int(0), Value::Number(1e-10)),
+            Ok(Value::Bool(false))
+        );
+        assert_eq!(
+            approx_eq(
+                Value::Number(f64::NAN),
+                Value::Number(f64::NAN),
+                Value::Int(1)
+            ),
+            Ok(Value::Bool(false))
+        );
+        assert!(approx_eq(Value::Int(1), Value::Str("1".into()), Value::Int(0)).is_err());
+    }
+}
@@ -1,4 +1,9 @@
-use itertools::Itertools;
+use std::cell::{OnceCell, RefCell};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::str;
+
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 use super::token::{Token, TokenType};
 
@@ -6,163 +11,670 @@ pub struct Scanner {
     s: String,
 }
 
+/// An edit of a source: the bytes of `range` were replaced by `len` bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub len: usize,
+}
+
 impl<'a> Scanner {
     pub fn new(source: String) -> Self {
         Self { s: source }
     }
 
+    /// Scans the source as it is read, only the part of the source around
+    /// the next token is read.
+    #[allow(dead_code)]
+    pub fn from_reader<R: Read>(reader: R) -> ReaderScanner<R> {
+        ReaderScanner {
+            input: RefCell::new(Input {
+                reader,
+                partial: Vec::new(),
+                done: false,
+                error: None,
+            }),
+            first: Chunk::default(),
+        }
+    }
+
+    /// The scanned source, see `Token::offset`.
+    pub fn source(&self) -> &str {
+        &self.s
+    }
+
+    /// Scans the tokens lazily, `&Scanner` is also an iterator of tokens.
+    pub fn tokens(&'a self) -> Tokens<'a> {
+        Tokens::new(&self.s, 0, 0)
+    }
+
     pub fn tokenize(&'a self) -> Vec<Token<'a>> {
-        use TokenType::*;
+        self.tokens().collect()
+    }
 
-        // I've chosen to divide the length of the source code by 3
-        // as a heuristic to allocate enough memory
-        let mut tokens: Vec<Token> = Vec::with_capacity(self.s.len() / 3);
-        let mut source = self.s.char_indices().peekable();
-        let mut line = 0;
+    /// Same as `tokenize` but keeps the comments as `Comment` tokens, for the
+    /// tools that print the source back.
+    pub fn tokenize_with_comments(&'a self) -> Vec<Token<'a>> {
+        self.tokens().with_comments().collect()
+    }
 
-        while let Some((i, c)) = source.next() {
-            match c {
-                // Single character tokens
-                '!' => {
-                    if source.next_if_eq(&(i + 1, '=')).is_some() {
-                        tokens.push(Token::new(BangEq, &self.s[i..i + 2], line))
-                    } else {
-                        tokens.push(Token::new(Bang, &self.s[i..i + 1], line))
-                    }
-                }
-                '^' => tokens.push(Token::new(Carrot, &self.s[i..i + 1], line)),
-                ',' => tokens.push(Token::new(Comma, &self.s[i..i + 1], line)),
-                '.' => tokens.push(Token::new(Dot, &self.s[i..i + 1], line)),
-
-                '=' => {
-                    if source.next_if_eq(&(i + 1, '=')).is_some() {
-                        tokens.push(Token::new(DoubleEq, &self.s[i..i + 2], line))
-                    } else {
-                        tokens.push(Token::new(Equal, &self.s[i..i + 1], line))
+    /// Tokens of this source, which is the source of `old` after `edit`.
+    /// Only the tokens around the edit are scanned again, the others are
+    /// taken from `old_tokens` and moved. `keep_comments` tells whether
+    /// `old_tokens` come from `tokenize_with_comments`.
+    pub fn relex(
+        &'a self,
+        old: &Scanner,
+        old_tokens: &[Token],
+        edit: &Edit,
+        keep_comments: bool,
+    ) -> Vec<Token<'a>> {
+        let old_source = old.source();
+        // The scan of a token looks at most two characters past its end, so
+        // it can only change if it starts less than two characters before
+        // the edit. The scanner is between tokens at the start of the others.
+        let restart = old_tokens
+            .iter()
+            .rposition(|token| {
+                start(token, old_source).is_some_and(|start| start + 1 < edit.range.start)
+            })
+            .unwrap_or(0);
+        let mut tokens = old_tokens[..restart]
+            .iter()
+            .map(|token| self.moved(token, old_source, 0, 0))
+            .collect::<Vec<_>>();
+
+        let (pos, line) = match old_tokens.get(restart) {
+            Some(token) if restart > 0 => (
+                token.offset(old_source) - quotes(token).0,
+                start_line(token),
+            ),
+            _ => (0, 0),
+        };
+        let mut scanned = Tokens::new(&self.s, pos, line);
+        scanned.keep_comments = keep_comments;
+
+        let offset = edit.len as isize - edit.range.len() as isize;
+        let mut old_index = restart;
+        for token in scanned {
+            // Once a token starts where an old token after the edit started,
+            // the rest of the source scans like before
+            if let Some(new_start) = start(&token, &self.s) {
+                while let Some(old_token) = old_tokens.get(old_index) {
+                    let moved_start = start(old_token, old_source)
+                        .filter(|old_start| *old_start >= edit.range.end)
+                        .map(|old_start| (old_start as isize + offset) as usize);
+                    match moved_start {
+                        Some(moved_start) if moved_start == new_start => {
+                            let lines =
+                                start_line(&token) as isize - start_line(old_token) as isize;
+                            tokens.extend(
+                                old_tokens[old_index..].iter().map(|old_token| {
+                                    self.moved(old_token, old_source, offset, lines)
+                                }),
+                            );
+                            return tokens;
+                        }
+                        Some(moved_start) if moved_start > new_start => break,
+                        _ => old_index += 1,
                     }
                 }
-                '>' => {
-                    if source.next_if_eq(&(i + 1, '=')).is_some() {
-                        tokens.push(Token::new(GreaterEq, &self.s[i..i + 2], line))
-                    } else {
-                        tokens.push(Token::new(Greater, &self.s[i..i + 1], line))
+            }
+            tokens.push(token);
+        }
+        tokens
+    }
+
+    // An old token moved to this source, `offset` bytes and `lines` lines
+    // further
+    fn moved(&'a self, token: &Token, old_source: &str, offset: isize, lines: isize) -> Token<'a> {
+        let line = (token.line() as isize + lines) as usize;
+        if token.ty() == TokenType::Eof {
+            return Token::new(TokenType::Eof, "", line);
+        }
+        let start = (token.offset(old_source) as isize + offset) as usize;
+        Token::new(
+            token.ty(),
+            &self.s[start..start + token.lexeme().len()],
+            line,
+        )
+    }
+}
+
+impl<'a> IntoIterator for &'a Scanner {
+    type Item = Token<'a>;
+    type IntoIter = Tokens<'a>;
+
+    fn into_iter(self) -> Tokens<'a> {
+        self.tokens()
+    }
+}
+
+// Characters of a token outside of its lexeme, before and after it
+fn quotes(token: &Token) -> (usize, usize) {
+    match token.ty() {
+        TokenType::CroxStr => (1, 1),
+        TokenType::Error("Unterminated String") => (1, 0),
+        _ => (0, 0),
+    }
+}
+
+// Offset where the scan of a token started, unknown for errors since the
+// scanner may skip characters before their lexeme
+fn start(token: &Token, source: &str) -> Option<usize> {
+    match token.ty() {
+        TokenType::Error(_) | TokenType::Eof => None,
+        _ => Some(token.offset(source) - quotes(token).0),
+    }
+}
+
+// Line where a token starts, tokens spanning lines have their last line
+fn start_line(token: &Token) -> usize {
+    token.line() - token.lexeme().matches('\n').count()
+}
+
+/// Scans the tokens of a source one at a time, the last one is `Eof`.
+pub struct Tokens<'a> {
+    s: &'a str,
+    // Offset of the next character to scan
+    pos: usize,
+    line: usize,
+    keep_comments: bool,
+    // Set once the Eof token is produced
+    done: bool,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(s: &'a str, pos: usize, line: usize) -> Self {
+        Self {
+            s,
+            pos,
+            line,
+            keep_comments: false,
+            done: false,
+        }
+    }
+
+    /// Produces the comments as `Comment` tokens.
+    pub fn with_comments(mut self) -> Self {
+        self.keep_comments = true;
+        self
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn matches(&mut self, expected: char) -> bool {
+        let matched = self.peek() == Some(expected);
+        if matched {
+            self.pos += expected.len_utf8();
+        }
+        matched
+    }
+
+    fn skip_while(&mut self, predicate: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&predicate) {
+            self.advance();
+        }
+    }
+
+    // Scans the token at `pos`, None when it is skipped
+    fn scan(&mut self) -> Option<Token<'a>> {
+        use TokenType::*;
+
+        let start = self.pos;
+        let c = self.advance()?;
+        let ty = match c {
+            // Single character tokens
+            '!' if self.matches('=') => BangEq,
+            '!' => Bang,
+            '^' => Carrot,
+            ',' => Comma,
+            '.' => Dot,
+            '=' if self.matches('=') => DoubleEq,
+            '=' => Equal,
+            '>' if self.matches('=') => GreaterEq,
+            '>' => Greater,
+            '{' => LeftBrace,
+            '[' => LeftBracket,
+            '(' => LeftParen,
+            '<' if self.matches('=') => LessEq,
+            '<' => Less,
+            '-' => Minus,
+            '%' => Percent,
+            '~' if self.matches('/') => TildeSlash,
+            '+' => Plus,
+            ')' => RightParen,
+            ']' => RightBracket,
+            '}' => RightBrace,
+            '*' => Star,
+            ';' => SemiColon,
+            ':' => Colon,
+
+            // Two character tokens,
+            '/' if self.matches('/') => return self.line_comment(start),
+            '/' if self.matches('*') => return self.block_comment(start),
+            '/' => Slash,
+            '0'..='9' => {
+                let (token, end) = self.number(start);
+                self.pos = end;
+                return Some(token);
+            }
+            // Identifiers follow Unicode's XID_Start and XID_Continue, '_'
+            // can also start one
+            c if c == '_' || is_xid_start(c) => {
+                self.skip_while(is_xid_continue);
+                get_ident_or_keyword_token_ty(&self.s[start..self.pos], c)
+            }
+            '"' => return Some(self.string(start)),
+            ' ' | '\r' | '\t' => return None,
+            '\n' => {
+                self.line += 1;
+                return None;
+            }
+            _ => Error("Unrecognized character"),
+        };
+        Some(Token::new(ty, &self.s[start..self.pos], self.line))
+    }
+
+    // The comment runs until the end of the line, the newline itself is
+    // scanned as whitespace
+    fn line_comment(&mut self, start: usize) -> Option<Token<'a>> {
+        self.skip_while(|c| c != '\n');
+        let lexeme = self.s[start..self.pos].trim_end();
+        // `////` and more is a regular comment, like in Rust
+        if lexeme.starts_with("///") && !lexeme.starts_with("////") {
+            Some(Token::new(TokenType::DocComment, lexeme, self.line))
+        } else if self.keep_comments {
+            Some(Token::new(TokenType::Comment, lexeme, self.line))
+        } else {
+            None
+        }
+    }
+
+    // Skips a block comment once its `/*` is consumed, nested comments
+    // included
+    fn block_comment(&mut self, start: usize) -> Option<Token<'a>> {
+        let start_line = self.line;
+        let mut depth = 1;
+        while let Some(c) = self.advance() {
+            match c {
+                '\n' => self.line += 1,
+                '/' if self.matches('*') => depth += 1,
+                '*' if self.matches('/') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        let lexeme = &self.s[start..self.pos];
+                        return self
+                            .keep_comments
+                            .then(|| Token::new(TokenType::Comment, lexeme, self.line));
                     }
                 }
-                '{' => tokens.push(Token::new(LeftBrace, &self.s[i..i + 1], line)),
-                '[' => tokens.push(Token::new(LeftBracket, &self.s[i..i + 1], line)),
-                '(' => tokens.push(Token::new(LeftParen, &self.s[i..i + 1], line)),
-                '<' => {
-                    if source.next_if_eq(&(i + 1, '=')).is_some() {
-                        tokens.push(Token::new(LessEq, &self.s[i..i + 2], line))
-                    } else {
-                        tokens.push(Token::new(Less, &self.s[i..i + 1], line))
+                _ => (),
+            }
+        }
+        Some(Token::new(
+            TokenType::Error("Unterminated block comment"),
+            &self.s[start..start + 2],
+            start_line,
+        ))
+    }
+
+    // The lexeme of a string leaves out its quotes
+    fn string(&mut self, start: usize) -> Token<'a> {
+        while let Some(c) = self.peek().filter(|c| *c != '"') {
+            if c == '\n' {
+                self.line += 1;
+            }
+            self.advance();
+        }
+        if self.matches('"') {
+            Token::new(
+                TokenType::CroxStr,
+                &self.s[start + 1..self.pos - 1],
+                self.line,
+            )
+        } else {
+            Token::new(
+                TokenType::Error("Unterminated String"),
+                &self.s[start + 1..self.pos],
+                self.line,
+            )
+        }
+    }
+
+    // Scans the number literal at `start`, returns its token and the offset
+    // right after it. A malformed literal gives an error token on the
+    // offending characters, the rest of the literal is skipped.
+    fn number(&self, start: usize) -> (Token<'a>, usize) {
+        use TokenType::*;
+
+        let line = self.line;
+        let bytes = self.s.as_bytes();
+        let at = |i: usize| bytes.get(i).copied().unwrap_or(0);
+        let error = |from: usize, to: usize, message| {
+            // Skips what is left of the literal, `1.2.3` is a single error
+            let end = to
+                + bytes[to..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.'))
+                    .count();
+            (Token::new(Error(message), &self.s[from..to], line), end)
+        };
+
+        let radix = match (at(start), at(start + 1)) {
+            (b'0', b'x' | b'X') => Some((16, "Invalid digit in hexadecimal literal")),
+            (b'0', b'o' | b'O') => Some((8, "Invalid digit in octal literal")),
+            (b'0', b'b' | b'B') => Some((2, "Invalid digit in binary literal")),
+            _ => None,
+        };
+        let separator = |i| error(i, i + 1, "'_' must be between digits");
+        if let Some((radix, invalid_digit)) = radix {
+            let end = match digits(bytes, start + 2, radix) {
+                Ok(end) => end,
+                Err(Digits::Missing(i)) => {
+                    return match at(i) {
+                        b'_' => separator(i),
+                        b if b.is_ascii_alphanumeric() => error(i, i + 1, invalid_digit),
+                        _ => error(start, i, "Expect digits after the base prefix"),
                     }
                 }
-                '-' => tokens.push(Token::new(Minus, &self.s[i..i + 1], line)),
-                '%' => tokens.push(Token::new(Percent, &self.s[i..i + 1], line)),
-                '+' => tokens.push(Token::new(Plus, &self.s[i..i + 1], line)),
-                ')' => tokens.push(Token::new(RightParen, &self.s[i..i + 1], line)),
-                ']' => tokens.push(Token::new(RightBracket, &self.s[i..i + 1], line)),
-                '}' => tokens.push(Token::new(RightBrace, &self.s[i..i + 1], line)),
-                '*' => tokens.push(Token::new(Star, &self.s[i..i + 1], line)),
-                ';' => tokens.push(Token::new(SemiColon, &self.s[i..i + 1], line)),
-                ':' => tokens.push(Token::new(Colon, &self.s[i..i + 1], line)),
-
-                // Two character tokens,
-                '/' => {
-                    if source.next_if_eq(&(i + 1, '/')).is_some() {
-                        // We skip the comment line
-                        if source.by_ref().any(|(_, chr)| chr == '\n') {
-                            line += 1;
-                        }
-                    } else {
-                        tokens.push(Token::new(Slash, &self.s[i..i + 1], line))
-                    }
+                Err(Digits::Separator(i)) => return separator(i),
+            };
+            if at(end).is_ascii_alphanumeric() || (at(end) == b'.' && at(end + 1).is_ascii_digit())
+            {
+                return error(end, end + 1, invalid_digit);
+            }
+            let lexeme = &self.s[start..end];
+            let digits = lexeme[2..].replace('_', "");
+            return match i64::from_str_radix(&digits, radix) {
+                Ok(num) => (Token::new(Integer(num), lexeme, line), end),
+                Err(_) => error(start, end, "Integer literal out of range"),
+            };
+        }
+
+        let mut end = match digits(bytes, start, 10) {
+            Ok(end) => end,
+            Err(Digits::Missing(i) | Digits::Separator(i)) => return separator(i),
+        };
+        let mut float = false;
+        if at(end) == b'.' && at(end + 1).is_ascii_digit() {
+            end = match digits(bytes, end + 1, 10) {
+                Ok(end) => end,
+                Err(Digits::Missing(i) | Digits::Separator(i)) => return separator(i),
+            };
+            float = true;
+        }
+        if matches!(at(end), b'e' | b'E') {
+            let exponent = end;
+            let mut i = end + 1;
+            if matches!(at(i), b'+' | b'-') {
+                i += 1;
+            }
+            end = match digits(bytes, i, 10) {
+                Ok(end) => end,
+                Err(Digits::Missing(i)) if at(i) != b'_' => {
+                    return error(exponent, i, "Expect digits in the exponent")
                 }
-                '0'..='9' => {
-                    let curr = i
-                        + 1
-                        + source
-                            .peeking_take_while(|(_, next_c)| next_c.is_numeric() || *next_c == '.')
-                            .count();
-                    if let Ok(num) = str::parse::<f64>(&self.s[i..curr]) {
-                        tokens.push(Token::new(Number(num), &self.s[i..curr], line))
-                    } else {
-                        tokens.push(Token::new(
-                            Error("Invalid Float literal"),
-                            &self.s[i..curr],
-                            line,
-                        ))
+                Err(Digits::Missing(i) | Digits::Separator(i)) => return separator(i),
+            };
+            float = true;
+        }
+        if at(end) == b'.' && at(end + 1).is_ascii_digit() {
+            return error(end, end + 1, "Unexpected '.' in number literal");
+        }
+        if at(end).is_ascii_alphanumeric() {
+            return error(end, end + 1, "Unexpected character after number literal");
+        }
+
+        let lexeme = &self.s[start..end];
+        let digits = lexeme.replace('_', "");
+        if !float {
+            return match digits.parse::<i64>() {
+                Ok(num) => (Token::new(Integer(num), lexeme, line), end),
+                Err(_) => error(start, end, "Integer literal out of range"),
+            };
+        }
+        match digits.parse::<f64>() {
+            Ok(num) if num.is_finite() => (Token::new(Number(num), lexeme, line), end),
+            _ => error(start, end, "Float literal out of range"),
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        while !self.done {
+            if self.pos == self.s.len() {
+                self.done = true;
+                return Some(Token::new(TokenType::Eof, "", self.line));
+            }
+            if let Some(token) = self.scan() {
+                return Some(token);
+            }
+        }
+        None
+    }
+}
+
+// Bytes read at once by a `ReaderScanner`
+const CHUNK_SIZE: usize = 8 * 1024;
+// Bytes of the two characters the scan of a token may look past its end
+const LOOKAHEAD: usize = 2 * 4;
+
+/// Scanner of a source read from a reader, see `Scanner::from_reader`. The
+/// offsets of its tokens are not offsets in the source.
+pub struct ReaderScanner<R> {
+    input: RefCell<Input<R>>,
+    // Empty, the source starts in the chunk after it
+    first: Chunk,
+}
+
+struct Input<R> {
+    reader: R,
+    // Start of a character split by the last read
+    partial: Vec<u8>,
+    done: bool,
+    error: Option<&'static str>,
+}
+
+// Part of the source read so far. The tokens are scanned within a single
+// chunk, the next chunk starts with the end of this one that wasn't scanned.
+#[derive(Default)]
+struct Chunk {
+    text: String,
+    next: OnceCell<Box<Chunk>>,
+}
+
+impl<R: Read> Input<R> {
+    // Reads at least `len` bytes and `CHUNK_SIZE` bytes, unless the source
+    // ends first
+    fn read(&mut self, len: usize) -> String {
+        let len = len.max(CHUNK_SIZE);
+        let mut bytes = std::mem::take(&mut self.partial);
+        let mut buffer = vec![0; len];
+        while bytes.len() < len && !self.done {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => self.done = true,
+                Ok(n) => bytes.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.fail("Can't read the source"),
+            }
+        }
+        match str::from_utf8(&bytes) {
+            Ok(_) => {}
+            Err(e) if e.error_len().is_none() && !self.done => {
+                self.partial = bytes.split_off(e.valid_up_to());
+            }
+            Err(e) => {
+                bytes.truncate(e.valid_up_to());
+                self.fail("Invalid UTF-8 in the source");
+            }
+        }
+        String::from_utf8(bytes).expect("bytes were validated")
+    }
+
+    fn fail(&mut self, message: &'static str) {
+        self.done = true;
+        self.error = Some(message);
+    }
+}
+
+impl<'a, R: Read> ReaderScanner<R> {
+    /// Scans the tokens lazily, `&ReaderScanner` is also an iterator of
+    /// tokens.
+    pub fn tokens(&'a self) -> ReaderTokens<'a, R> {
+        ReaderTokens {
+            scanner: self,
+            chunk: &self.first,
+            tokens: Tokens::new(&self.first.text, 0, 0),
+            reported: false,
+        }
+    }
+
+    // The chunk after `chunk`, starting at `start` in it
+    fn next_chunk(&'a self, chunk: &'a Chunk, start: usize) -> &'a Chunk {
+        chunk.next.get_or_init(|| {
+            let carried = &chunk.text[start..];
+            let mut input = self.input.borrow_mut();
+            // Reading as much as is carried keeps the copies linear when a
+            // token spans many chunks
+            let read = input.read(carried.len());
+            Box::new(Chunk {
+                text: carried.to_string() + &read,
+                next: OnceCell::new(),
+            })
+        })
+    }
+
+    // Whether there is source after `chunk`
+    fn continues(&self, chunk: &Chunk) -> bool {
+        chunk.next.get().is_some() || !self.input.borrow().done
+    }
+}
+
+impl<'a, R: Read> IntoIterator for &'a ReaderScanner<R> {
+    type Item = Token<'a>;
+    type IntoIter = ReaderTokens<'a, R>;
+
+    fn into_iter(self) -> ReaderTokens<'a, R> {
+        self.tokens()
+    }
+}
+
+/// Scans the tokens of a `ReaderScanner` one at a time, the last one is
+/// `Eof`. A failed read is an `Error` token before it.
+pub struct ReaderTokens<'a, R> {
+    scanner: &'a ReaderScanner<R>,
+    chunk: &'a Chunk,
+    tokens: Tokens<'a>,
+    // Set once the read error is produced
+    reported: bool,
+}
+
+impl<R: Read> ReaderTokens<'_, R> {
+    /// Produces the comments as `Comment` tokens.
+    #[allow(dead_code)]
+    pub fn with_comments(mut self) -> Self {
+        self.tokens.keep_comments = true;
+        self
+    }
+}
+
+impl<'a, R: Read> Iterator for ReaderTokens<'a, R> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            if !self.scanner.continues(self.chunk) {
+                if self.tokens.pos < self.tokens.s.len() {
+                    match Tokens::scan(&mut self.tokens) {
+                        Some(token) => return Some(token),
+                        None => continue,
                     }
                 }
-                'A'..='Z' | 'a'..='z' | '_' => {
-                    let curr = i
-                        + 1
-                        + source
-                            .peeking_take_while(|(_, next_c)| {
-                                next_c.is_ascii_alphabetic() || *next_c == '_'
-                            })
-                            .count();
-                    let lexeme = &self.s[i..curr];
-                    tokens.push(Token::new(
-                        Self::get_ident_or_keyword_token_ty(lexeme, c),
-                        lexeme,
-                        line,
-                    ))
+                let error = self.scanner.input.borrow().error;
+                if let Some(message) = error.filter(|_| !self.reported) {
+                    self.reported = true;
+                    return Some(Token::new(TokenType::Error(message), "", self.tokens.line));
                 }
-                '"' => {
-                    let mut curr = i + 1;
-                    source
-                        .peeking_take_while(|(_, next_c)| *next_c != '"')
-                        .for_each(|(_, next_c)| {
-                            if next_c == '\n' {
-                                line += 1
-                            }
-                            curr += 1;
-                        });
-                    if let Some(&(_, '"')) = source.peek() {
-                        source.next();
-                        tokens.push(Token::new(CroxStr, &self.s[i + 1..curr], line));
-                    } else {
-                        tokens.push(Token::new(
-                            Error("Unterminated String"),
-                            &self.s[i + 1..curr],
-                            line,
-                        ))
-                    }
-                }
-                ' ' => (),
-                '\n' | '\r' | '\t' => line += 1,
-                _ => tokens.push(Token::new(
-                    Error("Unrecognized character"),
-                    &self.s[i..i + 1],
-                    line,
-                )),
+                return self.tokens.next();
+            }
+            let (start, line) = (self.tokens.pos, self.tokens.line);
+            let token = Tokens::scan(&mut self.tokens);
+            // The token may continue in the next chunk, scan it again there
+            if self.tokens.pos + LOOKAHEAD > self.tokens.s.len() {
+                self.chunk = self.scanner.next_chunk(self.chunk, start);
+                let keep_comments = self.tokens.keep_comments;
+                self.tokens = Tokens::new(&self.chunk.text, 0, line);
+                self.tokens.keep_comments = keep_comments;
+                continue;
+            }
+            if token.is_some() {
+                return token;
             }
         }
-        tokens.push(Token::new(Eof, "", line));
-        tokens
     }
+}
 
-    fn get_ident_or_keyword_token_ty(lexeme: &str, c: char) -> TokenType {
-        use TokenType::*;
-        match c {
-            'a' if lexeme == "and" => And,
-            'c' if lexeme == "class" => Class,
-            'e' if lexeme == "else" => Else,
-            'f' if lexeme == "false" => False,
-            'f' if lexeme == "fn" => Fn,
-            'f' if lexeme == "for " => For,
-            'i' if lexeme == "if" => If,
-            'n' if lexeme == "null" => Null,
-            'o' if lexeme == "or" => Or,
-            'r' if lexeme == "return" => Return,
-            's' if lexeme == "super" => Super,
-            't' if lexeme == "this" => This,
-            't' if lexeme == "true" => True,
-            'l' if lexeme == "let" => Let,
-            'w' if lexeme == "while" => While,
-            _ => Identifier,
+fn get_ident_or_keyword_token_ty(lexeme: &str, c: char) -> TokenType {
+    use TokenType::*;
+    match c {
+        'a' if lexeme == "and" => And,
+        'a' if lexeme == "as" => As,
+        'c' if lexeme == "class" => Class,
+        'e' if lexeme == "else" => Else,
+        'f' if lexeme == "false" => False,
+        'f' if lexeme == "fn" => Fn,
+        'f' if lexeme == "for" => For,
+        'f' if lexeme == "from" => From,
+        'i' if lexeme == "if" => If,
+        'i' if lexeme == "import" => Import,
+        'n' if lexeme == "null" => Null,
+        'o' if lexeme == "or" => Or,
+        'p' if lexeme == "print" => Print,
+        'r' if lexeme == "return" => Return,
+        's' if lexeme == "super" => Super,
+        't' if lexeme == "this" => This,
+        't' if lexeme == "true" => True,
+        'l' if lexeme == "let" => Let,
+        'w' if lexeme == "while" => While,
+        _ => Identifier,
+    }
+}
+
+enum Digits {
+    // Offset where a digit was expected
+    Missing(usize),
+    // Offset of a '_' not followed by a digit
+    Separator(usize),
+}
+
+// Scans digits of `radix` with '_' separators from `start`, returns the
+// offset after the last digit
+fn digits(bytes: &[u8], start: usize, radix: u32) -> Result<usize, Digits> {
+    let is_digit = |i: usize| bytes.get(i).is_some_and(|b| (*b as char).is_digit(radix));
+    if !is_digit(start) {
+        return Err(Digits::Missing(start));
+    }
+    let mut i = start;
+    loop {
+        while is_digit(i) {
+            i += 1;
+        }
+        match bytes.get(i) {
+            Some(b'_') if is_digit(i + 1) => i += 1,
+            Some(b'_') => return Err(Digits::Separator(i)),
+            _ => return Ok(i),
         }
     }
 }
@@ -181,9 +693,9 @@ mod tokenizer_tests {
             Token::new(TokenType::Let, "let", 0),
             Token::new(TokenType::Identifier, "x", 0),
             Token::new(TokenType::Equal, "=", 0),
-            Token::new(TokenType::Number(5.0), "5", 0),
+            Token::new(TokenType::Integer(5), "5", 0),
             Token::new(TokenType::Plus, "+", 0),
-            Token::new(TokenType::Number(3.0), "3", 0),
+            Token::new(TokenType::Integer(3), "3", 0),
             Token::new(TokenType::SemiColon, ";", 0),
             Token::new(TokenType::Eof, "", 0),
         ];
@@ -193,7 +705,7 @@ mod tokenizer_tests {
 
     #[test]
     fn tokenize_single_character_tokens() {
-        let source = String::from("!^,.=><%/*+-{[(}]):;");
+        let source = String::from("!^,.=><%*/+-{[(}]):;");
         let scanner = Scanner::new(source);
         let tokens = scanner.tokenize();
 
@@ -206,8 +718,8 @@ mod tokenizer_tests {
             Token::new(TokenType::Greater, ">", 0),
             Token::new(TokenType::Less, "<", 0),
             Token::new(TokenType::Percent, "%", 0),
-            Token::new(TokenType::Slash, "/", 0),
             Token::new(TokenType::Star, "*", 0),
+            Token::new(TokenType::Slash, "/", 0),
             Token::new(TokenType::Plus, "+", 0),
             Token::new(TokenType::Minus, "-", 0),
             Token::new(TokenType::LeftBrace, "{", 0),
@@ -226,7 +738,7 @@ mod tokenizer_tests {
 
     #[test]
     fn tokenize_multi_character_tokens() {
-        let source = String::from("==>=<=!=");
+        let source = String::from("==>=<=!=~/");
         let scanner = Scanner::new(source);
         let tokens = scanner.tokenize();
 
@@ -235,6 +747,7 @@ mod tokenizer_tests {
             Token::new(TokenType::GreaterEq, ">=", 0),
             Token::new(TokenType::LessEq, "<=", 0),
             Token::new(TokenType::BangEq, "!=", 0),
+            Token::new(TokenType::TildeSlash, "~/", 0),
             Token::new(TokenType::Eof, "", 0),
         ];
 
@@ -242,21 +755,107 @@ mod tokenizer_tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn tokenize_numbers() {
-        let source = String::from("42 3.14 0.1");
+        let source = String::from("42 3.14 0.1 9223372036854775807 9223372036854775808");
         let scanner = Scanner::new(source);
         let tokens = scanner.tokenize();
 
         let expected_tokens = vec![
-            Token::new(TokenType::Number(42.0), "42", 0),
+            Token::new(TokenType::Integer(42), "42", 0),
             Token::new(TokenType::Number(3.14), "3.14", 0),
             Token::new(TokenType::Number(0.1), "0.1", 0),
+            Token::new(TokenType::Integer(i64::MAX), "9223372036854775807", 0),
+            Token::new(
+                TokenType::Error("Integer literal out of range"),
+                "9223372036854775808",
+                0,
+            ),
+            Token::new(TokenType::Eof, "", 0),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn tokenize_radix_numbers() {
+        let source = String::from("0xFF 0Xff 0b1010 0o17 0x7FFF_FFFF_FFFF_FFFF 0b1111_0000");
+        let scanner = Scanner::new(source);
+        let tokens = scanner.tokenize();
+
+        let expected_tokens = vec![
+            Token::new(TokenType::Integer(255), "0xFF", 0),
+            Token::new(TokenType::Integer(255), "0Xff", 0),
+            Token::new(TokenType::Integer(10), "0b1010", 0),
+            Token::new(TokenType::Integer(15), "0o17", 0),
+            Token::new(TokenType::Integer(i64::MAX), "0x7FFF_FFFF_FFFF_FFFF", 0),
+            Token::new(TokenType::Integer(240), "0b1111_0000", 0),
+            Token::new(TokenType::Eof, "", 0),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn tokenize_separators_and_exponents() {
+        let source = String::from("1_000 1_000.000_5 1.5e-3 2E10 1e+2 3e0 1.x");
+        let scanner = Scanner::new(source);
+        let tokens = scanner.tokenize();
+
+        let expected_tokens = vec![
+            Token::new(TokenType::Integer(1000), "1_000", 0),
+            Token::new(TokenType::Number(1000.0005), "1_000.000_5", 0),
+            Token::new(TokenType::Number(0.0015), "1.5e-3", 0),
+            Token::new(TokenType::Number(2e10), "2E10", 0),
+            Token::new(TokenType::Number(100.0), "1e+2", 0),
+            Token::new(TokenType::Number(3.0), "3e0", 0),
+            // A '.' without digits after it isn't part of the number
+            Token::new(TokenType::Integer(1), "1", 0),
+            Token::new(TokenType::Dot, ".", 0),
+            Token::new(TokenType::Identifier, "x", 0),
             Token::new(TokenType::Eof, "", 0),
         ];
 
         assert_eq!(tokens, expected_tokens);
     }
 
+    #[test]
+    fn tokenize_malformed_numbers() {
+        // Source, message, and the characters the error points at with their
+        // offset
+        let cases = [
+            ("1.2.3", "Unexpected '.' in number literal", ".", 3),
+            ("0b102", "Invalid digit in binary literal", "2", 4),
+            ("0o18", "Invalid digit in octal literal", "8", 3),
+            ("0xFG", "Invalid digit in hexadecimal literal", "G", 3),
+            ("0x", "Expect digits after the base prefix", "0x", 0),
+            ("0x_1", "'_' must be between digits", "_", 2),
+            ("1__0", "'_' must be between digits", "_", 1),
+            ("1_", "'_' must be between digits", "_", 1),
+            ("1_.5", "'_' must be between digits", "_", 1),
+            ("1e", "Expect digits in the exponent", "e", 1),
+            ("1.5E+", "Expect digits in the exponent", "E+", 3),
+            ("12px", "Unexpected character after number literal", "p", 2),
+            ("1e999", "Float literal out of range", "1e999", 0),
+            (
+                "0x8000_0000_0000_0000",
+                "Integer literal out of range",
+                "0x8000_0000_0000_0000",
+                0,
+            ),
+        ];
+        for (source, message, lexeme, offset) in cases {
+            let scanner = Scanner::new(format!("{source} +"));
+            let tokens = scanner.tokenize();
+
+            assert_eq!(tokens[0].ty(), TokenType::Error(message), "{source}");
+            assert_eq!(tokens[0].lexeme(), lexeme, "{source}");
+            assert_eq!(tokens[0].offset(scanner.source()), offset, "{source}");
+            // The rest of the literal is skipped
+            assert_eq!(tokens[1].ty(), TokenType::Plus, "{source}");
+        }
+    }
+
     #[test]
     fn tokenize_identifiers() {
         let source = String::from("let foo _bar");
@@ -273,6 +872,59 @@ mod tokenizer_tests {
         assert_eq!(tokens, expected_tokens);
     }
 
+    #[test]
+    fn tokenize_identifiers_with_digits_and_unicode() {
+        let source = String::from("x1 vec2 _9 café π 変数 a\u{301} 1x");
+        let scanner = Scanner::new(source);
+        let tokens = scanner.tokenize();
+
+        let expected_tokens = vec![
+            Token::new(TokenType::Identifier, "x1", 0),
+            Token::new(TokenType::Identifier, "vec2", 0),
+            Token::new(TokenType::Identifier, "_9", 0),
+            Token::new(TokenType::Identifier, "café", 0),
+            Token::new(TokenType::Identifier, "π", 0),
+            Token::new(TokenType::Identifier, "変数", 0),
+            // A combining accent continues an identifier
+            Token::new(TokenType::Identifier, "a\u{301}", 0),
+            Token::new(
+                TokenType::Error("Unexpected character after number literal"),
+                "x",
+                0,
+            ),
+            Token::new(TokenType::Eof, "", 0),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+
+        let scanner = Scanner::new(String::from("\u{301}a ½"));
+        assert_eq!(
+            scanner.tokenize()[..3],
+            [
+                Token::new(TokenType::Error("Unrecognized character"), "\u{301}", 0),
+                Token::new(TokenType::Identifier, "a", 0),
+                Token::new(TokenType::Error("Unrecognized character"), "½", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_keywords_as_whole_words() {
+        let source = String::from("for fort fn fn1 if iffy while1 let_ and");
+        let scanner = Scanner::new(source);
+        let types = scanner
+            .tokenize()
+            .iter()
+            .map(|token| token.ty())
+            .collect::<Vec<_>>();
+
+        use TokenType::*;
+        assert_eq!(
+            types,
+            vec![For, Identifier, Fn, Identifier, If, Identifier, Identifier, Identifier, And, Eof]
+        );
+    }
+
     #[test]
     fn tokenize_strings() {
         let source = String::from("\"Hello, world!\"");
@@ -287,6 +939,22 @@ mod tokenizer_tests {
         assert_eq!(tokens, expected_tokens);
     }
 
+    #[test]
+    fn tokenize_lines() {
+        let source = String::from("1\t+\r\n2");
+        let scanner = Scanner::new(source);
+        let tokens = scanner.tokenize();
+
+        let expected_tokens = vec![
+            Token::new(TokenType::Integer(1), "1", 0),
+            Token::new(TokenType::Plus, "+", 0),
+            Token::new(TokenType::Integer(2), "2", 1),
+            Token::new(TokenType::Eof, "", 1),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
     #[test]
     fn tokenize_error() {
         let source = String::from("@#&");
@@ -302,4 +970,241 @@ mod tokenizer_tests {
 
         assert_eq!(tokens, expected_tokens);
     }
+
+    #[test]
+    fn tokenize_comments() {
+        let source = String::from("1 // one\r\n// two\n2//");
+        let scanner = Scanner::new(source);
+
+        let expected_tokens = vec![
+            Token::new(TokenType::Integer(1), "1", 0),
+            Token::new(TokenType::Comment, "// one", 0),
+            Token::new(TokenType::Comment, "// two", 1),
+            Token::new(TokenType::Integer(2), "2", 2),
+            Token::new(TokenType::Comment, "//", 2),
+            Token::new(TokenType::Eof, "", 2),
+        ];
+
+        assert_eq!(scanner.tokenize_with_comments(), expected_tokens);
+        let without_comments = expected_tokens
+            .into_iter()
+            .filter(|token| token.ty() != TokenType::Comment)
+            .collect::<Vec<_>>();
+        assert_eq!(scanner.tokenize(), without_comments);
+    }
+
+    #[test]
+    fn tokenize_block_comments() {
+        let source = String::from("1 /* a /* nested\n*/ still */ 2 /**/ 3\n/* open\n\n");
+        let scanner = Scanner::new(source);
+
+        let expected_tokens = vec![
+            Token::new(TokenType::Integer(1), "1", 0),
+            // Like strings, the line is the one where the comment ends
+            Token::new(TokenType::Comment, "/* a /* nested\n*/ still */", 1),
+            Token::new(TokenType::Integer(2), "2", 1),
+            Token::new(TokenType::Comment, "/**/", 1),
+            Token::new(TokenType::Integer(3), "3", 1),
+            Token::new(TokenType::Error("Unterminated block comment"), "/*", 2),
+            Token::new(TokenType::Eof, "", 4),
+        ];
+
+        assert_eq!(scanner.tokenize_with_comments(), expected_tokens);
+        let without_comments = expected_tokens
+            .into_iter()
+            .filter(|token| token.ty() != TokenType::Comment)
+            .collect::<Vec<_>>();
+        assert_eq!(scanner.tokenize(), without_comments);
+    }
+
+    #[test]
+    fn tokenize_doc_comments() {
+        let source = String::from("/// Doc\n///\n//// Not a doc\nfn");
+        let scanner = Scanner::new(source);
+        let tokens = scanner.tokenize();
+
+        let expected_tokens = vec![
+            Token::new(TokenType::DocComment, "/// Doc", 0),
+            Token::new(TokenType::DocComment, "///", 1),
+            Token::new(TokenType::Fn, "fn", 3),
+            Token::new(TokenType::Eof, "", 3),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+        assert_eq!(tokens[0].doc_text(), Some("Doc"));
+        assert_eq!(tokens[1].doc_text(), Some(""));
+        assert_eq!(tokens[2].doc_text(), None);
+    }
+
+    #[test]
+    fn tokenize_multibyte_strings() {
+        let scanner = Scanner::new(String::from("\"héllo\" \"wörld"));
+
+        let expected_tokens = vec![
+            Token::new(TokenType::CroxStr, "héllo", 0),
+            Token::new(TokenType::Error("Unterminated String"), "wörld", 0),
+            Token::new(TokenType::Eof, "", 0),
+        ];
+
+        assert_eq!(scanner.tokenize(), expected_tokens);
+    }
+
+    #[test]
+    fn tokens_are_scanned_lazily() {
+        let scanner = Scanner::new(String::from("let x = 1;\n\"unterminated"));
+        let mut tokens = scanner.tokens();
+
+        assert_eq!(tokens.next(), Some(Token::new(TokenType::Let, "let", 0)));
+        assert_eq!(
+            tokens.next(),
+            Some(Token::new(TokenType::Identifier, "x", 0))
+        );
+        assert_eq!(tokens.count(), 5);
+
+        let mut count = 0;
+        for token in &scanner {
+            count += 1;
+            assert_ne!(token.ty(), TokenType::Comment);
+        }
+        assert_eq!(count, 7);
+
+        let mut tokens = scanner.tokens();
+        assert_eq!(tokens.nth(6), Some(Token::new(TokenType::Eof, "", 1)));
+        assert_eq!(tokens.next(), None);
+    }
+
+    fn relex(source: &str, range: Range<usize>, text: &str, keep_comments: bool) {
+        let old = Scanner::new(source.to_string());
+        let old_tokens = match keep_comments {
+            true => old.tokenize_with_comments(),
+            false => old.tokenize(),
+        };
+        let mut edited = source.to_string();
+        edited.replace_range(range.clone(), text);
+        let new = Scanner::new(edited);
+        let expected = match keep_comments {
+            true => new.tokenize_with_comments(),
+            false => new.tokenize(),
+        };
+
+        let edit = Edit {
+            range: range.clone(),
+            len: text.len(),
+        };
+        let tokens = new.relex(&old, &old_tokens, &edit, keep_comments);
+        assert_eq!(tokens, expected, "{source:?} {range:?} {text:?}");
+        // The lexemes point into the new source
+        for (token, expected) in tokens.iter().zip(&expected) {
+            assert_eq!(token.offset(new.source()), expected.offset(new.source()));
+        }
+    }
+
+    #[test]
+    fn relex_a_typed_character() {
+        let source = "let total = a + b;\nprint total;";
+        // `b` becomes `bc`
+        relex(source, 17..17, "c", false);
+        // `total` becomes `tot` on the first line only
+        relex(source, 7..9, "", false);
+        // A new line shifts the lines of the tokens after it
+        relex(source, 18..18, "\n\n", false);
+    }
+
+    #[test]
+    fn relex_every_edit() {
+        let source = "/// Doc\nfn f(a) {\n  return a.5 + 1.5e3 /* c /* d */ */;\n}\n\"s\ntr\" // c\n0x1F_ff ~/ 2";
+        let texts = [
+            "", "x", "1", ".", "\"", "/", "*", "/*", "*/", "\n", "_", "e",
+        ];
+        for start in 0..=source.len() {
+            for end in start..(start + 3).min(source.len() + 1) {
+                for text in texts {
+                    relex(source, start..end, text, false);
+                    relex(source, start..end, text, true);
+                }
+            }
+        }
+    }
+
+    // Returns at most `size` bytes per read
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        size: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let n = self.size.min(buffer.len()).min(self.bytes.len());
+            buffer[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn scan_a_reader() {
+        let source = "/// Doc\nfn f(a) {\n  return a.5 + 1.5e3 /* c /* d */ */;\n}\n\"wörld\ntr\" // c\n0x1F_ff ~/ 2 ½";
+        let scanner = Scanner::new(source.to_string());
+        let long = "\"".to_string() + &"ab\n".repeat(CHUNK_SIZE) + "\" x";
+        let long_scanner = Scanner::new(long.clone());
+        for size in [1, 2, 3, 7, CHUNK_SIZE] {
+            let reader = Scanner::from_reader(Trickle {
+                bytes: source.as_bytes(),
+                size,
+            });
+            assert_eq!(reader.tokens().collect::<Vec<_>>(), scanner.tokenize());
+            assert_eq!(
+                reader.tokens().with_comments().collect::<Vec<_>>(),
+                scanner.tokenize_with_comments()
+            );
+
+            let reader = Scanner::from_reader(Trickle {
+                bytes: long.as_bytes(),
+                size,
+            });
+            assert_eq!(reader.tokens().collect::<Vec<_>>(), long_scanner.tokenize());
+        }
+    }
+
+    #[test]
+    fn scan_a_reader_lazily() {
+        let source = "print 1;\n".repeat(CHUNK_SIZE);
+        let mut trickle = Trickle {
+            bytes: source.as_bytes(),
+            size: CHUNK_SIZE,
+        };
+        let scanner = Scanner::from_reader(&mut trickle);
+        assert_eq!(scanner.tokens().take(3).count(), 3);
+        drop(scanner);
+        assert!(trickle.bytes.len() > source.len() - 2 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn scan_a_failing_reader() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("failed"))
+            }
+        }
+        let scanner = Scanner::from_reader(Failing);
+        assert_eq!(
+            scanner.tokens().collect::<Vec<_>>(),
+            vec![
+                Token::new(TokenType::Error("Can't read the source"), "", 0),
+                Token::new(TokenType::Eof, "", 0),
+            ]
+        );
+
+        let scanner = Scanner::from_reader(&b"print 1 \xff"[..]);
+        assert_eq!(
+            scanner.tokens().collect::<Vec<_>>(),
+            vec![
+                Token::new(TokenType::Print, "print", 0),
+                Token::new(TokenType::Integer(1), "1", 0),
+                Token::new(TokenType::Error("Invalid UTF-8 in the source"), "", 0),
+                Token::new(TokenType::Eof, "", 0),
+            ]
+        );
+    }
 }
@@ -27,29 +27,43 @@ pub enum TokenType {
     GreaterEq,
     DoubleEq,
     BangEq,
+    // Integer division
+    TildeSlash,
 
     // Literals
     Identifier,
     Number(f64),
+    // Numbers without a '.'
+    Integer(i64),
     CroxStr,
 
     // Keywords
     And,
+    As,
     Class,
     Else,
     False,
     For,
     Fn,
+    From,
     If,
+    Import,
     Let,
     Null,
     Or,
+    Print,
     Return,
     Super,
     This,
     True,
     While,
 
+    // Trivia, only produced by `Scanner::tokenize_with_comments`
+    Comment,
+    // `///` comments, kept by `Scanner::tokenize` for the declaration that
+    // follows them
+    DocComment,
+
     Eof,
     Error(&'static str),
 }
@@ -65,4 +79,34 @@ impl<'a> Token<'a> {
     pub fn new(ty: TokenType, lexeme: &'a str, line: usize) -> Self {
         Self { ty, lexeme, line }
     }
+
+    pub fn ty(&self) -> TokenType {
+        self.ty
+    }
+
+    pub fn lexeme(&self) -> &'a str {
+        self.lexeme
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Text of a doc comment without its `///` and the space after it.
+    pub fn doc_text(&self) -> Option<&'a str> {
+        if self.ty != TokenType::DocComment {
+            return None;
+        }
+        let text = self.lexeme.strip_prefix("///").unwrap_or(self.lexeme);
+        Some(text.strip_prefix(' ').unwrap_or(text))
+    }
+
+    /// Byte offset of the lexeme in `source`, the string the token was
+    /// scanned from. The lexeme of a string excludes its opening quote.
+    pub fn offset(&self, source: &str) -> usize {
+        match self.ty {
+            TokenType::Eof => source.len(),
+            _ => self.lexeme.as_ptr() as usize - source.as_ptr() as usize,
+        }
+    }
 }
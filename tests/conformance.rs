@@ -0,0 +1,223 @@
+//! Runs every `.crox` file under `tests/crox/` through the `crox` binary and
+//! compares what it prints with the annotations written in the file:
+//!
+//! - `// expect: <line>` an expected line on stdout,
+//! - `// error: <message>` an expected compile error reported on the
+//!   annotation's line, `// [line N] error: <message>` for another line,
+//! - `// expect runtime error: <message>` the script must stop with this
+//!   runtime error, raised from the annotation's line.
+//!
+//! Each file runs with `-O0` and with `-O1`. The files under a `lib/`
+//! directory are modules imported by the tests, they don't run on their own.
+//! Arguments filter the files to run by path:
+//! `cargo test --test conformance -- scanner/ errors`
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const EXPECT: &str = "expect: ";
+const ERROR: &str = "error: ";
+const RUNTIME_ERROR: &str = "expect runtime error: ";
+
+// Every script runs once per optimization level, the optimizer must not
+// change what a script does
+const OPT_FLAGS: [&[&str]; 2] = [&["-O0"], &["-O1"]];
+
+const COMPILE_ERROR_CODE: i32 = 65;
+const RUNTIME_ERROR_CODE: i32 = 70;
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let line_nb = index + 1;
+            let Some(at) = line.find("// ") else {
+                continue;
+            };
+            let comment = &line[at + 3..];
+            if let Some(output) = comment.strip_prefix(EXPECT) {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix(RUNTIME_ERROR) {
+                expectations.runtime_error = Some((message.to_string(), line_nb));
+            } else if let Some(message) = comment.strip_prefix(ERROR) {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {line_nb}] {message}"));
+            } else if let Some((line_nb, message)) = Self::explicit_line(comment) {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {line_nb}] {message}"));
+            }
+        }
+        expectations
+    }
+
+    // Parses `[line N] error: <message>` annotations
+    fn explicit_line(comment: &str) -> Option<(usize, &str)> {
+        let (line_nb, message) = comment.strip_prefix("[line ")?.split_once(']')?;
+        let message = message.strip_prefix(' ')?.strip_prefix(ERROR)?;
+        Some((line_nb.parse().ok()?, message))
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            COMPILE_ERROR_CODE
+        } else if self.runtime_error.is_some() {
+            RUNTIME_ERROR_CODE
+        } else {
+            0
+        }
+    }
+
+    fn expected_stderr(&self) -> Vec<String> {
+        match &self.runtime_error {
+            Some((message, line)) => vec![message.clone(), format!("[line {line}]")],
+            None => self.compile_errors.clone(),
+        }
+    }
+
+    // A runtime error is followed by the call stack, only the message and the
+    // line of the innermost frame are checked
+    fn actual_stderr(&self, stderr: Vec<String>) -> Vec<String> {
+        if self.runtime_error.is_none() {
+            return stderr;
+        }
+        stderr
+            .into_iter()
+            .take(2)
+            .enumerate()
+            .map(|(index, line)| match line.find(']') {
+                Some(end) if index == 1 => line[..=end].to_string(),
+                _ => line,
+            })
+            .collect()
+    }
+}
+
+fn diff(what: &str, expected: &[String], actual: &[String]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let mut out = format!("  {what} differs (- expected, + actual):\n");
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(e), Some(a)) if e == a => out.push_str(&format!("      {e}\n")),
+            (e, a) => {
+                if let Some(e) = e {
+                    out.push_str(&format!("    - {e}\n"));
+                }
+                if let Some(a) = a {
+                    out.push_str(&format!("    + {a}\n"));
+                }
+            }
+        }
+    }
+    Some(out)
+}
+
+fn lines(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+fn run(path: &Path, flags: &[&str]) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("  can't read: {e}\n"))?;
+    let expectations = Expectations::parse(&source);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_crox"))
+        .args(flags)
+        .arg(path)
+        .output()
+        .map_err(|e| format!("  can't run crox: {e}\n"))?;
+
+    let mut failures = String::new();
+    if let Some(diff) = diff("stdout", &expectations.output, &lines(&output.stdout)) {
+        failures.push_str(&diff);
+    }
+    if let Some(diff) = diff(
+        "stderr",
+        &expectations.expected_stderr(),
+        &expectations.actual_stderr(lines(&output.stderr)),
+    ) {
+        failures.push_str(&diff);
+    }
+    let code = output.status.code();
+    if code != Some(expectations.exit_code()) {
+        failures.push_str(&format!(
+            "  expected exit code {}, got {code:?}\n",
+            expectations.exit_code()
+        ));
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).expect("Expected a readable test directory");
+    for entry in entries {
+        let path = entry.expect("Expected a readable test entry").path();
+        if path.is_dir() {
+            if !path.ends_with("lib") {
+                collect(&path, files);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "crox") {
+            files.push(path);
+        }
+    }
+}
+
+fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("crox");
+    // cargo forwards its own flags to the harness, only keep the filters
+    let filters = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect::<Vec<_>>();
+
+    let mut files = Vec::new();
+    collect(&root, &mut files);
+    files.sort();
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in files {
+        let name = file
+            .strip_prefix(&root)
+            .unwrap_or(&file)
+            .display()
+            .to_string();
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str())) {
+            continue;
+        }
+        for flags in OPT_FLAGS {
+            match run(&file, flags) {
+                Ok(()) => passed += 1,
+                Err(failures) => {
+                    failed += 1;
+                    println!("FAIL {name} {}\n{failures}", flags.join(" "));
+                }
+            }
+        }
+    }
+
+    println!("conformance: {passed} passed; {failed} failed");
+    if failed > 0 {
+        process::exit(1);
+    }
+}